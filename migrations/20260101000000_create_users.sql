@@ -0,0 +1,17 @@
+-- The users unFTP authenticates and the details it applies to them. The types work in both SQLite
+-- and PostgreSQL. Lists are comma separated.
+CREATE TABLE unftp_users (
+    username        TEXT PRIMARY KEY,
+    -- A bcrypt, Argon2 or SHA-crypt hash, like in an htpasswd file. Users without one can't log
+    -- in with a password.
+    password_hash   TEXT,
+    account_enabled BOOLEAN NOT NULL DEFAULT TRUE,
+    name            TEXT,
+    surname         TEXT,
+    -- The home directory relative to the storage back-end root.
+    root            TEXT,
+    -- Like vfs_perms in the JSON user file, e.g. '-mkdir,-rmdir'. NULL allows everything.
+    vfs_perms       TEXT,
+    -- Networks the user can log in from, e.g. '10.0.0.0/8,192.168.1.7'. NULL allows any.
+    allowed_ips     TEXT
+);
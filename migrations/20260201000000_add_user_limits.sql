@@ -0,0 +1,5 @@
+-- Per-user limits, like max_sessions, max_download_bps and max_upload_bps in the JSON user file.
+-- NULL or 0 means no limit.
+ALTER TABLE unftp_users ADD COLUMN max_sessions INTEGER;
+ALTER TABLE unftp_users ADD COLUMN max_download_bps BIGINT;
+ALTER TABLE unftp_users ADD COLUMN max_upload_bps BIGINT;
@@ -0,0 +1,431 @@
+//! Accepts the FTP control connections, so that clients can be turned away before they get the
+//! greeting, can start TLS or can send any command.
+//!
+//! libunftp's own listener hands every connection straight to a session. unFTP therefore accepts
+//! the connections itself and serves those it lets in through `Server::service`, which takes the
+//! server, so every connection gets a server of its own. It is only built once the client is let in.
+//!
+//! In proxy protocol mode libunftp has to accept the connections, because it passes data
+//! connections on to their session, and it doesn't tell unFTP the address from the PROXY header.
+//! unFTP therefore reads the header first and relays the connections it lets in to libunftp, which
+//! then listens on a loopback port.
+
+use crate::ip_filter::IpFilter;
+use crate::sessions::{ConnectionClaim, Sessions};
+use libunftp::{auth::UserDetail, storage::StorageBackend, Server};
+use slog::Logger;
+use std::{
+    collections::HashSet,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpSocket, TcpStream},
+    sync::broadcast,
+    task::JoinSet,
+};
+
+// How long a client gets to send the PROXY header or to take a refusal.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+// The longest a PROXY protocol version 1 header can be, including the CRLF.
+const MAX_HEADER_LEN: usize = 107;
+
+/// The connections that unFTP's HTTP health check makes to the FTP port. These are always let in,
+/// so that the filter and the limits can't make an instance look unhealthy.
+#[derive(Debug, Default)]
+pub struct Probes(Mutex<HashSet<SocketAddr>>);
+
+impl Probes {
+    /// Connects to the FTP server as a health check.
+    pub async fn connect(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        let socket = match addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        // Binding first tells the local address before the server can see the connection.
+        socket.bind(SocketAddr::new(addr.ip(), 0))?;
+        let local = socket.local_addr()?;
+        self.0.lock().unwrap().insert(local);
+        let connected = socket.connect(addr).await;
+        if connected.is_err() {
+            self.0.lock().unwrap().remove(&local);
+        }
+        connected
+    }
+
+    fn take(&self, peer: SocketAddr) -> bool {
+        self.0.lock().unwrap().remove(&peer)
+    }
+}
+
+/// Decides which control connections are let in.
+#[derive(Debug)]
+pub struct Gate {
+    log: Logger,
+    ip_filter: Option<Arc<IpFilter>>,
+    sessions: Arc<Sessions>,
+    probes: Arc<Probes>,
+}
+
+impl Gate {
+    pub fn new(
+        log: Logger,
+        ip_filter: Option<Arc<IpFilter>>,
+        sessions: Arc<Sessions>,
+        probes: Arc<Probes>,
+    ) -> Self {
+        Gate {
+            log,
+            ip_filter,
+            sessions,
+            probes,
+        }
+    }
+
+    // Tells whether the client may connect. `peer` is the address the connection came from and
+    // `client_ip` the address of the client, which differ behind a proxy. The session that is
+    // claimed must be kept until the connection is closed. The error is the reply to send before
+    // closing the connection.
+    async fn admit(
+        &self,
+        peer: SocketAddr,
+        client_ip: IpAddr,
+    ) -> Result<Option<ConnectionClaim>, &'static str> {
+        if self.probes.take(peer) {
+            return Ok(None);
+        }
+        if let Some(filter) = &self.ip_filter {
+            if !filter.allows(client_ip).await {
+                return Err("421 Not allowed to connect from this address.\r\n");
+            }
+        }
+        match self.sessions.connect(client_ip.to_canonical()) {
+            Ok(claim) => Ok(Some(claim)),
+            Err(e) => {
+                slog::warn!(self.log, "Rejected connection: {}", e; "source_ip" => %client_ip);
+                Err("421 Too many connections, try again later.\r\n")
+            }
+        }
+    }
+
+    // Sends the reply and closes the connection.
+    async fn refuse(&self, mut stream: TcpStream, reply: &str) {
+        let sent = tokio::time::timeout(HEADER_TIMEOUT, async {
+            stream.write_all(reply.as_bytes()).await?;
+            stream.shutdown().await
+        })
+        .await;
+        if let Ok(Err(e)) = sent {
+            slog::debug!(self.log, "Could not refuse connection: {}", e);
+        }
+    }
+}
+
+/// Accepts control connections on the address and serves those that the gate lets in with a server
+/// from `new_server`. On shutdown no new connections are accepted, the sessions are told to close and
+/// get the grace period to finish what they were doing.
+pub async fn serve<S, U, F>(
+    log: Logger,
+    addr: SocketAddr,
+    gate: Arc<Gate>,
+    new_server: F,
+    mut shutdown: broadcast::Receiver<()>,
+    grace_period: Duration,
+) -> Result<(), String>
+where
+    S: StorageBackend<U> + 'static,
+    U: UserDetail + 'static,
+    F: Fn() -> Result<Server<S, U>, String> + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind(addr)
+        .await
+        .map_err(|e| format!("could not listen on {}: {}", addr, e))?;
+    let new_server = Arc::new(new_server);
+    let mut sessions = JoinSet::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, peer) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        slog::warn!(log, "Could not accept connection: {}", e);
+                        continue;
+                    }
+                };
+                let gate = gate.clone();
+                let new_server = new_server.clone();
+                let log = log.clone();
+                let mut shutdown = shutdown.resubscribe();
+                sessions.spawn(async move {
+                    let _claim = match gate.admit(peer, peer.ip()).await {
+                        Ok(claim) => claim,
+                        Err(reply) => return gate.refuse(stream, reply).await,
+                    };
+                    let server = match new_server() {
+                        Ok(server) => server,
+                        Err(e) => {
+                            slog::error!(log, "{}", e);
+                            return;
+                        }
+                    };
+                    tokio::select! {
+                        served = server.service(stream) => if let Err(e) = served {
+                            slog::warn!(log, "Control connection failed: {}", e; "peer" => %peer);
+                        },
+                        // libunftp gives every session served this way its own shutdown notifier,
+                        // which tells the control loop to close the connection when it's dropped
+                        // along with `service`.
+                        _ = shutdown.recv() => {},
+                    }
+                });
+            },
+            Some(_) = sessions.join_next(), if !sessions.is_empty() => {},
+            _ = shutdown.recv() => break,
+        }
+    }
+
+    slog::info!(log, "Shutting down FTP server");
+    drop(listener);
+    while sessions.join_next().await.is_some() {}
+    // A session ends when libunftp drops its storage back-end, after the control loop and any
+    // transfer that was still running.
+    let ended = tokio::time::timeout(grace_period, gate.sessions.all_logged_out()).await;
+    if ended.is_err() {
+        slog::warn!(
+            log,
+            "{} FTP sessions didn't end in time",
+            gate.sessions.counts().users.values().sum::<u32>()
+        );
+    }
+    Ok(())
+}
+
+/// Accepts connections that start with a PROXY header on the address and passes those that the
+/// gate lets in on to libunftp at `backend`. Connections to other ports than the external control
+/// port are data connections and are always passed on.
+pub async fn front_door(
+    log: Logger,
+    addr: SocketAddr,
+    backend: SocketAddr,
+    external_control_port: u16,
+    gate: Arc<Gate>,
+    mut shutdown: broadcast::Receiver<()>,
+) -> Result<(), String> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .map_err(|e| format!("could not listen on {}: {}", addr, e))?;
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    slog::warn!(log, "Could not accept connection: {}", e);
+                    continue;
+                }
+            },
+            _ = shutdown.recv() => return Ok(()),
+        };
+        let gate = gate.clone();
+        let log = log.clone();
+        tokio::spawn(async move {
+            if let Err(e) = pass_on(stream, peer, backend, external_control_port, &gate).await {
+                slog::debug!(log, "Closed proxied connection: {}", e; "peer" => %peer);
+            }
+        });
+    }
+}
+
+// Reads the PROXY header and, if the client is let in, relays the connection to libunftp.
+async fn pass_on(
+    mut stream: TcpStream,
+    peer: SocketAddr,
+    backend: SocketAddr,
+    external_control_port: u16,
+    gate: &Gate,
+) -> Result<(), String> {
+    let mut buf = Vec::with_capacity(MAX_HEADER_LEN);
+    let header_len = tokio::time::timeout(HEADER_TIMEOUT, read_header(&mut stream, &mut buf))
+        .await
+        .map_err(|_| "timed out waiting for the PROXY header".to_string())??;
+    let (source, destination_port) = parse_header(&buf[..header_len])?;
+    // Data connections belong to a session that was let in already.
+    let _claim = if destination_port == external_control_port {
+        match gate.admit(peer, source).await {
+            Ok(claim) => claim,
+            Err(reply) => {
+                gate.refuse(stream, reply).await;
+                return Ok(());
+            }
+        }
+    } else {
+        None
+    };
+    let mut upstream = TcpStream::connect(backend)
+        .await
+        .map_err(|e| format!("could not connect to {}: {}", backend, e))?;
+    upstream.write_all(&buf).await.map_err(|e| e.to_string())?;
+    tokio::io::copy_bidirectional(&mut stream, &mut upstream)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+// Reads until the end of the PROXY header and returns its length. What the client sent after it is
+// left in the buffer.
+async fn read_header(stream: &mut TcpStream, buf: &mut Vec<u8>) -> Result<usize, String> {
+    let mut chunk = [0u8; MAX_HEADER_LEN];
+    loop {
+        if let Some(end) = buf.windows(2).position(|w| w == b"\r\n") {
+            return Ok(end + 2);
+        }
+        if buf.len() >= MAX_HEADER_LEN {
+            return Err("PROXY header is too long".to_string());
+        }
+        let n = stream.read(&mut chunk).await.map_err(|e| e.to_string())?;
+        if n == 0 {
+            return Err("connection closed before the PROXY header".to_string());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+// Gets the source address and the destination port from a PROXY protocol version 1 header like
+// `PROXY TCP4 192.0.2.1 198.51.100.1 56324 21\r\n`.
+fn parse_header(header: &[u8]) -> Result<(IpAddr, u16), String> {
+    let invalid = || "invalid PROXY header".to_string();
+    let header = std::str::from_utf8(header).map_err(|_| invalid())?;
+    let fields: Vec<&str> = header.trim_end().split(' ').collect();
+    match fields[..] {
+        ["PROXY", "TCP4" | "TCP6", source, _, _, destination_port] => Ok((
+            source.parse().map_err(|_| invalid())?,
+            destination_port.parse().map_err(|_| invalid())?,
+        )),
+        _ => Err(invalid()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_header, serve, Gate, Probes};
+    use crate::ip_filter::{IpFilter, Rules};
+    use crate::sessions::{SessionLimits, Sessions};
+    use libunftp::{
+        auth::{AnonymousAuthenticator, DefaultUser},
+        ServerBuilder,
+    };
+    use std::{net::SocketAddr, sync::Arc, time::Duration};
+    use tokio::{io::AsyncReadExt, net::TcpStream, sync::broadcast};
+    use unftp_sbe_fs::Filesystem;
+
+    #[test]
+    fn proxy_headers_are_parsed() {
+        let parsed = parse_header(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 2121\r\n").unwrap();
+        assert_eq!(parsed, ("192.0.2.1".parse().unwrap(), 2121));
+        assert!(parse_header(b"PROXY UNKNOWN\r\n").is_err());
+        assert!(parse_header(b"GET / HTTP/1.1\r\n").is_err());
+    }
+
+    // Serves anonymous FTP on a free port behind the gate until the sender is dropped.
+    async fn start(gate: Gate) -> (SocketAddr, broadcast::Sender<()>) {
+        let addr = {
+            let l = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            l.local_addr().unwrap()
+        };
+        let new_server = || {
+            ServerBuilder::<Filesystem, DefaultUser>::with_authenticator(
+                Box::new(|| Filesystem::new(std::env::temp_dir()).unwrap()),
+                Arc::new(AnonymousAuthenticator),
+            )
+            .greeting("Welcome")
+            .build()
+            .map_err(|e| e.to_string())
+        };
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let (stop, shutdown) = broadcast::channel(1);
+        tokio::spawn(serve(
+            log,
+            addr,
+            Arc::new(gate),
+            new_server,
+            shutdown,
+            Duration::ZERO,
+        ));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        (addr, stop)
+    }
+
+    async fn greeting(stream: &mut TcpStream) -> String {
+        let mut reply = [0u8; 64];
+        let n = stream.read(&mut reply).await.unwrap();
+        String::from_utf8_lossy(&reply[..n]).to_string()
+    }
+
+    #[tokio::test]
+    async fn clients_that_are_not_allowed_get_no_greeting() {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let rules = Rules {
+            allow: vec![],
+            deny: Rules::parse_list("127.0.0.1").unwrap(),
+        };
+        let filter = IpFilter::new(Arc::new(log.clone()), rules, None).unwrap();
+        let sessions = Arc::new(Sessions::default());
+        let probes = Arc::new(Probes::default());
+        let (addr, _stop) = start(Gate::new(
+            log,
+            Some(Arc::new(filter)),
+            sessions,
+            probes.clone(),
+        ))
+        .await;
+
+        let mut refused = TcpStream::connect(addr).await.unwrap();
+        assert!(greeting(&mut refused).await.starts_with("421 "));
+        let mut probe = probes.connect(addr).await.unwrap();
+        assert!(greeting(&mut probe).await.starts_with("220 Welcome"));
+    }
+
+    #[tokio::test]
+    async fn connections_over_the_limit_are_refused_until_one_is_closed() {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let sessions = Arc::new(Sessions::new(SessionLimits {
+            total: None,
+            per_ip: Some(1),
+        }));
+        let probes = Arc::new(Probes::default());
+        let (addr, _stop) = start(Gate::new(log, None, sessions.clone(), probes)).await;
+
+        let mut first = TcpStream::connect(addr).await.unwrap();
+        assert!(greeting(&mut first).await.starts_with("220 "));
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        assert!(greeting(&mut second)
+            .await
+            .starts_with("421 Too many connections"));
+
+        drop(first);
+        for _ in 0..50 {
+            if sessions.counts().total == 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let mut third = TcpStream::connect(addr).await.unwrap();
+        assert!(greeting(&mut third).await.starts_with("220 "));
+    }
+    #[tokio::test]
+    async fn sessions_are_closed_on_shutdown() {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let gate = Gate::new(log, None, Arc::default(), Arc::default());
+        let (addr, stop) = start(gate).await;
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        assert!(greeting(&mut client).await.starts_with("220 "));
+        stop.send(()).unwrap();
+        let mut rest = vec![];
+        let closed = tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut rest));
+        assert!(closed.await.is_ok());
+    }
+}
@@ -3,8 +3,55 @@ use clap::{Arg, ArgEnum, Command};
 use std::str::FromStr;
 use strum_macros::{Display, EnumString};
 
-#[cfg(feature = "auth_jsonfile")]
+pub const ALLOW_IPS: &str = "allow-ips";
+pub const AUTH_CACHE_NEGATIVE_TTL: &str = "auth-cache-negative-ttl";
+pub const AUTH_CACHE_SIZE: &str = "auth-cache-size";
+pub const AUTH_CACHE_TTL: &str = "auth-cache-ttl";
+pub const AUTH_CHAIN: &str = "auth-chain";
+pub const AUTH_HTPASSWD_PATH: &str = "auth-htpasswd-path";
 pub const AUTH_JSON_PATH: &str = "auth-json-path";
+#[cfg(feature = "auth_jwt")]
+pub const AUTH_JWT_AUDIENCE: &str = "auth-jwt-audience";
+#[cfg(feature = "auth_jwt")]
+pub const AUTH_JWT_ISSUER: &str = "auth-jwt-issuer";
+#[cfg(feature = "auth_jwt")]
+pub const AUTH_JWT_JWKS_FILE: &str = "auth-jwt-jwks-file";
+#[cfg(feature = "auth_jwt")]
+pub const AUTH_JWT_JWKS_REFRESH: &str = "auth-jwt-jwks-refresh";
+#[cfg(feature = "auth_jwt")]
+pub const AUTH_JWT_JWKS_URL: &str = "auth-jwt-jwks-url";
+#[cfg(feature = "auth_jwt")]
+pub const AUTH_JWT_LEEWAY: &str = "auth-jwt-leeway";
+#[cfg(feature = "auth_jwt")]
+pub const AUTH_JWT_ROLE_PERMS: &str = "auth-jwt-role-perms";
+#[cfg(feature = "auth_jwt")]
+pub const AUTH_JWT_ROLES_CLAIM: &str = "auth-jwt-roles-claim";
+#[cfg(feature = "auth_jwt")]
+pub const AUTH_JWT_ROOT_CLAIM: &str = "auth-jwt-root-claim";
+#[cfg(feature = "auth_jwt")]
+pub const AUTH_JWT_USERNAME_CLAIM: &str = "auth-jwt-username-claim";
+#[cfg(feature = "auth_ldap")]
+pub const AUTH_LDAP_BIND_DN: &str = "auth-ldap-bind-dn";
+#[cfg(feature = "auth_ldap")]
+pub const AUTH_LDAP_BIND_PASSWORD: &str = "auth-ldap-bind-password";
+#[cfg(feature = "auth_ldap")]
+pub const AUTH_LDAP_GROUP_ATTRIBUTE: &str = "auth-ldap-group-attribute";
+#[cfg(feature = "auth_ldap")]
+pub const AUTH_LDAP_POOL_SIZE: &str = "auth-ldap-pool-size";
+#[cfg(feature = "auth_ldap")]
+pub const AUTH_LDAP_REQUIRED_GROUPS: &str = "auth-ldap-required-groups";
+#[cfg(feature = "auth_ldap")]
+pub const AUTH_LDAP_SEARCH_BASE: &str = "auth-ldap-search-base";
+#[cfg(feature = "auth_ldap")]
+pub const AUTH_LDAP_SEARCH_FILTER: &str = "auth-ldap-search-filter";
+#[cfg(feature = "auth_ldap")]
+pub const AUTH_LDAP_STARTTLS: &str = "auth-ldap-starttls";
+#[cfg(feature = "auth_ldap")]
+pub const AUTH_LDAP_TIMEOUT: &str = "auth-ldap-timeout";
+#[cfg(feature = "auth_ldap")]
+pub const AUTH_LDAP_URL: &str = "auth-ldap-url";
+#[cfg(feature = "auth_ldap")]
+pub const AUTH_LDAP_USER_DN: &str = "auth-ldap-user-dn";
 #[cfg(feature = "auth_pam")]
 pub const AUTH_PAM_SERVICE: &str = "auth-pam-service";
 #[cfg(feature = "auth_rest")]
@@ -17,14 +64,30 @@ pub const AUTH_REST_REGEX: &str = "auth-rest-regex";
 pub const AUTH_REST_SELECTOR: &str = "auth-rest-selector";
 #[cfg(feature = "auth_rest")]
 pub const AUTH_REST_URL: &str = "auth-rest-url";
+#[cfg(feature = "auth_sql")]
+pub const AUTH_SQL_MIGRATE: &str = "auth-sql-migrate";
+#[cfg(feature = "auth_sql")]
+pub const AUTH_SQL_POOL_SIZE: &str = "auth-sql-pool-size";
+#[cfg(feature = "auth_sql")]
+pub const AUTH_SQL_TIMEOUT: &str = "auth-sql-timeout";
+#[cfg(feature = "auth_sql")]
+pub const AUTH_SQL_URL: &str = "auth-sql-url";
+pub const AUTH_TOTP_SKEW: &str = "auth-totp-skew";
 pub const AUTH_TYPE: &str = "auth-type";
 pub const BIND_ADDRESS: &str = "bind-address";
+pub const DENY_IPS: &str = "deny-ips";
+pub const ENABLE_HTTP_ADMIN: &str = "enable-http-admin";
 pub const ENABLE_SITEMD5: &str = "enable-sitemd5";
 pub const FAILED_LOGINS_POLICY: &str = "failed-logins-policy";
 pub const FAILED_MAX_ATTEMPTS: &str = "failed-max-attempts";
 pub const FAILED_EXPIRE_AFTER: &str = "failed-expire-after";
+#[cfg(feature = "failed_logins_redis")]
+pub const FAILED_REDIS_PREFIX: &str = "failed-logins-redis-prefix";
+#[cfg(feature = "failed_logins_redis")]
+pub const FAILED_REDIS_URL: &str = "failed-logins-redis-url";
 pub const FTPS_CERTS_FILE: &str = "ftps-certs-file";
 pub const FTPS_CLIENT_AUTH: &str = "ftps-client-auth";
+pub const FTPS_CLIENT_CERT_IDENTITY: &str = "ftps-client-cert-identity";
 pub const FTPS_KEY_FILE: &str = "ftps-key-file";
 pub const FTPS_REQUIRED_ON_CONTROL_CHANNEL: &str = "ftps-required-on-control-channel";
 pub const FTPS_REQUIRED_ON_DATA_CHANNEL: &str = "ftps-required-on-data-channel";
@@ -55,14 +118,25 @@ pub const AZBLOB_ACCOUNT_KEY: &str = "sbe-opendal-azblob-account-key";
 pub const AZBLOB_SAS_TOKEN: &str = "sbe-opendal-azblob-sas-token";
 #[cfg(feature = "sbe_azblob")]
 pub const AZBLOB_BATCH_MAX_OPERATIONS: &str = "sbe-opendal-azblob-batch-max-operations";
+pub const HOOKS_FILE: &str = "hooks-file";
+pub const HTTP_ADMIN_TOKEN: &str = "http-admin-token";
 pub const HTTP_BIND_ADDRESS: &str = "bind-address-http";
 pub const IDLE_SESSION_TIMEOUT: &str = "idle-session-timeout";
 pub const INSTANCE_NAME: &str = "instance-name";
+pub const IP_FILTER_FILE: &str = "ip-filter-file";
 pub const LOG_LEVEL: &str = "log-level";
+pub const MAX_SESSIONS: &str = "max-sessions";
+pub const MAX_SESSIONS_PER_IP: &str = "max-sessions-per-ip";
 pub const PASSIVE_HOST: &str = "passive-host";
 pub const PASSIVE_PORTS: &str = "passive-ports";
 pub const PROXY_EXTERNAL_CONTROL_PORT: &str = "proxy-external-control-port";
+pub const NTF_ROUTES_FILE: &str = "ntf-routes-file";
 pub const PUBSUB_BASE_URL: &str = "ntf-pubsub-base-url";
+pub const PUBSUB_BATCH_DELAY: &str = "ntf-pubsub-batch-delay";
+pub const PUBSUB_BATCH_SIZE: &str = "ntf-pubsub-batch-size";
+pub const PUBSUB_BUFFER_SIZE: &str = "ntf-pubsub-buffer-size";
+pub const PUBSUB_KEY_FILE: &str = "ntf-pubsub-key-file";
+pub const PUBSUB_NO_AUTH: &str = "ntf-pubsub-no-auth";
 pub const PUBSUB_TOPIC: &str = "ntf-pubsub-topic";
 pub const PUBSUB_PROJECT: &str = "ntf-pubsub-project";
 pub const REDIS_HOST: &str = "log-redis-host";
@@ -70,9 +144,47 @@ pub const REDIS_KEY: &str = "log-redis-key";
 pub const REDIS_PORT: &str = "log-redis-port";
 pub const ROOT_DIR: &str = "root-dir";
 pub const STORAGE_BACKEND_TYPE: &str = "sbe-type";
+pub const THROTTLE_DOWNLOAD_BPS: &str = "throttle-download-bps";
+pub const THROTTLE_SESSION_DOWNLOAD_BPS: &str = "throttle-session-download-bps";
+pub const THROTTLE_SESSION_UPLOAD_BPS: &str = "throttle-session-upload-bps";
+pub const THROTTLE_UPLOAD_BPS: &str = "throttle-upload-bps";
 pub const USR_JSON_PATH: &str = "usr-json-path";
 pub const USR_HTTP_URL: &str = "usr-http-url";
+pub const USR_VFS_PERMS_DEFAULT: &str = "usr-vfs-perms-default";
+pub const USR_CREATE_HOME: &str = "usr-create-home";
+pub const USR_HOME_SKELETON: &str = "usr-home-skeleton";
+pub const USR_ROOT_PATTERN: &str = "usr-root-pattern";
+pub const USR_HTTP_METHOD: &str = "usr-http-method";
+pub const USR_HTTP_BODY: &str = "usr-http-body";
+pub const USR_HTTP_AUTH_BEARER: &str = "usr-http-auth-bearer";
+pub const USR_HTTP_AUTH_BASIC: &str = "usr-http-auth-basic";
+pub const USR_HTTP_AUTH_HEADER: &str = "usr-http-auth-header";
+pub const USR_HTTP_CA_FILE: &str = "usr-http-ca-file";
+pub const USR_HTTP_CLIENT_CERT: &str = "usr-http-client-cert";
+pub const USR_HTTP_CLIENT_KEY: &str = "usr-http-client-key";
+pub const USR_HTTP_TIMEOUT: &str = "usr-http-timeout";
+pub const USR_HTTP_RETRIES: &str = "usr-http-retries";
+pub const USR_EXPIRY_WARNING_DAYS: &str = "usr-expiry-warning-days";
+#[cfg(feature = "auth_ldap")]
+pub const USR_LDAP: &str = "usr-ldap";
+#[cfg(feature = "auth_ldap")]
+pub const USR_LDAP_ATTRIBUTES: &str = "usr-ldap-attributes";
+#[cfg(feature = "auth_sql")]
+pub const USR_SQL: &str = "usr-sql";
 pub const VERBOSITY: &str = "verbosity";
+pub const USER_CMD: &str = "user";
+pub const USER_CMD_ADD: &str = "add";
+pub const USER_CMD_DISABLE: &str = "disable";
+pub const USER_CMD_ENABLE: &str = "enable";
+pub const USER_CMD_LIST: &str = "list";
+pub const USER_CMD_PASSWD: &str = "passwd";
+pub const USER_CMD_REMOVE: &str = "remove";
+pub const USER_DISABLED: &str = "disabled";
+pub const USER_ENCODING: &str = "encoding";
+pub const USER_NAME: &str = "username";
+pub const USER_PASSWORD: &str = "password";
+pub const USER_ROOT: &str = "root";
+pub const USER_VFS_PERMS: &str = "vfs-perms";
 pub const GLOG_LOGNAME: &str = "log-google-logname";
 pub const GLOG_LEVEL_LABEL: &str = "log-google-level-label";
 pub const GLOG_RESOURCE_TYPE: &str = "log-google-resource-type";
@@ -80,16 +192,24 @@ pub const GLOG_LABELS_FILE: &str = "log-google-labels-file";
 #[cfg(feature = "tokio_console")]
 pub const TOKIO_CONSOLE_BIND_ADDRESS: &str = "bind-address-tokio-console";
 
-#[derive(Debug, EnumString, Display, PartialEq)]
+#[derive(Debug, EnumString, Display, PartialEq, Clone, Copy)]
 #[strum(serialize_all = "lowercase")]
 pub enum AuthType {
     Anonymous,
+    Chain,
+    Htpasswd,
     #[cfg(feature = "auth_pam")]
     Pam,
     #[cfg(feature = "auth_rest")]
     Rest,
     #[cfg(feature = "auth_jsonfile")]
     Json,
+    #[cfg(feature = "auth_ldap")]
+    Ldap,
+    #[cfg(feature = "auth_jwt")]
+    Jwt,
+    #[cfg(feature = "auth_sql")]
+    Sql,
 }
 
 #[derive(ArgEnum, Clone, Debug)]
@@ -279,6 +399,17 @@ pub(crate) fn clap_app(tmp_dir: &str) -> Command<'_> {
                 .takes_value(true)
                 .default_value("off")
         )
+        .arg(
+            Arg::new(FTPS_CLIENT_CERT_IDENTITY)
+                .long("ftps-client-cert-identity")
+                .value_name("SOURCES")
+                .help("Ties client certificates to users. The certificate must hold the username, or one of the \
+                       cert_identities in the user details, in one of these comma separated places: \
+                       cn, email or spiffe.")
+                .env("UNFTP_FTPS_CLIENT_CERT_IDENTITY")
+                .takes_value(true)
+                .requires(FTPS_CLIENT_AUTH),
+        )
         .arg(
             Arg::new(FTPS_KEY_FILE)
                 .long("ftps-key-file")
@@ -353,6 +484,24 @@ pub(crate) fn clap_app(tmp_dir: &str) -> Command<'_> {
                 .takes_value(true)
                 .default_value("0.0.0.0:8080"),
         )
+        .arg(
+            Arg::new(ENABLE_HTTP_ADMIN)
+                .long("enable-http-admin")
+                .help("Enable the admin endpoints of the HTTP server, which show the sessions and blocked clients \
+                       and lift blocks or empty the authentication cache. They need the --http-admin-token.")
+                .env("UNFTP_ENABLE_HTTP_ADMIN")
+                .takes_value(false)
+                .requires(HTTP_ADMIN_TOKEN),
+        )
+        .arg(
+            Arg::new(HTTP_ADMIN_TOKEN)
+                .long("http-admin-token")
+                .value_name("TOKEN")
+                .help("The token that requests to the admin endpoints of the HTTP server must send in an \
+                       'Authorization: Bearer' header.")
+                .env("UNFTP_HTTP_ADMIN_TOKEN")
+                .takes_value(true),
+        )
         .arg(
             Arg::new(INSTANCE_NAME)
                 .long("instance-name")
@@ -390,13 +539,69 @@ pub(crate) fn clap_app(tmp_dir: &str) -> Command<'_> {
                 .long("auth-type")
                 .value_name("TYPE")
                 .help("The type of authorization back-end to use. \
-                         Possible values could by 'anonymous', 'pam', 'json' or 'rest', but it depends \
+                         Possible values could by 'anonymous', 'chain', 'htpasswd', 'pam', 'json', 'rest', 'ldap', 'jwt' or 'sql', but it depends \
                          on whether unFTP was compiled to include that particular feature.")
                 //.case_insensitive(true)
                 .env("UNFTP_AUTH_TYPE")
                 .takes_value(true)
                 .default_value("anonymous"),
         )
+        .arg(
+            Arg::new(AUTH_CACHE_TTL)
+                .long("auth-cache-ttl")
+                .value_name("SECONDS")
+                .help("Caches user details and successful password checks for this many seconds so that not \
+                       every login reaches the back-ends. Off by default.")
+                .env("UNFTP_AUTH_CACHE_TTL")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new(AUTH_CACHE_NEGATIVE_TTL)
+                .long("auth-cache-negative-ttl")
+                .value_name("SECONDS")
+                .help("How long the cache remembers that a user doesn't exist.")
+                .env("UNFTP_AUTH_CACHE_NEGATIVE_TTL")
+                .default_value("30")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new(AUTH_CACHE_SIZE)
+                .long("auth-cache-size")
+                .value_name("ENTRIES")
+                .help("The maximum number of users the cache holds the details and passwords of.")
+                .env("UNFTP_AUTH_CACHE_SIZE")
+                .default_value("10000")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new(AUTH_TOTP_SKEW)
+                .long("auth-totp-skew")
+                .value_name("STEPS")
+                .help("How many 30 second steps a TOTP code may be off to allow for clock differences. \
+                       Applies to users with a totp_secret in their user details.")
+                .env("UNFTP_AUTH_TOTP_SKEW")
+                .default_value("1")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new(AUTH_CHAIN)
+                .long("auth-chain")
+                .value_name("STEPS")
+                .help("The authentication back-ends to try in order when --auth-type is 'chain', as a \
+                       comma separated list of type:flag pairs. The flag is required, sufficient or optional. \
+                       For example 'json:sufficient,rest:sufficient,pam:required'.")
+                .env("UNFTP_AUTH_CHAIN")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new(AUTH_HTPASSWD_PATH)
+                .long("auth-htpasswd-path")
+                .value_name("PATH")
+                .help("The path to the htpasswd file with bcrypt, argon2 or SHA-crypt hashes. The file is \
+                       read again when it changes.")
+                .env("UNFTP_AUTH_HTPASSWD_PATH")
+                .takes_value(true),
+        )
         .arg(
             Arg::new(STORAGE_BACKEND_TYPE)
                 .long("sbe-type")
@@ -417,6 +622,81 @@ pub(crate) fn clap_app(tmp_dir: &str) -> Command<'_> {
                 .takes_value(true)
                 .default_value("600"),
         )
+        .arg(
+            Arg::new(ALLOW_IPS)
+                .long("allow-ips")
+                .value_name("NETWORKS")
+                .help("Comma separated networks in CIDR notation, or single IP addresses, that clients may log in from. \
+                       Clients from other addresses are rejected.")
+                .env("UNFTP_ALLOW_IPS")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new(DENY_IPS)
+                .long("deny-ips")
+                .value_name("NETWORKS")
+                .help("Comma separated networks in CIDR notation, or single IP addresses, that clients may not log in \
+                       from. These win over --allow-ips.")
+                .env("UNFTP_DENY_IPS")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new(IP_FILTER_FILE)
+                .long("ip-filter-file")
+                .value_name("FILE")
+                .help("The path to a file with 'allow' and 'deny' rules for client networks, one per line, which \
+                       is read again when it changes. The rules are added to --allow-ips and --deny-ips.")
+                .env("UNFTP_IP_FILTER_FILE")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new(MAX_SESSIONS)
+                .long("max-sessions")
+                .value_name("COUNT")
+                .help("The number of sessions that can be connected at the same time. Connections over it get a 421 reply.")
+                .env("UNFTP_MAX_SESSIONS")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new(MAX_SESSIONS_PER_IP)
+                .long("max-sessions-per-ip")
+                .value_name("COUNT")
+                .help("The number of sessions that can be connected from a single client IP address at the same time.")
+                .env("UNFTP_MAX_SESSIONS_PER_IP")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new(THROTTLE_DOWNLOAD_BPS)
+                .long("throttle-download-bps")
+                .value_name("BYTES_PER_SECOND")
+                .help("The bytes per second that all downloads together can use.")
+                .env("UNFTP_THROTTLE_DOWNLOAD_BPS")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new(THROTTLE_UPLOAD_BPS)
+                .long("throttle-upload-bps")
+                .value_name("BYTES_PER_SECOND")
+                .help("The bytes per second that all uploads together can use.")
+                .env("UNFTP_THROTTLE_UPLOAD_BPS")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new(THROTTLE_SESSION_DOWNLOAD_BPS)
+                .long("throttle-session-download-bps")
+                .value_name("BYTES_PER_SECOND")
+                .help("The bytes per second that the downloads of a single session can use.")
+                .env("UNFTP_THROTTLE_SESSION_DOWNLOAD_BPS")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new(THROTTLE_SESSION_UPLOAD_BPS)
+                .long("throttle-session-upload-bps")
+                .value_name("BYTES_PER_SECOND")
+                .help("The bytes per second that the uploads of a single session can use.")
+                .env("UNFTP_THROTTLE_SESSION_UPLOAD_BPS")
+                .takes_value(true),
+        )
         .arg(
             Arg::new(PROXY_EXTERNAL_CONTROL_PORT)
                 .long("proxy-external-control-port")
@@ -440,14 +720,151 @@ pub(crate) fn clap_app(tmp_dir: &str) -> Command<'_> {
                 .env("UNFTP_USR_JSON_PATH")
                 .takes_value(true),
         )
+        .arg(
+            Arg::new(USR_VFS_PERMS_DEFAULT)
+                .long("usr-vfs-perms-default")
+                .value_name("PERMS")
+                .help("The permissions users in the JSON user detail file start out with before their \
+                       vfs_perms are applied. With 'none' users may only do what their vfs_perms allow.")
+                .env("UNFTP_USR_VFS_PERMS_DEFAULT")
+                .possible_values(["all", "none"])
+                .default_value("all")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new(USR_ROOT_PATTERN)
+                .long("usr-root-pattern")
+                .value_name("PATTERN")
+                .help("The home directory of users that don't have a root in their user details, \
+                       like /home/{USER}. {USER} is replaced with the username.")
+                .env("UNFTP_USR_ROOT_PATTERN")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new(USR_CREATE_HOME)
+                .long("usr-create-home")
+                .help("Create the home directory of users on the storage back-end when they log in \
+                       and it doesn't exist yet.")
+                .env("UNFTP_USR_CREATE_HOME")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new(USR_HOME_SKELETON)
+                .long("usr-home-skeleton")
+                .value_name("DIRS")
+                .help("A comma separated list of directories, like incoming,outgoing,archive, to create \
+                       in new home directories.")
+                .env("UNFTP_USR_HOME_SKELETON")
+                .requires(USR_CREATE_HOME)
+                .takes_value(true),
+        )
         .arg(
             Arg::new(USR_HTTP_URL)
                 .long("usr-http-url")
                 .value_name("URL")
-                .help("The URL to fetch user details from via a GET request. The username will be appended to this path.")
+                .help("The URL to fetch user details from. {USER} and {IP} are replaced with the username and the \
+                       client's IP address, without them the username is appended.")
                 .env("UNFTP_USR_HTTP_URL")
                 .takes_value(true),
         )
+        .arg(
+            Arg::new(USR_HTTP_METHOD)
+                .long("usr-http-method")
+                .value_name("METHOD")
+                .help("The HTTP method used to fetch user details.")
+                .env("UNFTP_USR_HTTP_METHOD")
+                .possible_values(["GET", "POST"])
+                .default_value("GET")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new(USR_HTTP_BODY)
+                .long("usr-http-body")
+                .value_name("TEMPLATE")
+                .help("The request body sent to --usr-http-url, with {USER} and {IP} placeholders.")
+                .env("UNFTP_USR_HTTP_BODY")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new(USR_HTTP_AUTH_BEARER)
+                .long("usr-http-auth-bearer")
+                .value_name("TOKEN")
+                .help("Authenticates to the user detail service with this bearer token.")
+                .env("UNFTP_USR_HTTP_AUTH_BEARER")
+                .conflicts_with_all(&[USR_HTTP_AUTH_BASIC, USR_HTTP_AUTH_HEADER])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new(USR_HTTP_AUTH_BASIC)
+                .long("usr-http-auth-basic")
+                .value_name("USER:PASSWORD")
+                .help("Authenticates to the user detail service with HTTP basic authentication.")
+                .env("UNFTP_USR_HTTP_AUTH_BASIC")
+                .conflicts_with(USR_HTTP_AUTH_HEADER)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new(USR_HTTP_AUTH_HEADER)
+                .long("usr-http-auth-header")
+                .value_name("NAME: VALUE")
+                .help("Authenticates to the user detail service with a header of your choice, e.g. 'X-Api-Key: 123'.")
+                .env("UNFTP_USR_HTTP_AUTH_HEADER")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new(USR_HTTP_CA_FILE)
+                .long("usr-http-ca-file")
+                .value_name("PEM_FILE")
+                .help("Trusts only the CA certificates in this file for the user detail service instead of the system ones.")
+                .env("UNFTP_USR_HTTP_CA_FILE")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new(USR_HTTP_CLIENT_CERT)
+                .long("usr-http-client-cert")
+                .value_name("PEM_FILE")
+                .help("The client certificate chain presented to the user detail service.")
+                .env("UNFTP_USR_HTTP_CLIENT_CERT")
+                .requires(USR_HTTP_CLIENT_KEY)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new(USR_HTTP_CLIENT_KEY)
+                .long("usr-http-client-key")
+                .value_name("PEM_FILE")
+                .help("The private key of --usr-http-client-cert.")
+                .env("UNFTP_USR_HTTP_CLIENT_KEY")
+                .requires(USR_HTTP_CLIENT_CERT)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new(USR_HTTP_TIMEOUT)
+                .long("usr-http-timeout")
+                .value_name("SECONDS")
+                .help("How long to wait for the user detail service.")
+                .env("UNFTP_USR_HTTP_TIMEOUT")
+                .default_value("5")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new(USR_HTTP_RETRIES)
+                .long("usr-http-retries")
+                .value_name("COUNT")
+                .help("How many times to try again when the user detail service can't be reached, times out or \
+                       replies with a server error.")
+                .env("UNFTP_USR_HTTP_RETRIES")
+                .default_value("2")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new(USR_EXPIRY_WARNING_DAYS)
+                .long("usr-expiry-warning-days")
+                .value_name("DAYS")
+                .help("Logs and publishes an event once a day for every user in the --usr-json-path file whose \
+                       account or password expires within this many days.")
+                .env("UNFTP_USR_EXPIRY_WARNING_DAYS")
+                .takes_value(true),
+        )
         .arg(
             Arg::new(PUBSUB_BASE_URL)
                 .long("ntf-pubsub-base-url")
@@ -473,6 +890,68 @@ pub(crate) fn clap_app(tmp_dir: &str) -> Command<'_> {
                 .env("UNFTP_NTF_PUBSUB_PROJECT")
                 .takes_value(true),
         )
+        .arg(
+            Arg::new(PUBSUB_KEY_FILE)
+                .long("ntf-pubsub-key-file")
+                .value_name("KEY_FILE")
+                .help("The JSON file that contains the service account key for access to Google Pub/Sub. \
+                       Workload identity is used if neither this nor --ntf-pubsub-no-auth is given.")
+                .env("UNFTP_NTF_PUBSUB_KEY_FILE")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new(PUBSUB_NO_AUTH)
+                .long("ntf-pubsub-no-auth")
+                .help("Send no credentials to Google Pub/Sub. Use this with the Pub/Sub emulator.")
+                .env("UNFTP_NTF_PUBSUB_NO_AUTH"),
+        )
+        .arg(
+            Arg::new(PUBSUB_BATCH_SIZE)
+                .long("ntf-pubsub-batch-size")
+                .value_name("MESSAGES")
+                .help("The maximum number of events sent to Google Pub/Sub in one publish request (1-1000)")
+                .env("UNFTP_NTF_PUBSUB_BATCH_SIZE")
+                .default_value("100")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new(PUBSUB_BATCH_DELAY)
+                .long("ntf-pubsub-batch-delay")
+                .value_name("MILLISECONDS")
+                .help("How long to wait for more events before sending an incomplete batch to Google Pub/Sub. \
+                       With 0 events are sent right away.")
+                .env("UNFTP_NTF_PUBSUB_BATCH_DELAY")
+                .default_value("50")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new(PUBSUB_BUFFER_SIZE)
+                .long("ntf-pubsub-buffer-size")
+                .value_name("MESSAGES")
+                .help("The number of events that can wait to be published to Google Pub/Sub. FTP sessions \
+                       wait for room in the buffer when it is full.")
+                .env("UNFTP_NTF_PUBSUB_BUFFER_SIZE")
+                .default_value("1000")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new(HOOKS_FILE)
+                .long("hooks-file")
+                .value_name("FILE")
+                .help("The path to a JSON file that configures commands to run after files were uploaded, \
+                       renamed or deleted.")
+                .env("UNFTP_HOOKS_FILE")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new(NTF_ROUTES_FILE)
+                .long("ntf-routes-file")
+                .value_name("FILE")
+                .help("The path to a JSON file with rules that route events to Pub/Sub topics or webhooks. \
+                       Cannot be combined with --ntf-pubsub-topic.")
+                .env("UNFTP_NTF_ROUTES_FILE")
+                .takes_value(true),
+        )
         .arg(
             Arg::new(GLOG_LOGNAME)
                 .long("log-google-logname")
@@ -549,6 +1028,26 @@ pub(crate) fn clap_app(tmp_dir: &str) -> Command<'_> {
         cmd = auth_rest_commands(cmd);
     }
 
+    #[cfg(feature = "auth_ldap")]
+    {
+        cmd = auth_ldap_commands(cmd);
+    }
+
+    #[cfg(feature = "auth_jwt")]
+    {
+        cmd = auth_jwt_commands(cmd);
+    }
+
+    #[cfg(feature = "auth_sql")]
+    {
+        cmd = auth_sql_commands(cmd);
+    }
+
+    #[cfg(feature = "failed_logins_redis")]
+    {
+        cmd = failed_logins_redis_commands(cmd);
+    }
+
     #[cfg(feature = "tokio_console")]
     {
         cmd = cmd.arg(
@@ -562,7 +1061,103 @@ pub(crate) fn clap_app(tmp_dir: &str) -> Command<'_> {
         );
     }
 
-    cmd
+    cmd.subcommand(user_command())
+}
+
+// The `unftp user` subcommands that edit the JSON credentials and user detail files.
+fn user_command<'a>() -> Command<'a> {
+    let files = [
+        Arg::new(AUTH_JSON_PATH)
+            .long("auth-json-path")
+            .value_name("PATH")
+            .help("The JSON credentials file to edit")
+            .env("UNFTP_AUTH_JSON_PATH")
+            .takes_value(true),
+        Arg::new(USR_JSON_PATH)
+            .long("usr-json-path")
+            .value_name("PATH")
+            .help("The JSON user detail file to edit")
+            .env("UNFTP_USR_JSON_PATH")
+            .takes_value(true),
+        Arg::new(USER_ENCODING)
+            .long("encoding")
+            .value_name("ENCODING")
+            .help("How to write the files. By default they keep the encoding they have.")
+            .possible_values(["plain", "gzip", "gzip-base64"])
+            .takes_value(true),
+    ];
+    let username = Arg::new(USER_NAME)
+        .value_name("USERNAME")
+        .required(true)
+        .help("The name of the user");
+    let password = Arg::new(USER_PASSWORD)
+        .long("password")
+        .value_name("PASSWORD")
+        .help(
+            "The new password. Read from standard input if not given, which keeps it out of the \
+               process list.",
+        )
+        .takes_value(true);
+    Command::new(USER_CMD)
+        .about("Manage the users in JSON credentials and user detail files")
+        .subcommand_required(true)
+        .subcommand(
+            Command::new(USER_CMD_ADD)
+                .about("Add a user")
+                .args(files.clone())
+                .arg(username.clone())
+                .arg(password.clone())
+                .arg(
+                    Arg::new(USER_ROOT)
+                        .long("root")
+                        .value_name("PATH")
+                        .help("The user's home directory relative to the storage back-end root")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new(USER_VFS_PERMS)
+                        .long("vfs-perms")
+                        .value_name("PERMS")
+                        .help("Comma separated permissions, e.g. '-mkdir,-del'")
+                        .allow_hyphen_values(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new(USER_DISABLED)
+                        .long("disabled")
+                        .help("Add the account disabled"),
+                ),
+        )
+        .subcommand(
+            Command::new(USER_CMD_REMOVE)
+                .about("Remove a user")
+                .args(files.clone())
+                .arg(username.clone()),
+        )
+        .subcommand(
+            Command::new(USER_CMD_PASSWD)
+                .about("Change the password of a user")
+                .args(files.clone())
+                .arg(username.clone())
+                .arg(password),
+        )
+        .subcommand(
+            Command::new(USER_CMD_LIST)
+                .about("List the users")
+                .args(files.clone()),
+        )
+        .subcommand(
+            Command::new(USER_CMD_DISABLE)
+                .about("Disable the account of a user")
+                .args(files.clone())
+                .arg(username.clone()),
+        )
+        .subcommand(
+            Command::new(USER_CMD_ENABLE)
+                .about("Enable the account of a user again")
+                .args(files)
+                .arg(username),
+        )
 }
 
 #[cfg(feature = "sbe_iso")]
@@ -700,6 +1295,211 @@ fn auth_jsonfile_commands(cmd: Command) -> Command {
     )
 }
 
+#[cfg(feature = "auth_ldap")]
+fn auth_ldap_commands(cmd: Command) -> Command {
+    cmd.arg(
+        Arg::new(AUTH_LDAP_URL)
+            .long("auth-ldap-url")
+            .value_name("URL")
+            .help("The ldap:// or ldaps:// URL of the LDAP server")
+            .env("UNFTP_AUTH_LDAP_URL")
+            .takes_value(true),
+    )
+        .arg(
+            Arg::new(AUTH_LDAP_STARTTLS)
+                .long("auth-ldap-starttls")
+                .help("Upgrade ldap:// connections to TLS with StartTLS")
+                .env("UNFTP_AUTH_LDAP_STARTTLS"),
+        )
+        .arg(
+            Arg::new(AUTH_LDAP_USER_DN)
+                .long("auth-ldap-user-dn")
+                .value_name("TEMPLATE")
+                .help("The DN to bind with to authenticate users, with {USER} replaced by the username. \
+                       For example 'uid={USER},ou=people,dc=example,dc=com' or '{USER}@corp.example.com' for \
+                       Active Directory. Cannot be combined with --auth-ldap-search-base.")
+                .env("UNFTP_AUTH_LDAP_USER_DN")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new(AUTH_LDAP_SEARCH_BASE)
+                .long("auth-ldap-search-base")
+                .value_name("DN")
+                .help("Search for users under this DN and bind with the DN found to authenticate them")
+                .env("UNFTP_AUTH_LDAP_SEARCH_BASE")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new(AUTH_LDAP_SEARCH_FILTER)
+                .long("auth-ldap-search-filter")
+                .value_name("FILTER")
+                .help("The filter to find users by, with {USER} replaced by the username. For Active \
+                       Directory use '(sAMAccountName={USER})'.")
+                .env("UNFTP_AUTH_LDAP_SEARCH_FILTER")
+                .default_value("(uid={USER})")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new(AUTH_LDAP_BIND_DN)
+                .long("auth-ldap-bind-dn")
+                .value_name("DN")
+                .help("The DN of the service account to search with. Searches are anonymous if not set.")
+                .env("UNFTP_AUTH_LDAP_BIND_DN")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new(AUTH_LDAP_BIND_PASSWORD)
+                .long("auth-ldap-bind-password")
+                .value_name("PASSWORD")
+                .help("The password of the service account")
+                .env("UNFTP_AUTH_LDAP_BIND_PASSWORD")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new(AUTH_LDAP_REQUIRED_GROUPS)
+                .long("auth-ldap-required-groups")
+                .value_name("GROUP_DNS")
+                .help("A semicolon separated list of group DNs. Users have to be a member of at least one of them.")
+                .env("UNFTP_AUTH_LDAP_REQUIRED_GROUPS")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new(AUTH_LDAP_GROUP_ATTRIBUTE)
+                .long("auth-ldap-group-attribute")
+                .value_name("ATTRIBUTE")
+                .help("The user attribute that lists the groups the user is a member of")
+                .env("UNFTP_AUTH_LDAP_GROUP_ATTRIBUTE")
+                .default_value("memberOf")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new(AUTH_LDAP_POOL_SIZE)
+                .long("auth-ldap-pool-size")
+                .value_name("CONNECTIONS")
+                .help("The maximum number of idle LDAP connections to keep open")
+                .env("UNFTP_AUTH_LDAP_POOL_SIZE")
+                .default_value("5")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new(AUTH_LDAP_TIMEOUT)
+                .long("auth-ldap-timeout")
+                .value_name("SECONDS")
+                .help("The time limit for connecting to LDAP and for each authentication or user lookup")
+                .env("UNFTP_AUTH_LDAP_TIMEOUT")
+                .default_value("5")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new(USR_LDAP)
+                .long("usr-ldap")
+                .help("Get user details from the user's LDAP entry. Uses the --auth-ldap-* settings.")
+                .env("UNFTP_USR_LDAP"),
+        )
+        .arg(
+            Arg::new(USR_LDAP_ATTRIBUTES)
+                .long("usr-ldap-attributes")
+                .value_name("MAPPING")
+                .help("How user details map to LDAP attributes, as a comma separated list of \
+                       field=attribute pairs. The fields are name, surname, root and vfs_perms.")
+                .env("UNFTP_USR_LDAP_ATTRIBUTES")
+                .default_value("name=givenName,surname=sn")
+                .takes_value(true),
+        )
+}
+
+#[cfg(feature = "auth_jwt")]
+fn auth_jwt_commands(cmd: Command) -> Command {
+    cmd.arg(
+        Arg::new(AUTH_JWT_JWKS_URL)
+            .long("auth-jwt-jwks-url")
+            .value_name("URL")
+            .help("The URL of the JSON Web Key Set to validate tokens with, for example \
+                   https://idp.example.com/.well-known/jwks.json")
+            .env("UNFTP_AUTH_JWT_JWKS_URL")
+            .takes_value(true),
+    )
+        .arg(
+            Arg::new(AUTH_JWT_JWKS_FILE)
+                .long("auth-jwt-jwks-file")
+                .value_name("PATH")
+                .help("The path to a JSON Web Key Set file to validate tokens with")
+                .env("UNFTP_AUTH_JWT_JWKS_FILE")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new(AUTH_JWT_JWKS_REFRESH)
+                .long("auth-jwt-jwks-refresh")
+                .value_name("SECONDS")
+                .help("How long keys fetched from --auth-jwt-jwks-url are used before fetching them again")
+                .env("UNFTP_AUTH_JWT_JWKS_REFRESH")
+                .default_value("3600")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new(AUTH_JWT_ISSUER)
+                .long("auth-jwt-issuer")
+                .value_name("ISSUER")
+                .help("The required value of the iss claim")
+                .env("UNFTP_AUTH_JWT_ISSUER")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new(AUTH_JWT_AUDIENCE)
+                .long("auth-jwt-audience")
+                .value_name("AUDIENCE")
+                .help("The required value of the aud claim")
+                .env("UNFTP_AUTH_JWT_AUDIENCE")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new(AUTH_JWT_LEEWAY)
+                .long("auth-jwt-leeway")
+                .value_name("SECONDS")
+                .help("The clock skew to allow when checking the exp and nbf claims")
+                .env("UNFTP_AUTH_JWT_LEEWAY")
+                .default_value("60")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new(AUTH_JWT_USERNAME_CLAIM)
+                .long("auth-jwt-username-claim")
+                .value_name("CLAIM")
+                .help("The claim that has to match the FTP username")
+                .env("UNFTP_AUTH_JWT_USERNAME_CLAIM")
+                .default_value("sub")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new(AUTH_JWT_ROOT_CLAIM)
+                .long("auth-jwt-root-claim")
+                .value_name("CLAIM")
+                .help("The claim with the user's home directory")
+                .env("UNFTP_AUTH_JWT_ROOT_CLAIM")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new(AUTH_JWT_ROLES_CLAIM)
+                .long("auth-jwt-roles-claim")
+                .value_name("CLAIM")
+                .help("The claim with the user's roles. Use a dotted path like realm_access.roles for \
+                       nested claims.")
+                .env("UNFTP_AUTH_JWT_ROLES_CLAIM")
+                .default_value("roles")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new(AUTH_JWT_ROLE_PERMS)
+                .long("auth-jwt-role-perms")
+                .value_name("MAPPING")
+                .help("The permissions each role gives, for example 'reader=none +get +list;admin=all'. \
+                       Users get the permissions of all their roles combined, and none without a listed role. \
+                       Without this option users get all permissions.")
+                .env("UNFTP_AUTH_JWT_ROLE_PERMS")
+                .takes_value(true),
+        )
+}
+
 #[cfg(feature = "auth_rest")]
 fn auth_rest_commands(cmd: Command) -> Command {
     cmd.arg(
@@ -745,3 +1545,69 @@ fn auth_rest_commands(cmd: Command) -> Command {
                 .takes_value(true),
         )
 }
+
+#[cfg(feature = "auth_sql")]
+fn auth_sql_commands(cmd: Command) -> Command {
+    cmd.arg(
+        Arg::new(AUTH_SQL_URL)
+            .long("auth-sql-url")
+            .value_name("URL")
+            .help("The sqlite:// or postgres:// URL of the user database")
+            .env("UNFTP_AUTH_SQL_URL")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::new(AUTH_SQL_MIGRATE)
+            .long("auth-sql-migrate")
+            .help("Create or update the tables in the user database at startup")
+            .env("UNFTP_AUTH_SQL_MIGRATE"),
+    )
+    .arg(
+        Arg::new(AUTH_SQL_POOL_SIZE)
+            .long("auth-sql-pool-size")
+            .value_name("CONNECTIONS")
+            .help("The maximum number of connections to the user database")
+            .env("UNFTP_AUTH_SQL_POOL_SIZE")
+            .default_value("5")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::new(AUTH_SQL_TIMEOUT)
+            .long("auth-sql-timeout")
+            .value_name("SECONDS")
+            .help("How long to wait for a connection to the user database")
+            .env("UNFTP_AUTH_SQL_TIMEOUT")
+            .default_value("5")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::new(USR_SQL)
+            .long("usr-sql")
+            .help("Get user details from the user database. Uses the --auth-sql-* settings.")
+            .env("UNFTP_USR_SQL"),
+    )
+}
+
+#[cfg(feature = "failed_logins_redis")]
+fn failed_logins_redis_commands(cmd: Command) -> Command {
+    cmd.arg(
+        Arg::new(FAILED_REDIS_URL)
+            .long("failed-logins-redis-url")
+            .value_name("URL")
+            .help("Keeps the failed login attempts in the Redis server at this URL, e.g. redis://redis:6379/0, \
+                   so that all unFTP replicas that use it share them.")
+            .env("UNFTP_FAILED_LOGINS_REDIS_URL")
+            .takes_value(true)
+            .requires(FAILED_LOGINS_POLICY),
+    )
+    .arg(
+        Arg::new(FAILED_REDIS_PREFIX)
+            .long("failed-logins-redis-prefix")
+            .value_name("PREFIX")
+            .help("The prefix of the Redis keys that hold the failed login attempts.")
+            .env("UNFTP_FAILED_LOGINS_REDIS_PREFIX")
+            .takes_value(true)
+            .requires(FAILED_REDIS_URL)
+            .default_value("unftp:failed-logins:"),
+    )
+}
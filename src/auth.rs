@@ -1,54 +1,803 @@
-use crate::domain::user::{User, UserDetailError, UserDetailProvider};
+use crate::cache::AuthCache;
+use crate::client_cert::{self, IdentitySource};
+use crate::domain::events::FTPEventPayload;
+use crate::domain::user::{root_from_pattern, User, UserDetailError, UserDetailProvider};
+use crate::failed_logins::{FailedLogins, LockState};
+use crate::notify::FTPListener;
+use crate::totp::{self, Totp};
 use async_trait::async_trait;
-use libunftp::auth::{AuthenticationError, Credentials, DefaultUser};
+use chrono::Utc;
+use libunftp::auth::{AuthenticationError, Authenticator, Credentials, DefaultUser};
+use prometheus::{IntCounterVec, Opts};
+use slog::Logger;
+use std::{
+    collections::BTreeSet,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use strum_macros::{Display, EnumString};
+
+lazy_static! {
+    static ref LOGIN_REJECTIONS: IntCounterVec = prometheus::register_int_counter_vec!(
+        Opts::new(
+            "unftp_login_rejections_total",
+            "Logins with valid credentials that were rejected by the user's login restrictions."
+        ),
+        &["reason"]
+    )
+    .unwrap();
+}
+
+// The ID handed to the next successful login, see `User::login_id`.
+static NEXT_LOGIN_ID: AtomicU64 = AtomicU64::new(1);
+
+// The authenticator that checks the credentials.
+#[derive(Debug)]
+enum Inner {
+    // Only checks the credentials, the user details come from the user detail provider.
+    Credentials(Box<dyn Authenticator<DefaultUser>>),
+    // Also tells who the user is, for example from the claims in a token.
+    #[cfg(feature = "auth_jwt")]
+    User(Box<dyn Authenticator<User>>),
+    // Tries several authenticators in turn.
+    Chain(Chain),
+}
+
+/// The cache of user details and of what the authenticator found out about users whose passwords
+/// were valid.
+pub type LoginCache = AuthCache<Authenticated>;
+
+// What the inner authenticator found out about a user whose credentials were valid.
+#[derive(Debug, Default, Clone)]
+pub struct Authenticated {
+    user: Option<User>,
+    // The names of the chain steps that succeeded, joined with a '+'.
+    steps: Option<String>,
+}
+
+impl Inner {
+    async fn authenticate(
+        &self,
+        username: &str,
+        creds: &Credentials,
+    ) -> Result<Authenticated, AuthenticationError> {
+        match self {
+            Inner::Credentials(inner) => inner
+                .authenticate(username, creds)
+                .await
+                .map(|_| Authenticated::default()),
+            #[cfg(feature = "auth_jwt")]
+            Inner::User(inner) => {
+                inner
+                    .authenticate(username, creds)
+                    .await
+                    .map(|user| Authenticated {
+                        user: Some(user),
+                        steps: None,
+                    })
+            }
+            Inner::Chain(chain) => Box::pin(chain.authenticate(username, creds)).await,
+        }
+    }
+
+    async fn cert_auth_sufficient(&self, username: &str) -> bool {
+        match self {
+            Inner::Credentials(inner) => inner.cert_auth_sufficient(username).await,
+            #[cfg(feature = "auth_jwt")]
+            Inner::User(inner) => inner.cert_auth_sufficient(username).await,
+            Inner::Chain(chain) => Box::pin(chain.cert_auth_sufficient(username)).await,
+        }
+    }
+}
+
+/// How the outcome of a step in an authentication chain counts, modeled after the PAM control
+/// flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, Display)]
+#[strum(serialize_all = "lowercase")]
+pub enum ControlFlag {
+    /// The step has to succeed. If it fails the chain fails right away.
+    Required,
+    /// If the step succeeds the chain succeeds right away. A failure is ignored.
+    Sufficient,
+    /// A success is enough when there are no required steps. A failure is ignored.
+    Optional,
+}
+
+#[derive(Debug)]
+struct ChainStep {
+    name: String,
+    flag: ControlFlag,
+    inner: Inner,
+}
+
+#[derive(Debug)]
+struct Chain {
+    log: Arc<Logger>,
+    steps: Vec<ChainStep>,
+}
+
+impl Chain {
+    async fn authenticate(
+        &self,
+        username: &str,
+        creds: &Credentials,
+    ) -> Result<Authenticated, AuthenticationError> {
+        let mut succeeded: Vec<&str> = vec![];
+        let mut user = None;
+        let mut error = None;
+        for step in &self.steps {
+            match step.inner.authenticate(username, creds).await {
+                Ok(authenticated) => {
+                    slog::debug!(self.log, "Authentication step succeeded"; "step" => &step.name, "username" => username);
+                    user = user.or(authenticated.user);
+                    succeeded.push(&step.name);
+                    if step.flag == ControlFlag::Sufficient {
+                        break;
+                    }
+                }
+                Err(err) => {
+                    slog::debug!(self.log, "Authentication step failed: {}", err; "step" => &step.name, "username" => username);
+                    if step.flag == ControlFlag::Required {
+                        return Err(err);
+                    }
+                    // Only say the user is unknown if none of the steps knew them.
+                    if error
+                        .as_ref()
+                        .is_none_or(|e| matches!(e, AuthenticationError::BadUser))
+                    {
+                        error = Some(err);
+                    }
+                }
+            }
+        }
+        if succeeded.is_empty() {
+            return Err(error.unwrap_or(AuthenticationError::BadPassword));
+        }
+        let steps = succeeded.join("+");
+        slog::info!(self.log, "User authenticated"; "username" => username, "steps" => &steps);
+        Ok(Authenticated {
+            user,
+            steps: Some(steps),
+        })
+    }
+
+    // A client certificate is only enough if a sufficient step says so and no step is required.
+    async fn cert_auth_sufficient(&self, username: &str) -> bool {
+        if self.steps.iter().any(|s| s.flag == ControlFlag::Required) {
+            return false;
+        }
+        for step in self
+            .steps
+            .iter()
+            .filter(|s| s.flag == ControlFlag::Sufficient)
+        {
+            if step.inner.cert_auth_sufficient(username).await {
+                return true;
+            }
+        }
+        false
+    }
+}
 
 #[derive(Debug)]
 pub struct LookupAuthenticator {
-    inner: Box<dyn libunftp::auth::Authenticator<DefaultUser>>,
+    inner: Inner,
     usr_detail: Option<Box<dyn UserDetailProvider + Send + Sync>>,
+    listener: Option<Arc<FTPListener>>,
+    failed_logins: Option<Arc<FailedLogins>>,
+    totp: Option<Totp>,
+    cert_identity: Option<BTreeSet<IdentitySource>>,
+    cache: Option<Arc<LoginCache>>,
+    root_pattern: Option<String>,
+    log: Option<Arc<Logger>>,
 }
 
 impl LookupAuthenticator {
-    pub fn new<A: libunftp::auth::Authenticator<DefaultUser> + Send + Sync + 'static>(
-        inner: A,
-    ) -> Self {
+    // The authenticator around the given one, without any of the optional parts set.
+    fn base(inner: Inner) -> Self {
         LookupAuthenticator {
-            inner: Box::new(inner),
+            inner,
             usr_detail: None,
+            listener: None,
+            failed_logins: None,
+            totp: None,
+            cert_identity: None,
+            cache: None,
+            root_pattern: None,
+            log: None,
         }
     }
 
+    pub fn new<A: Authenticator<DefaultUser> + Send + Sync + 'static>(inner: A) -> Self {
+        Self::base(Inner::Credentials(Box::new(inner)))
+    }
+
+    /// Creates a lookup authenticator around an authenticator that also provides the user details.
+    /// These are used unless a user detail provider is set.
+    #[cfg(feature = "auth_jwt")]
+    pub fn with_user_details<A: Authenticator<User> + Send + Sync + 'static>(inner: A) -> Self {
+        Self::base(Inner::User(Box::new(inner)))
+    }
+
+    /// Creates a lookup authenticator that tries the given authenticators in order. Their user
+    /// detail providers, listeners and failed login counters are not used.
+    pub fn chain(log: Arc<Logger>, steps: Vec<(String, ControlFlag, LookupAuthenticator)>) -> Self {
+        let steps = steps
+            .into_iter()
+            .map(|(name, flag, auth)| ChainStep {
+                name,
+                flag,
+                inner: auth.inner,
+            })
+            .collect();
+        Self::base(Inner::Chain(Chain { log, steps }))
+    }
+
     pub fn set_usr_detail(&mut self, provider: Box<dyn UserDetailProvider + Send + Sync>) {
         self.usr_detail = Some(provider);
     }
+
+    /// Requires users that have a TOTP secret to append a code to their password.
+    pub fn set_totp(&mut self, totp: Totp) {
+        self.totp = Some(totp);
+    }
+
+    /// Requires client certificates to carry an identity from one of the given sources that is the
+    /// username or one of the user's `cert_identities`.
+    pub fn set_client_cert_identity(&mut self, sources: BTreeSet<IdentitySource>) {
+        self.cert_identity = Some(sources);
+    }
+
+    /// Caches user details and successful password checks.
+    pub fn set_cache(&mut self, cache: Arc<LoginCache>) {
+        self.cache = Some(cache);
+    }
+
+    /// Gives users without a root the home directory from the pattern, with `{USER}` replaced by
+    /// the username.
+    pub fn set_root_pattern(&mut self, pattern: String) {
+        self.root_pattern = Some(pattern);
+    }
+
+    /// Sets the logger that rejected logins are logged to.
+    pub fn set_logger(&mut self, log: Arc<Logger>) {
+        self.log = Some(log);
+    }
+
+    /// Sets the listener that security events like failed logins are dispatched to.
+    pub fn set_event_listener(&mut self, listener: Arc<FTPListener>) {
+        self.listener = Some(listener);
+    }
+
+    /// Sets the failed login counter that enforces the failed logins policy.
+    pub fn set_failed_logins(&mut self, failed_logins: Arc<FailedLogins>) {
+        self.failed_logins = Some(failed_logins);
+    }
+
+    // Looks up the user's details in the cache or else with the user detail provider, if there is
+    // one. The cache is keyed by username only, whatever address the details were fetched for.
+    async fn user_detail(
+        &self,
+        username: &str,
+        source_ip: Option<IpAddr>,
+    ) -> Option<Result<User, UserDetailError>> {
+        let provider = self.usr_detail.as_ref()?;
+        if let Some(cached) = self.cache.as_ref().and_then(|c| c.user(username)) {
+            return Some(cached);
+        }
+        let found = provider.provide_user_detail_for(username, source_ip).await;
+        if let Some(cache) = &self.cache {
+            cache.put_user(username, &found);
+        }
+        Some(found)
+    }
+
+    // Dispatches the event in the background so that it doesn't hold up the login reply.
+    fn report(&self, username: &str, payload: FTPEventPayload) {
+        if let Some(listener) = self.listener.clone() {
+            let username = username.to_string();
+            tokio::spawn(async move {
+                listener
+                    .dispatch_security_event(payload, Some(username), None)
+                    .await
+            });
+        }
+    }
+
+    // Tells why the user may not log in from this address or at this time, if they may not.
+    fn restriction(&self, user: &User, source_ip: IpAddr) -> Option<&'static str> {
+        if let Some(reason) = user.validity_error(Utc::now()) {
+            Some(reason)
+        } else if user
+            .allowed_ips
+            .as_ref()
+            .is_some_and(|nets| !nets.iter().any(|net| net.contains(&source_ip)))
+        {
+            Some("ip_not_allowed")
+        } else if user
+            .login_schedule
+            .as_ref()
+            .is_some_and(|schedule| !schedule.allows_now())
+        {
+            Some("outside_login_schedule")
+        } else {
+            None
+        }
+    }
+
+    // Reports a login that failed for another reason than wrong credentials, like an error of a
+    // back-end. These don't count towards the failed logins policy.
+    fn login_failed(&self, username: &str, source_ip: IpAddr, err: &AuthenticationError) {
+        self.report(
+            username,
+            FTPEventPayload::LoginFailed {
+                source_ip: source_ip.to_string(),
+                reason: err.to_string(),
+            },
+        );
+    }
+
+    // Reports a login with wrong credentials and counts it towards the failed logins policy.
+    async fn wrong_credentials(
+        &self,
+        username: &str,
+        source_ip: IpAddr,
+        err: &AuthenticationError,
+    ) {
+        self.login_failed(username, source_ip, err);
+        let Some(failed_logins) = &self.failed_logins else {
+            return;
+        };
+        // Like libunftp, we don't count attempts for unknown users.
+        if matches!(err, AuthenticationError::BadUser) {
+            return;
+        }
+        if let Some(LockState::MaxFailuresReached) = failed_logins.failed(source_ip, username).await
+        {
+            self.report(
+                username,
+                FTPEventPayload::LockedOut {
+                    source_ip: source_ip.to_string(),
+                    block_by: failed_logins.block_by().to_string(),
+                },
+            );
+        }
+    }
 }
 
 #[async_trait]
-impl libunftp::auth::Authenticator<User> for LookupAuthenticator {
+impl Authenticator<User> for LookupAuthenticator {
     async fn authenticate(
         &self,
         username: &str,
         creds: &Credentials,
     ) -> Result<User, AuthenticationError> {
-        self.inner.authenticate(username, creds).await?;
-        let user_provider = self.usr_detail.as_ref().unwrap();
-        Ok(user_provider
-            .provide_user_detail(username)
-            .await
-            .map_err(|e| AuthenticationError::with_source("error getting user detail", e))?)
+        // Users with a TOTP secret append a code to their password and client certificates can be
+        // tied to users in their details, so these are needed before the password can be checked.
+        let mut details = None;
+        if self.totp.is_some() || self.cert_identity.is_some() {
+            details = self.user_detail(username, Some(creds.source_ip)).await;
+        }
+        let known = details.as_ref().and_then(|found| found.as_ref().ok());
+
+        let mut cert_login = false;
+        let cert = creds.certificate_chain.as_ref().and_then(|c| c.first());
+        if let (Some(sources), Some(cert)) = (&self.cert_identity, cert) {
+            let matched = client_cert::identities(cert.as_ref(), sources).map(|ids| {
+                ids.iter().any(|id| {
+                    id == username
+                        || known
+                            .and_then(|u| u.cert_identities.as_ref())
+                            .is_some_and(|mapped| mapped.contains(id))
+                })
+            });
+            match matched {
+                Ok(true) => {
+                    cert_login = creds.password.is_none() && known.is_some_and(|u| u.cert_login)
+                }
+                Ok(false) => {
+                    let err = AuthenticationError::new("client certificate doesn't match the user");
+                    self.wrong_credentials(username, creds.source_ip, &err)
+                        .await;
+                    return Err(err);
+                }
+                Err(e) => {
+                    let err = AuthenticationError::new(e);
+                    self.login_failed(username, creds.source_ip, &err);
+                    return Err(err);
+                }
+            }
+        }
+
+        let mut totp_step = None;
+        let mut inner_creds = None;
+        if let (
+            Some(totp),
+            Some(User {
+                totp_secret: Some(secret),
+                ..
+            }),
+        ) = (&self.totp, known)
+        {
+            let checked = creds
+                .password
+                .as_deref()
+                .and_then(totp::split_code)
+                .and_then(|(password, code)| {
+                    totp.check(username, secret, code)
+                        .map(|step| (password, step))
+                });
+            let Some((password, step)) = checked else {
+                let err = AuthenticationError::new("missing, invalid or reused TOTP code");
+                self.wrong_credentials(username, creds.source_ip, &err)
+                    .await;
+                return Err(err);
+            };
+            totp_step = Some(step);
+            inner_creds = Some(Credentials {
+                password: Some(password.to_string()),
+                ..creds.clone()
+            });
+        }
+
+        // A matching certificate replaces the password for users that may log in with one.
+        let authenticated = if cert_login {
+            Authenticated::default()
+        } else {
+            let inner_creds = inner_creds.as_ref().unwrap_or(creds);
+            // Only password checks are cached, certificates may be checked by the authenticator too.
+            let cacheable = match (&self.cache, &inner_creds.password) {
+                (Some(cache), Some(password)) if inner_creds.certificate_chain.is_none() => {
+                    Some((cache, password))
+                }
+                _ => None,
+            };
+            let cached = cacheable.and_then(|(cache, password)| {
+                cache.credentials(username, password, creds.source_ip)
+            });
+            match cached {
+                Some(authenticated) => authenticated,
+                None => match self.inner.authenticate(username, inner_creds).await {
+                    Ok(authenticated) => {
+                        if let Some((cache, password)) = cacheable {
+                            cache.put_credentials(
+                                username,
+                                password,
+                                creds.source_ip,
+                                authenticated.clone(),
+                            );
+                        }
+                        authenticated
+                    }
+                    Err(err @ AuthenticationError::ImplPropagated(..)) => {
+                        self.login_failed(username, creds.source_ip, &err);
+                        return Err(err);
+                    }
+                    Err(err) => {
+                        self.wrong_credentials(username, creds.source_ip, &err)
+                            .await;
+                        return Err(err);
+                    }
+                },
+            }
+        };
+        let details = match details {
+            Some(found) => Some(found),
+            None => self.user_detail(username, Some(creds.source_ip)).await,
+        };
+        let mut user = match (details, authenticated.user) {
+            (Some(Ok(user)), _) => user,
+            (Some(Err(e)), _) => {
+                let err = AuthenticationError::with_source("error getting user detail", e);
+                self.login_failed(username, creds.source_ip, &err);
+                return Err(err);
+            }
+            (None, Some(user)) => user,
+            (None, None) => User::with_defaults(username),
+        };
+        // Another login may have used the same code while the password was checked.
+        if let (Some(totp), Some(step)) = (&self.totp, totp_step) {
+            if !totp.redeem(username, step) {
+                let err = AuthenticationError::new("missing, invalid or reused TOTP code");
+                self.wrong_credentials(username, creds.source_ip, &err)
+                    .await;
+                return Err(err);
+            }
+        }
+        user.source_ip = Some(creds.source_ip);
+        user.auth_steps = authenticated.steps;
+        user.login_id = NEXT_LOGIN_ID.fetch_add(1, Ordering::Relaxed);
+        if let (None, Some(pattern)) = (&user.root, &self.root_pattern) {
+            match root_from_pattern(pattern, username) {
+                Ok(root) => user.root = Some(root),
+                Err(e) => {
+                    let err = AuthenticationError::new(e);
+                    self.login_failed(username, creds.source_ip, &err);
+                    return Err(err);
+                }
+            }
+        }
+        // Users that may not log in right now are rejected with their own error rather than as
+        // wrong credentials, so that they don't count as failed logins.
+        let rejected = self.restriction(&user, creds.source_ip);
+
+        // libunftp isn't given the failed logins policy, it would only count the attempts of a
+        // single connection, so a client that is locked out is rejected here.
+        let locked = match &self.failed_logins {
+            Some(failed_logins) => failed_logins
+                .success(creds.source_ip, username)
+                .await
+                .is_some(),
+            None => false,
+        };
+        if locked {
+            let reason = "locked out by the failed logins policy";
+            self.report(
+                username,
+                FTPEventPayload::LoginFailed {
+                    source_ip: creds.source_ip.to_string(),
+                    reason: reason.to_string(),
+                },
+            );
+            if let Some(log) = &self.log {
+                slog::warn!(log, "Login rejected"; "username" => username, "source_ip" => creds.source_ip.to_string(), "reason" => "locked_out");
+            }
+            // Like libunftp, make the client wait a while.
+            tokio::time::sleep(std::time::Duration::from_millis(1500)).await;
+            return Err(AuthenticationError::new(reason));
+        } else if let Some(reason) = rejected {
+            LOGIN_REJECTIONS.with_label_values(&[reason]).inc();
+            if let Some(log) = &self.log {
+                slog::warn!(log, "Login rejected"; "username" => username, "source_ip" => creds.source_ip.to_string(), "reason" => reason);
+            }
+            self.report(
+                username,
+                FTPEventPayload::LoginFailed {
+                    source_ip: creds.source_ip.to_string(),
+                    reason: reason.to_string(),
+                },
+            );
+            return Err(match reason {
+                "ip_not_allowed" => AuthenticationError::IpDisallowed,
+                _ => AuthenticationError::new(format!("login not allowed: {}", reason)),
+            });
+        } else if !user.account_enabled {
+            self.report(
+                username,
+                FTPEventPayload::AccountDisabled {
+                    source_ip: creds.source_ip.to_string(),
+                },
+            );
+        }
+        Ok(user)
     }
 
     async fn cert_auth_sufficient(&self, username: &str) -> bool {
+        if self.cert_identity.is_some() {
+            if let Some(Ok(User {
+                cert_login: true, ..
+            })) = self.user_detail(username, None).await
+            {
+                return true;
+            }
+        }
         self.inner.cert_auth_sufficient(username).await
     }
 }
 
-#[derive(Debug)]
-pub struct DefaultUserProvider {}
+#[cfg(test)]
+mod tests {
+    use super::{ControlFlag, LookupAuthenticator};
+    use crate::domain::user::User;
+    use async_trait::async_trait;
+    use libunftp::auth::{AuthenticationError, Authenticator, Credentials, DefaultUser};
+    use std::sync::Arc;
 
-#[async_trait]
-impl UserDetailProvider for DefaultUserProvider {
-    async fn provide_user_detail(&self, username: &str) -> Result<User, UserDetailError> {
-        Ok(User::with_defaults(username))
+    // Accepts only the given password for known users.
+    #[derive(Debug)]
+    struct Fixed {
+        users: &'static [&'static str],
+        password: &'static str,
+    }
+
+    #[async_trait]
+    impl Authenticator<DefaultUser> for Fixed {
+        async fn authenticate(
+            &self,
+            username: &str,
+            creds: &Credentials,
+        ) -> Result<DefaultUser, AuthenticationError> {
+            if !self.users.contains(&username) {
+                return Err(AuthenticationError::BadUser);
+            }
+            match creds.password.as_deref() {
+                Some(p) if p == self.password => Ok(DefaultUser {}),
+                _ => Err(AuthenticationError::BadPassword),
+            }
+        }
+    }
+
+    fn chain(steps: &[(&str, ControlFlag, &'static [&'static str])]) -> LookupAuthenticator {
+        let log = Arc::new(slog::Logger::root(slog::Discard, slog::o!()));
+        LookupAuthenticator::chain(
+            log,
+            steps
+                .iter()
+                .map(|(name, flag, users)| {
+                    let password = if *name == "b" { "pw-b" } else { "pw" };
+                    (
+                        name.to_string(),
+                        *flag,
+                        LookupAuthenticator::new(Fixed { users, password }),
+                    )
+                })
+                .collect(),
+        )
+    }
+
+    async fn login(
+        auth: &LookupAuthenticator,
+        username: &str,
+        password: &str,
+    ) -> Result<Option<String>, AuthenticationError> {
+        auth.inner
+            .authenticate(username, &Credentials::from(password))
+            .await
+            .map(|a| a.steps)
+    }
+
+    #[tokio::test]
+    async fn chain_steps_follow_their_control_flags() {
+        use ControlFlag::*;
+
+        // Fall back to the next back-end when a sufficient one doesn't know the user.
+        let fallback = chain(&[("a", Sufficient, &["alice"]), ("b", Sufficient, &["bob"])]);
+        assert_eq!(
+            login(&fallback, "alice", "pw").await.unwrap().as_deref(),
+            Some("a")
+        );
+        assert_eq!(
+            login(&fallback, "bob", "pw-b").await.unwrap().as_deref(),
+            Some("b")
+        );
+        assert!(matches!(
+            login(&fallback, "carol", "pw").await,
+            Err(AuthenticationError::BadUser)
+        ));
+        assert!(matches!(
+            login(&fallback, "bob", "pw").await,
+            Err(AuthenticationError::BadPassword)
+        ));
+
+        // Both factors are needed when both steps are required.
+        let two_factors = chain(&[("a", Required, &["alice"]), ("c", Required, &["alice"])]);
+        assert_eq!(
+            login(&two_factors, "alice", "pw").await.unwrap().as_deref(),
+            Some("a+c")
+        );
+        let two_factors = chain(&[("a", Required, &["alice"]), ("b", Required, &["alice"])]);
+        assert!(login(&two_factors, "alice", "pw").await.is_err());
+
+        // A failed required step can't be made up for by a later sufficient one.
+        let required_first = chain(&[("b", Required, &["alice"]), ("a", Sufficient, &["alice"])]);
+        assert!(login(&required_first, "alice", "pw").await.is_err());
+
+        // Optional steps count only when nothing is required.
+        let optional = chain(&[("a", Optional, &["alice"]), ("b", Optional, &["alice"])]);
+        assert_eq!(
+            login(&optional, "alice", "pw").await.unwrap().as_deref(),
+            Some("a")
+        );
+    }
+    #[tokio::test]
+    async fn users_can_only_log_in_from_allowed_networks() {
+        use crate::infra::usrdetail_json::JsonUserProvider;
+
+        let mut auth = LookupAuthenticator::new(Fixed {
+            users: &["alice"],
+            password: "pw",
+        });
+        auth.set_usr_detail(Box::new(
+            JsonUserProvider::from_json(
+                r#"[{"username": "alice", "allowed_ips": ["10.0.0.0/8", "192.168.1.7"]}]"#,
+            )
+            .unwrap(),
+        ));
+        let from = |ip: &str| Credentials {
+            source_ip: ip.parse().unwrap(),
+            ..Credentials::from("pw")
+        };
+
+        let enabled = |user: Result<User, AuthenticationError>| user.unwrap().account_enabled;
+        assert!(enabled(auth.authenticate("alice", &from("10.1.2.3")).await));
+        assert!(enabled(
+            auth.authenticate("alice", &from("192.168.1.7")).await
+        ));
+        assert!(matches!(
+            auth.authenticate("alice", &from("192.168.1.8")).await,
+            Err(AuthenticationError::IpDisallowed)
+        ));
+    }
+    #[tokio::test]
+    async fn only_wrong_credentials_count_as_failed_logins() {
+        use crate::args::FailedLoginsPolicyType;
+        use crate::failed_logins::FailedLogins;
+        use crate::infra::usrdetail_json::JsonUserProvider;
+        use std::time::Duration;
+
+        let mut auth = LookupAuthenticator::new(Fixed {
+            users: &["alice", "bob"],
+            password: "pw",
+        });
+        // Bob's password is right but his details can't be found.
+        auth.set_usr_detail(Box::new(
+            JsonUserProvider::from_json(r#"[{"username": "alice"}]"#).unwrap(),
+        ));
+        let failed_logins = Arc::new(FailedLogins::new(
+            1,
+            Duration::from_secs(60),
+            FailedLoginsPolicyType::user,
+        ));
+        auth.set_failed_logins(failed_logins.clone());
+
+        assert!(auth
+            .authenticate("bob", &Credentials::from("pw"))
+            .await
+            .is_err());
+        assert!(failed_logins.blocks().await.unwrap().is_empty());
+        assert!(auth
+            .authenticate("alice", &Credentials::from("wrong"))
+            .await
+            .is_err());
+        assert_eq!(failed_logins.blocks().await.unwrap().len(), 1);
+    }
+    #[tokio::test]
+    async fn client_certificates_must_match_the_user() {
+        use crate::client_cert::{parse_sources, TEST_CERT};
+        use crate::infra::usrdetail_json::JsonUserProvider;
+        use data_encoding::BASE64;
+        use libunftp::auth::ClientCert;
+
+        let mut auth = LookupAuthenticator::new(Fixed {
+            users: &["bob", "alice"],
+            password: "pw",
+        });
+        auth.set_usr_detail(Box::new(
+            JsonUserProvider::from_json(
+                r#"[
+                    {"username": "bob", "cert_identities": ["bob-the-builder"], "cert_login": true},
+                    {"username": "alice"}
+                ]"#,
+            )
+            .unwrap(),
+        ));
+        auth.set_client_cert_identity(parse_sources("cn").unwrap());
+        let with_cert = |password: Option<&str>| Credentials {
+            certificate_chain: Some(vec![ClientCert(
+                BASE64.decode(TEST_CERT.as_bytes()).unwrap(),
+            )]),
+            password: password.map(str::to_string),
+            ..Credentials::from("")
+        };
+
+        assert!(auth.cert_auth_sufficient("bob").await);
+        assert!(!auth.cert_auth_sufficient("alice").await);
+        assert!(auth.authenticate("bob", &with_cert(None)).await.is_ok());
+        assert!(auth
+            .authenticate("bob", &with_cert(Some("pw")))
+            .await
+            .is_ok());
+        assert!(auth
+            .authenticate("bob", &with_cert(Some("wrong")))
+            .await
+            .is_err());
+        // Alice's password is right but the certificate is Bob's.
+        assert!(auth
+            .authenticate("alice", &with_cert(Some("pw")))
+            .await
+            .is_err());
     }
 }
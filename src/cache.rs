@@ -0,0 +1,253 @@
+//! Caches user details and successful credential checks for a while so that not every login hits
+//! the authentication and user detail back-ends.
+
+use crate::domain::user::{User, UserDetailError};
+use hmac::{Hmac, Mac};
+use prometheus::{IntCounterVec, Opts};
+use sha2::Sha256;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+lazy_static! {
+    static ref CACHE_LOOKUPS: IntCounterVec = prometheus::register_int_counter_vec!(
+        Opts::new(
+            "unftp_auth_cache_lookups_total",
+            "Lookups in the authentication caches by cache (user_detail or credentials) and result (hit or miss)."
+        ),
+        &["cache", "result"]
+    )
+    .unwrap();
+}
+
+/// How long entries are kept and how many.
+#[derive(Debug, Clone, Copy)]
+pub struct CacheSettings {
+    /// How long user details and successful credential checks are kept.
+    pub ttl: Duration,
+    /// How long it is remembered that a user doesn't exist.
+    pub negative_ttl: Duration,
+    /// The maximum number of entries in each cache.
+    pub max_entries: usize,
+}
+
+// A successful credential check. Only a salted HMAC of the password is kept.
+#[derive(Debug)]
+struct CheckedCredentials<T> {
+    source_ip: IpAddr,
+    salt: [u8; 16],
+    hash: [u8; 32],
+    outcome: T,
+}
+
+/// The user detail and credential caches shared by the authenticator and the admin endpoint that
+/// invalidates users.
+#[derive(Debug)]
+pub struct AuthCache<T> {
+    settings: CacheSettings,
+    // The random key for the password HMACs. It never leaves the process, so the HMACs can't be
+    // brute-forced from a memory dump of the cache alone.
+    key: [u8; 32],
+    // The details of users, or None for users that don't exist.
+    users: Mutex<Entries<Option<User>>>,
+    credentials: Mutex<Entries<CheckedCredentials<T>>>,
+}
+
+impl<T: Clone> AuthCache<T> {
+    pub fn new(settings: CacheSettings) -> Self {
+        AuthCache {
+            settings,
+            key: rand::random(),
+            users: Mutex::new(Entries::default()),
+            credentials: Mutex::new(Entries::default()),
+        }
+    }
+
+    /// Returns the cached outcome of looking up the user's details, if any.
+    pub fn user(&self, username: &str) -> Option<Result<User, UserDetailError>> {
+        let found = self.users.lock().unwrap().get(username).cloned();
+        count("user_detail", found.is_some());
+        found.map(|user| {
+            user.ok_or_else(|| UserDetailError::UserNotFound {
+                username: username.to_string(),
+            })
+        })
+    }
+
+    /// Remembers the outcome of a user detail lookup. Errors other than an unknown user are not
+    /// cached.
+    pub fn put_user(&self, username: &str, result: &Result<User, UserDetailError>) {
+        let (entry, ttl) = match result {
+            Ok(user) => (Some(user.clone()), self.settings.ttl),
+            Err(UserDetailError::UserNotFound { .. }) => (None, self.settings.negative_ttl),
+            Err(_) => return,
+        };
+        self.users.lock().unwrap().insert(
+            username.to_string(),
+            entry,
+            ttl,
+            self.settings.max_entries,
+        );
+    }
+
+    /// Returns the outcome of an earlier successful check of these credentials, if any.
+    pub fn credentials(&self, username: &str, password: &str, source_ip: IpAddr) -> Option<T> {
+        let found = self
+            .credentials
+            .lock()
+            .unwrap()
+            .get(username)
+            .filter(|c| {
+                c.source_ip == source_ip
+                    && crate::totp::constant_time_eq(&self.hash(&c.salt, password), &c.hash)
+            })
+            .map(|c| c.outcome.clone());
+        count("credentials", found.is_some());
+        found
+    }
+
+    /// Remembers a successful credential check.
+    pub fn put_credentials(&self, username: &str, password: &str, source_ip: IpAddr, outcome: T) {
+        let salt: [u8; 16] = rand::random();
+        let checked = CheckedCredentials {
+            source_ip,
+            salt,
+            hash: self.hash(&salt, password),
+            outcome,
+        };
+        self.credentials.lock().unwrap().insert(
+            username.to_string(),
+            checked,
+            self.settings.ttl,
+            self.settings.max_entries,
+        );
+    }
+
+    /// Forgets everything about the user. Returns whether there was anything to forget.
+    pub fn invalidate(&self, username: &str) -> bool {
+        let user = self.users.lock().unwrap().remove(username);
+        let credentials = self.credentials.lock().unwrap().remove(username);
+        user || credentials
+    }
+
+    /// Forgets everything.
+    pub fn clear(&self) {
+        self.users.lock().unwrap().clear();
+        self.credentials.lock().unwrap().clear();
+    }
+
+    fn hash(&self, salt: &[u8], password: &str) -> [u8; 32] {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC takes keys of any size");
+        mac.update(salt);
+        mac.update(password.as_bytes());
+        mac.finalize().into_bytes().into()
+    }
+}
+
+fn count(cache: &str, hit: bool) {
+    let result = if hit { "hit" } else { "miss" };
+    CACHE_LOOKUPS.with_label_values(&[cache, result]).inc();
+}
+
+// Entries that expire, with a maximum size.
+#[derive(Debug)]
+struct Entries<V> {
+    entries: HashMap<String, (Instant, V)>,
+}
+
+impl<V> Default for Entries<V> {
+    fn default() -> Self {
+        Entries {
+            entries: HashMap::new(),
+        }
+    }
+}
+
+impl<V> Entries<V> {
+    fn get(&self, key: &str) -> Option<&V> {
+        self.entries
+            .get(key)
+            .filter(|(expires, _)| *expires > Instant::now())
+            .map(|(_, value)| value)
+    }
+
+    // When full, expired entries are dropped first and then the one that expires soonest.
+    fn insert(&mut self, key: String, value: V, ttl: Duration, max_entries: usize) {
+        if max_entries == 0 {
+            return;
+        }
+        let now = Instant::now();
+        if self.entries.len() >= max_entries && !self.entries.contains_key(&key) {
+            self.entries.retain(|_, (expires, _)| *expires > now);
+            if self.entries.len() >= max_entries {
+                let soonest = self
+                    .entries
+                    .iter()
+                    .min_by_key(|(_, (expires, _))| *expires)
+                    .map(|(k, _)| k.clone());
+                if let Some(soonest) = soonest {
+                    self.entries.remove(&soonest);
+                }
+            }
+        }
+        self.entries.insert(key, (now + ttl, value));
+    }
+
+    fn remove(&mut self, key: &str) -> bool {
+        self.entries.remove(key).is_some()
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AuthCache, CacheSettings};
+    use crate::domain::user::{User, UserDetailError};
+    use std::time::Duration;
+
+    #[test]
+    fn entries_are_cached_until_they_expire_or_are_invalidated() {
+        let cache = AuthCache::<u32>::new(CacheSettings {
+            ttl: Duration::from_secs(60),
+            negative_ttl: Duration::ZERO,
+            max_entries: 2,
+        });
+        let ip = "10.0.0.1".parse().unwrap();
+
+        cache.put_credentials("alice", "secret", ip, 1);
+        assert_eq!(cache.credentials("alice", "secret", ip), Some(1));
+        assert_eq!(cache.credentials("alice", "guess", ip), None);
+        assert_eq!(
+            cache.credentials("alice", "secret", "10.0.0.2".parse().unwrap()),
+            None
+        );
+
+        cache.put_user("alice", &Ok(User::with_defaults("alice")));
+        cache.put_user(
+            "nobody",
+            &Err(UserDetailError::UserNotFound {
+                username: "nobody".to_string(),
+            }),
+        );
+        cache.put_user("broken", &Err(UserDetailError::new("timeout")));
+        assert!(matches!(cache.user("alice"), Some(Ok(_))));
+        // The negative entry expired right away and errors aren't cached at all.
+        assert!(cache.user("nobody").is_none());
+        assert!(cache.user("broken").is_none());
+
+        // The cache is full, so adding Bob drops the expired entry for nobody.
+        cache.put_user("bob", &Ok(User::with_defaults("bob")));
+        assert!(cache.user("alice").is_some() && cache.user("bob").is_some());
+
+        assert!(cache.invalidate("alice"));
+        assert!(cache.user("alice").is_none());
+        assert_eq!(cache.credentials("alice", "secret", ip), None);
+    }
+}
@@ -0,0 +1,109 @@
+//! Ties TLS client certificates to FTP users by the identities in their subject.
+
+use std::{collections::BTreeSet, str::FromStr};
+use x509_parser::{extensions::GeneralName, prelude::parse_x509_certificate};
+
+/// Where in a client certificate to look for the identity of the user.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum IdentitySource {
+    /// The common names (CN) of the subject.
+    Cn,
+    /// The email addresses in the subject alternative names or the subject.
+    Email,
+    /// The SPIFFE IDs in the URI subject alternative names.
+    Spiffe,
+}
+
+impl FromStr for IdentitySource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "cn" => Ok(IdentitySource::Cn),
+            "email" => Ok(IdentitySource::Email),
+            "spiffe" => Ok(IdentitySource::Spiffe),
+            other => Err(format!(
+                "unknown certificate identity '{}', use cn, email or spiffe",
+                other
+            )),
+        }
+    }
+}
+
+/// Parses a comma separated list of identity sources like `cn,spiffe`.
+pub fn parse_sources(s: &str) -> Result<BTreeSet<IdentitySource>, String> {
+    s.split(',').map(str::parse).collect()
+}
+
+/// Returns the identities found in the given sources of a DER encoded certificate.
+pub fn identities(der: &[u8], sources: &BTreeSet<IdentitySource>) -> Result<Vec<String>, String> {
+    let (_, cert) =
+        parse_x509_certificate(der).map_err(|e| format!("invalid client certificate: {}", e))?;
+    let subject = cert.subject();
+    let alt_names = match cert.subject_alternative_name() {
+        Ok(Some(san)) => san.value.general_names.clone(),
+        Ok(None) => vec![],
+        Err(e) => return Err(format!("invalid subject alternative names: {}", e)),
+    };
+    let mut found = vec![];
+    for source in sources {
+        match source {
+            IdentitySource::Cn => found.extend(
+                subject
+                    .iter_common_name()
+                    .filter_map(|cn| cn.as_str().ok().map(str::to_string)),
+            ),
+            IdentitySource::Email => {
+                found.extend(alt_names.iter().filter_map(|name| match name {
+                    GeneralName::RFC822Name(email) => Some(email.to_string()),
+                    _ => None,
+                }));
+                found.extend(
+                    subject
+                        .iter_email()
+                        .filter_map(|email| email.as_str().ok().map(str::to_string)),
+                );
+            }
+            IdentitySource::Spiffe => {
+                found.extend(alt_names.iter().filter_map(|name| match name {
+                    GeneralName::URI(uri) if uri.starts_with("spiffe://") => Some(uri.to_string()),
+                    _ => None,
+                }))
+            }
+        }
+    }
+    Ok(found)
+}
+
+// A self-signed certificate for CN=bob-the-builder with the alternative names
+// bob@example.com and spiffe://example.org/ftp/bob.
+#[cfg(test)]
+pub const TEST_CERT: &str = "\
+    MIIB7DCCAZGgAwIBAgIUbxZ1Dx/iS4qaBl6i/lOmxAl/W+AwCgYIKoZIzj0EAwIwLDEYMBYGA1UEAwwPYm9iLXRoZS1idWlsZGVy\
+    MRAwDgYDVQQKDAdFeGFtcGxlMCAXDTI2MTAxODIyMDMwM1oYDzIxMjYwOTI0MjIwMzAzWjAsMRgwFgYDVQQDDA9ib2ItdGhlLWJ1\
+    aWxkZXIxEDAOBgNVBAoMB0V4YW1wbGUwWTATBgcqhkjOPQIBBggqhkjOPQMBBwNCAATyXguiW+7eUWWYJCJ/+2sjG8iuGLKTqmxg\
+    sM8Z4QXpsYaQT8lkCaC0spAyeyWa4icZY7JX0w8OLDo93yvkTzCOo4GOMIGLMB0GA1UdDgQWBBQJODkHZemIV6UC/zjGMs7L4jnv\
+    aTAfBgNVHSMEGDAWgBQJODkHZemIV6UC/zjGMs7L4jnvaTAPBgNVHRMBAf8EBTADAQH/MDgGA1UdEQQxMC+BD2JvYkBleGFtcGxl\
+    LmNvbYYcc3BpZmZlOi8vZXhhbXBsZS5vcmcvZnRwL2JvYjAKBggqhkjOPQQDAgNJADBGAiEAo1koYI0W8Iev78bF6vjTM1L2UTb3\
+    mzer49NAYocBI68CIQCzFq68onygmv2FFjhMjva0hG+AWrXW5P4v5uqTlt2ddw==";
+
+#[cfg(test)]
+mod tests {
+    use super::{identities, parse_sources, TEST_CERT};
+    use data_encoding::BASE64;
+
+    #[test]
+    fn identities_are_taken_from_the_chosen_sources() {
+        let der = BASE64.decode(TEST_CERT.as_bytes()).unwrap();
+        assert_eq!(
+            identities(&der, &parse_sources("cn").unwrap()).unwrap(),
+            vec!["bob-the-builder"]
+        );
+        assert_eq!(
+            identities(&der, &parse_sources("spiffe,email").unwrap()).unwrap(),
+            vec!["bob@example.com", "spiffe://example.org/ftp/bob"]
+        );
+        assert!(parse_sources("cn,dns").is_err());
+        assert!(identities(b"not a certificate", &parse_sources("cn").unwrap()).is_err());
+    }
+}
@@ -3,8 +3,14 @@
 //! Packages elsewhere e.g. in the [`infra`](crate::infra) module implements these traits defined here.
 
 use async_trait::async_trait;
+use globset::{GlobBuilder, GlobMatcher};
 use serde::{Deserialize, Serialize};
 use std::fmt::Debug;
+use strum_macros::{IntoStaticStr, VariantNames};
+
+/// The version of the [`FTPEvent`] schema. Bump this when fields are removed or change meaning
+/// so that consumers can tell the formats apart. Version 1 was the format without this field.
+pub const EVENT_SCHEMA_VERSION: u32 = 2;
 
 // EventDispatcher can send events to the outside world.
 #[async_trait]
@@ -12,6 +18,13 @@ pub trait EventDispatcher<T>: Send + Sync + Debug {
     async fn dispatch(&self, event: T);
 }
 
+/// EventPublisher delivers a single event to the outside world and reports whether that worked,
+/// leaving it to the caller to decide what to do about failures.
+#[async_trait]
+pub trait EventPublisher<T>: Send + Sync + Debug {
+    async fn publish(&self, event: &T) -> Result<(), String>;
+}
+
 #[async_trait]
 impl EventDispatcher<FTPEvent> for NullEventDispatcher {
     async fn dispatch(&self, _event: FTPEvent) {
@@ -24,8 +37,11 @@ impl EventDispatcher<FTPEvent> for NullEventDispatcher {
 pub struct NullEventDispatcher {}
 
 // The event that will be sent
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FTPEvent {
+    /// The version of the event format. See [`EVENT_SCHEMA_VERSION`].
+    #[serde(default = "legacy_schema_version")]
+    pub schema_version: u32,
     pub source_instance: String,
     pub hostname: String,
     pub payload: FTPEventPayload,
@@ -40,20 +56,33 @@ pub struct FTPEvent {
     pub sequence_number: Option<u64>,
 }
 
-// The event variant
-#[derive(Serialize, Deserialize, Debug)]
+fn legacy_schema_version() -> u32 {
+    1
+}
+
+// The event variant. Its names as event types are listed in `FTPEventPayload::VARIANTS`.
+#[derive(Serialize, Deserialize, Debug, Clone, IntoStaticStr, VariantNames)]
+#[strum(serialize_all = "camelCase")]
 pub enum FTPEventPayload {
     Startup {
         libunftp_version: String,
         unftp_version: String,
     },
-    Login {},
+    Login {
+        /// The steps of the authentication chain that accepted the user, joined with a '+'.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        auth_steps: Option<String>,
+    },
     Logout {},
     Get {
         path: String,
+        #[serde(flatten)]
+        transfer: TransferDetail,
     },
     Put {
         path: String,
+        #[serde(flatten)]
+        transfer: TransferDetail,
     },
     Delete {
         path: String,
@@ -68,4 +97,147 @@ pub enum FTPEventPayload {
     RemoveDir {
         path: String,
     },
+    /// A login attempt was rejected.
+    LoginFailed {
+        source_ip: String,
+        reason: String,
+    },
+    /// Too many failed logins made the failed logins policy block further attempts.
+    LockedOut {
+        source_ip: String,
+        /// What the policy blocks by: 'ip', 'user' or 'combination'.
+        block_by: String,
+    },
+    /// The credentials were valid but the account is disabled.
+    AccountDisabled {
+        source_ip: String,
+    },
+    /// A client failed to upgrade the control channel to TLS.
+    TlsHandshakeFailed {
+        source_ip: String,
+        reason: String,
+    },
+    /// The user's account or password expires soon.
+    AccountExpiring {
+        /// What expires: 'account' or 'password'.
+        expires: String,
+        /// When it expires, in RFC 3339 format.
+        expires_at: String,
+        days_left: i64,
+    },
+}
+
+impl FTPEventPayload {
+    /// The name of the event type as used in the `eventType` Pub/Sub attribute and in event routes.
+    pub fn event_type(&self) -> &'static str {
+        self.into()
+    }
+
+    /// Tells whether this is one of the security related events.
+    pub fn is_security_event(&self) -> bool {
+        matches!(
+            self,
+            FTPEventPayload::LoginFailed { .. }
+                | FTPEventPayload::LockedOut { .. }
+                | FTPEventPayload::AccountDisabled { .. }
+                | FTPEventPayload::TlsHandshakeFailed { .. }
+        )
+    }
+
+    /// The file or directory paths this event pertains to, if any.
+    pub fn paths(&self) -> Vec<&str> {
+        match self {
+            FTPEventPayload::Get { path, .. }
+            | FTPEventPayload::Put { path, .. }
+            | FTPEventPayload::Delete { path }
+            | FTPEventPayload::MakeDir { path }
+            | FTPEventPayload::RemoveDir { path } => vec![path.as_str()],
+            FTPEventPayload::Rename { from, to } => vec![from.as_str(), to.as_str()],
+            _ => vec![],
+        }
+    }
+}
+
+/// Matches events on their username and paths with glob patterns, as event routes and hooks do.
+/// In path patterns a `*` doesn't match a `/` but a `**` does.
+#[derive(Debug, Default)]
+pub struct EventPatterns {
+    username: Option<GlobMatcher>,
+    path: Option<GlobMatcher>,
+}
+
+impl EventPatterns {
+    pub fn new(username: Option<&str>, path: Option<&str>) -> Result<Self, String> {
+        let glob = |pattern: Option<&str>, what: &str, literal_separator: bool| {
+            pattern
+                .map(|p| {
+                    GlobBuilder::new(p)
+                        .literal_separator(literal_separator)
+                        .build()
+                        .map(|g| g.compile_matcher())
+                        .map_err(|e| format!("invalid {} pattern: {}", what, e))
+                })
+                .transpose()
+        };
+        Ok(EventPatterns {
+            username: glob(username, "username", false)?,
+            path: glob(path, "path", true)?,
+        })
+    }
+
+    /// Tells whether the username and one of the paths of the event match the patterns, where
+    /// they were given.
+    pub fn matches(&self, event: &FTPEvent) -> bool {
+        let username_ok = self.username.as_ref().is_none_or(|m| {
+            event
+                .username
+                .as_deref()
+                .is_some_and(|username| m.is_match(username))
+        });
+        let path_ok = self
+            .path
+            .as_ref()
+            .is_none_or(|m| event.payload.paths().iter().any(|p| m.is_match(p)));
+        username_ok && path_ok
+    }
+}
+
+/// How a file transfer ended.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TransferOutcome {
+    #[default]
+    Succeeded,
+    Failed,
+}
+
+/// Details about a file transfer that accompany the [`Get`](FTPEventPayload::Get) and
+/// [`Put`](FTPEventPayload::Put) events. Apart from the byte count and the outcome, fields are only
+/// present when unFTP could determine them.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct TransferDetail {
+    /// The amount of bytes transferred, also when the transfer failed.
+    pub bytes: u64,
+    /// Whether the transfer succeeded or failed.
+    #[serde(default)]
+    pub outcome: TransferOutcome,
+    /// How long the transfer took on the storage back-end in milliseconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+    /// The IP address of the client.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_ip: Option<String>,
+    /// The MIME type as derived from the file extension.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    /// The storage back-end type as given to --sbe-type.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub storage_backend: Option<String>,
+    /// The hex encoded MD5 digest of the transferred bytes. Not set for resumed (REST) transfers.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub md5: Option<String>,
+    /// The path as passed to the storage back-end i.e. including the user's root. This is not
+    /// published.
+    #[serde(skip)]
+    pub storage_path: Option<String>,
 }
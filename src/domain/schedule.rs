@@ -0,0 +1,147 @@
+//! Time windows in which a user may log in.
+
+use chrono::{DateTime, Datelike, NaiveTime, TimeZone, Utc, Weekday};
+use chrono_tz::Tz;
+use serde::Deserialize;
+
+/// The days and times at which a user may log in.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LoginSchedule {
+    /// The IANA time zone the windows are in, e.g. `Europe/Amsterdam`. Defaults to UTC.
+    #[serde(default = "default_timezone")]
+    pub timezone: Tz,
+    pub windows: Vec<TimeWindow>,
+}
+
+fn default_timezone() -> Tz {
+    Tz::UTC
+}
+
+impl LoginSchedule {
+    /// Tells whether the time falls within one of the windows.
+    pub fn allows<T: TimeZone>(&self, time: &DateTime<T>) -> bool {
+        let local = time.with_timezone(&self.timezone);
+        let (day, time) = (local.weekday(), local.time());
+        self.windows.iter().any(|w| w.contains(day, time))
+    }
+
+    /// Tells whether logins are allowed right now.
+    pub fn allows_now(&self) -> bool {
+        self.allows(&Utc::now())
+    }
+}
+
+/// A daily time window like 08:00 to 18:00 on weekdays. A window that ends before it starts, like
+/// 22:00 to 06:00, runs past midnight and belongs to the day it starts on.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "RawTimeWindow")]
+pub struct TimeWindow {
+    days: Vec<Weekday>,
+    from: NaiveTime,
+    to: NaiveTime,
+}
+
+impl TimeWindow {
+    fn contains(&self, day: Weekday, time: NaiveTime) -> bool {
+        if self.from <= self.to {
+            self.days.contains(&day) && self.from <= time && time < self.to
+        } else {
+            (self.days.contains(&day) && time >= self.from)
+                || (self.days.contains(&day.pred()) && time < self.to)
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawTimeWindow {
+    #[serde(default)]
+    days: Option<Vec<String>>,
+    from: String,
+    to: String,
+}
+
+impl TryFrom<RawTimeWindow> for TimeWindow {
+    type Error = String;
+
+    fn try_from(raw: RawTimeWindow) -> Result<Self, Self::Error> {
+        let time = |s: &str| {
+            NaiveTime::parse_from_str(s, "%H:%M")
+                .map_err(|_| format!("invalid time '{}', use HH:MM", s))
+        };
+        let days = match raw.days {
+            Some(days) => {
+                let mut parsed = vec![];
+                for spec in &days {
+                    parsed.extend(parse_days(spec)?);
+                }
+                parsed
+            }
+            None => all_days(Weekday::Mon, Weekday::Sun),
+        };
+        Ok(TimeWindow {
+            days,
+            from: time(&raw.from)?,
+            to: time(&raw.to)?,
+        })
+    }
+}
+
+// Parses a day like `mon` or a range of days like `mon-fri`.
+fn parse_days(spec: &str) -> Result<Vec<Weekday>, String> {
+    let day = |s: &str| {
+        s.trim().parse::<Weekday>().map_err(|_| {
+            format!(
+                "invalid day '{}', use mon, tue, ... or a range like mon-fri",
+                s
+            )
+        })
+    };
+    match spec.split_once('-') {
+        Some((first, last)) => Ok(all_days(day(first)?, day(last)?)),
+        None => Ok(vec![day(spec)?]),
+    }
+}
+
+fn all_days(first: Weekday, last: Weekday) -> Vec<Weekday> {
+    let mut days = vec![first];
+    let mut day = first;
+    while day != last {
+        day = day.succ();
+        days.push(day);
+    }
+    days
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LoginSchedule;
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn windows_are_checked_in_the_schedule_timezone() {
+        let schedule: LoginSchedule = serde_json::from_str(
+            r#"{
+                "timezone": "Europe/Amsterdam",
+                "windows": [
+                    {"days": ["mon-fri"], "from": "08:00", "to": "18:00"},
+                    {"days": ["sat"], "from": "22:00", "to": "02:00"}
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        // Wednesday 2025-01-15 07:30 UTC is 08:30 in Amsterdam.
+        assert!(schedule.allows(&Utc.with_ymd_and_hms(2025, 1, 15, 7, 30, 0).unwrap()));
+        assert!(!schedule.allows(&Utc.with_ymd_and_hms(2025, 1, 15, 17, 30, 0).unwrap()));
+        // Sunday 00:30 in Amsterdam is still in Saturday's window, Monday 00:30 isn't.
+        assert!(schedule.allows(&Utc.with_ymd_and_hms(2025, 1, 18, 23, 30, 0).unwrap()));
+        assert!(!schedule.allows(&Utc.with_ymd_and_hms(2025, 1, 19, 23, 30, 0).unwrap()));
+
+        assert!(serde_json::from_str::<LoginSchedule>(
+            r#"{"windows": [{"days": ["someday"], "from": "08:00", "to": "18:00"}]}"#
+        )
+        .is_err());
+    }
+}
@@ -1,16 +1,22 @@
 //! Contains definitions pertaining to FTP User Detail
+use super::schedule::LoginSchedule;
 use async_trait::async_trait;
+use chrono::{DateTime, Days, Utc};
+use data_encoding::BASE32_NOPAD;
+use ipnet::IpNet;
 use libunftp::auth::UserDetail;
 use std::{
     fmt::{Debug, Display, Formatter},
+    net::IpAddr,
     path::PathBuf,
+    sync::Arc,
 };
 use thiserror::Error;
 use unftp_sbe_restrict::{UserWithPermissions, VfsOperations};
 use unftp_sbe_rooter::UserWithRoot;
 
 /// The unFTP user details
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct User {
     pub username: String,
     pub name: Option<String>,
@@ -23,6 +29,37 @@ pub struct User {
     pub allowed_mime_types: Option<Vec<String>>, // TODO: Look at https://crates.io/crates/infer to do this
     /// The user's home directory relative to the storage back-end root
     pub root: Option<PathBuf>,
+    /// The IP address of the client that logged in as this user. Set at login time.
+    pub source_ip: Option<IpAddr>,
+    /// The authentication chain steps that accepted the user, joined with a '+'. Set at login time.
+    pub auth_steps: Option<String>,
+    /// Tells this login apart from other logins of the same user. Set at login time.
+    pub login_id: u64,
+    /// If set, the user has to append a code for this TOTP secret to their password.
+    pub totp_secret: Option<TotpSecret>,
+    /// If set, the user can only log in from these networks.
+    pub allowed_ips: Option<Vec<IpNet>>,
+    /// If set, the user can only log in at these times.
+    pub login_schedule: Option<LoginSchedule>,
+    /// If set, the user can't log in before this time.
+    pub valid_from: Option<DateTime<Utc>>,
+    /// If set, the user can't log in after this time.
+    pub valid_until: Option<DateTime<Utc>>,
+    /// When the user last changed their password.
+    pub password_changed_at: Option<DateTime<Utc>>,
+    /// If set, the number of days after `password_changed_at` that the password expires.
+    pub max_password_age: Option<u32>,
+    /// The client certificate identities, like a CN, email address or SPIFFE ID, that belong to
+    /// this user next to the username itself.
+    pub cert_identities: Option<Vec<String>>,
+    /// Tells whether the user can log in with a matching client certificate and no password.
+    pub cert_login: bool,
+    /// If set, the bytes per second the user can download at, over all of their sessions.
+    pub max_download_bps: Option<u64>,
+    /// If set, the bytes per second the user can upload at, over all of their sessions.
+    pub max_upload_bps: Option<u64>,
+    /// If set, the number of sessions the user can have at the same time.
+    pub max_sessions: Option<u32>,
 }
 
 impl User {
@@ -35,6 +72,40 @@ impl User {
             vfs_permissions: VfsOperations::all(),
             allowed_mime_types: None,
             root: None,
+            source_ip: None,
+            auth_steps: None,
+            login_id: 0,
+            totp_secret: None,
+            allowed_ips: None,
+            login_schedule: None,
+            valid_from: None,
+            valid_until: None,
+            password_changed_at: None,
+            max_password_age: None,
+            cert_identities: None,
+            cert_login: false,
+            max_download_bps: None,
+            max_upload_bps: None,
+            max_sessions: None,
+        }
+    }
+
+    /// When the user's password expires, if it does.
+    pub fn password_expires_at(&self) -> Option<DateTime<Utc>> {
+        let changed_at = self.password_changed_at?;
+        changed_at.checked_add_days(Days::new(self.max_password_age?.into()))
+    }
+
+    /// Tells why the account or password is not valid at the given time, if it isn't.
+    pub fn validity_error(&self, now: DateTime<Utc>) -> Option<&'static str> {
+        if self.valid_from.is_some_and(|from| now < from) {
+            Some("account_not_yet_valid")
+        } else if self.valid_until.is_some_and(|until| now >= until) {
+            Some("account_expired")
+        } else if self.password_expires_at().is_some_and(|at| now >= at) {
+            Some("password_expired")
+        } else {
+            None
         }
     }
 }
@@ -67,6 +138,114 @@ impl UserWithPermissions for User {
     }
 }
 
+/// Makes the home directory of a user from a pattern like `/home/{USER}`. Usernames that would
+/// point elsewhere, like `..` or ones with a slash, are refused.
+pub fn root_from_pattern(pattern: &str, username: &str) -> Result<PathBuf, String> {
+    if username.is_empty()
+        || username == "."
+        || username == ".."
+        || username.contains(['/', '\\', '\0'])
+    {
+        return Err(format!(
+            "username '{}' can't be used in a home directory",
+            username
+        ));
+    }
+    Ok(PathBuf::from(pattern.replace("{USER}", username)))
+}
+
+/// Parses an IP network like `10.0.0.0/8` or a single IP address.
+pub fn parse_ip_net(s: &str) -> Result<IpNet, String> {
+    let s = s.trim();
+    s.parse::<IpNet>()
+        .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| format!("invalid IP address or network '{}'", s))
+}
+
+/// A base32 encoded TOTP secret as shown by authenticator apps. The secret is not printed by its
+/// `Debug` implementation.
+#[derive(Clone, PartialEq, Eq)]
+pub struct TotpSecret(String);
+
+impl TotpSecret {
+    /// Checks that the secret is valid base32 and wraps it.
+    pub fn new(secret: impl Into<String>) -> Result<Self, String> {
+        let secret = TotpSecret(secret.into());
+        secret.key()?;
+        Ok(secret)
+    }
+
+    /// The decoded key.
+    pub fn key(&self) -> Result<Vec<u8>, String> {
+        // Authenticator apps show secrets in groups, with or without padding.
+        let normalized: String = self
+            .0
+            .chars()
+            .filter(|c| !c.is_whitespace() && *c != '=')
+            .map(|c| c.to_ascii_uppercase())
+            .collect();
+        match BASE32_NOPAD.decode(normalized.as_bytes()) {
+            Ok(key) if !key.is_empty() => Ok(key),
+            Ok(_) => Err("TOTP secret is empty".to_string()),
+            Err(e) => Err(format!("TOTP secret is not valid base32: {}", e)),
+        }
+    }
+}
+
+impl Debug for TotpSecret {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("TotpSecret(..)")
+    }
+}
+
+/// Turns a list of permission settings like `["none", "+get", "+list"]` into the operations a user
+/// may perform. Settings are applied in order, starting from the given operations. Unknown settings
+/// are ignored, use [`validate_vfs_permissions`] to reject them.
+pub fn parse_vfs_permissions<S: AsRef<str>>(start: VfsOperations, perms: &[S]) -> VfsOperations {
+    perms.iter().fold(start, |ops, s| {
+        apply_vfs_permission(ops, s.as_ref()).unwrap_or(ops)
+    })
+}
+
+/// Checks that all the given `vfs_perms` tokens are known.
+pub fn validate_vfs_permissions<S: AsRef<str>>(perms: &[S]) -> Result<(), String> {
+    match perms
+        .iter()
+        .find(|s| apply_vfs_permission(VfsOperations::all(), s.as_ref()).is_none())
+    {
+        Some(unknown) => Err(format!(
+            "unknown vfs_perms value '{}', use none, all or +/- followed by mkdir, rmdir, del, ren, md5, get, put or list",
+            unknown.as_ref()
+        )),
+        None => Ok(()),
+    }
+}
+
+// Applies one vfs_perms token, or returns None if it's not known.
+fn apply_vfs_permission(ops: VfsOperations, token: &str) -> Option<VfsOperations> {
+    Some(match token {
+        "none" => VfsOperations::empty(),
+        "all" => VfsOperations::all(),
+        "-mkdir" => ops - VfsOperations::MK_DIR,
+        "-rmdir" => ops - VfsOperations::RM_DIR,
+        "-del" => ops - VfsOperations::DEL,
+        "-ren" => ops - VfsOperations::RENAME,
+        "-md5" => ops - VfsOperations::MD5,
+        "-get" => ops - VfsOperations::GET,
+        "-put" => ops - VfsOperations::PUT,
+        "-list" => ops - VfsOperations::LIST,
+        "+mkdir" => ops | VfsOperations::MK_DIR,
+        "+rmdir" => ops | VfsOperations::RM_DIR,
+        "+del" => ops | VfsOperations::DEL,
+        "+ren" => ops | VfsOperations::RENAME,
+        "+md5" => ops | VfsOperations::MD5,
+        "+get" => ops | VfsOperations::GET,
+        "+put" => ops | VfsOperations::PUT,
+        "+list" => ops | VfsOperations::LIST,
+        _ => return None,
+    })
+}
+
 /// Implementation of UserDetailProvider can look up and provide FTP user account details from
 /// a source.
 #[async_trait]
@@ -74,6 +253,42 @@ pub trait UserDetailProvider: Debug {
     /// This will do the lookup. An error is returned if the user was not found or something else
     /// went wrong.
     async fn provide_user_detail(&self, username: &str) -> Result<User, UserDetailError>;
+
+    /// Like [`provide_user_detail`](Self::provide_user_detail) but also tells the provider where
+    /// the client connects from, if known. Most providers ignore it.
+    async fn provide_user_detail_for(
+        &self,
+        username: &str,
+        _source_ip: Option<IpAddr>,
+    ) -> Result<User, UserDetailError> {
+        self.provide_user_detail(username).await
+    }
+
+    /// Lists the details of all users, for providers that know them all up front.
+    async fn list_users(&self) -> Result<Vec<User>, UserDetailError> {
+        Err(UserDetailError::Generic(
+            "this user detail provider can't list users".to_string(),
+        ))
+    }
+}
+
+#[async_trait]
+impl<P: UserDetailProvider + Send + Sync + ?Sized> UserDetailProvider for Arc<P> {
+    async fn provide_user_detail(&self, username: &str) -> Result<User, UserDetailError> {
+        (**self).provide_user_detail(username).await
+    }
+
+    async fn provide_user_detail_for(
+        &self,
+        username: &str,
+        source_ip: Option<IpAddr>,
+    ) -> Result<User, UserDetailError> {
+        (**self).provide_user_detail_for(username, source_ip).await
+    }
+
+    async fn list_users(&self) -> Result<Vec<User>, UserDetailError> {
+        (**self).list_users().await
+    }
 }
 
 /// The error type returned by [`UserDetailProvider`]
@@ -0,0 +1,108 @@
+//! Warns about user accounts and passwords that are about to expire.
+
+use crate::{
+    domain::{
+        events::FTPEventPayload,
+        user::{User, UserDetailProvider},
+    },
+    notify::FTPListener,
+};
+use chrono::{DateTime, Duration, Utc};
+use slog::Logger;
+use std::sync::Arc;
+
+// How often the users are checked.
+const CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(24 * 60 * 60);
+
+/// Checks the users right away and then once a day, and logs and publishes an
+/// [`AccountExpiring`](FTPEventPayload::AccountExpiring) event for every account or password that
+/// expires within the given number of days.
+pub fn spawn_warnings(
+    log: Arc<Logger>,
+    users: Arc<dyn UserDetailProvider + Send + Sync>,
+    listener: Arc<FTPListener>,
+    within_days: u32,
+) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            let users = match users.list_users().await {
+                Ok(users) => users,
+                Err(e) => {
+                    slog::error!(log, "Could not list users to check for expiry: {}", e);
+                    continue;
+                }
+            };
+            let now = Utc::now();
+            for user in &users {
+                for (expires, at) in expiring(user, now, Duration::days(within_days.into())) {
+                    let days_left = (at - now).num_days();
+                    slog::warn!(log, "User {} expires soon", expires; "username" => &user.username, "expires_at" => at.to_rfc3339(), "days_left" => days_left);
+                    listener
+                        .dispatch_account_event(
+                            FTPEventPayload::AccountExpiring {
+                                expires: expires.to_string(),
+                                expires_at: at.to_rfc3339(),
+                                days_left,
+                            },
+                            user.username.clone(),
+                        )
+                        .await;
+                }
+            }
+        }
+    });
+}
+
+// What of the user expires within the given time, 'account' or 'password', and when. Things that
+// expired already are left out, the user is reminded of those when they try to log in.
+fn expiring(
+    user: &User,
+    now: DateTime<Utc>,
+    within: Duration,
+) -> Vec<(&'static str, DateTime<Utc>)> {
+    [
+        ("account", user.valid_until),
+        ("password", user.password_expires_at()),
+    ]
+    .into_iter()
+    .filter_map(|(expires, at)| at.map(|at| (expires, at)))
+    .filter(|(_, at)| *at > now && *at - now <= within)
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::expiring;
+    use crate::domain::user::User;
+    use chrono::{Duration, TimeZone, Utc};
+
+    #[test]
+    fn accounts_and_passwords_expiring_soon_are_found() {
+        let now = Utc.with_ymd_and_hms(2025, 3, 1, 12, 0, 0).unwrap();
+        let mut user = User::with_defaults("contractor");
+        user.valid_until = Some(Utc.with_ymd_and_hms(2025, 3, 5, 0, 0, 0).unwrap());
+        user.password_changed_at = Some(Utc.with_ymd_and_hms(2025, 1, 1, 0, 0, 0).unwrap());
+        user.max_password_age = Some(90);
+
+        assert_eq!(
+            expiring(&user, now, Duration::days(7)),
+            vec![("account", user.valid_until.unwrap())]
+        );
+        assert_eq!(
+            expiring(&user, now, Duration::days(31))
+                .iter()
+                .map(|(what, _)| *what)
+                .collect::<Vec<_>>(),
+            vec!["account", "password"]
+        );
+        assert_eq!(user.validity_error(now), None);
+        let later = Utc.with_ymd_and_hms(2025, 3, 5, 0, 0, 0).unwrap();
+        assert_eq!(user.validity_error(later), Some("account_expired"));
+        assert!(expiring(&user, later, Duration::days(7)).is_empty());
+        user.valid_until = None;
+        let april = Utc.with_ymd_and_hms(2025, 4, 1, 0, 0, 0).unwrap();
+        assert_eq!(user.validity_error(april), Some("password_expired"));
+    }
+}
@@ -0,0 +1,550 @@
+//! Keeps count of failed logins the same way libunftp's
+//! [`FailedLoginsPolicy`](libunftp::options::FailedLoginsPolicy) does.
+//!
+//! libunftp is not given the policy, since it doesn't tell us when it blocks a client and doesn't
+//! let us lift its blocks. Instead the [`LookupAuthenticator`](crate::auth::LookupAuthenticator)
+//! enforces it with the counts from here, which can be shared between replicas through Redis when
+//! unFTP is built with the `failed_logins_redis` feature.
+
+use crate::args::FailedLoginsPolicyType;
+#[cfg(feature = "failed_logins_redis")]
+use redis::aio::{ConnectionManager, ConnectionManagerConfig};
+use serde::Serialize;
+#[cfg(feature = "failed_logins_redis")]
+use slog::Logger;
+#[cfg(feature = "failed_logins_redis")]
+use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+
+/// The outcome of registering a login attempt while the policy applies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockState {
+    /// With this failed login attempt the lockout threshold has been reached
+    MaxFailuresReached,
+    /// The client is already locked out from previous failed attempts
+    AlreadyLocked,
+}
+
+#[derive(Hash, Eq, PartialEq, Debug, Clone)]
+struct Key {
+    ip: Option<IpAddr>,
+    username: Option<String>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    attempts: u32,
+    last_attempt_at: Instant,
+}
+
+/// A client that is locked out, as shown on the HTTP admin API.
+#[derive(Debug, Serialize)]
+pub struct Block {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip: Option<IpAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    pub attempts: u32,
+    /// Seconds until the block is lifted, unless there are more failed attempts.
+    pub expires_in: u64,
+}
+
+#[cfg(feature = "failed_logins_redis")]
+lazy_static! {
+    // Forgets the attempts of a client that isn't locked out and tells whether it is, in one step so
+    // that another replica can't count a failed attempt in between.
+    static ref SUCCESS_SCRIPT: redis::Script = redis::Script::new(
+        r"
+        local attempts = tonumber(redis.call('GET', KEYS[1]))
+        if attempts and attempts >= tonumber(ARGV[1]) then
+            return 1
+        end
+        redis.call('DEL', KEYS[1])
+        return 0
+        ",
+    );
+}
+
+// Counts the attempts in Redis keys that expire after the policy's period, shared by all replicas.
+#[cfg(feature = "failed_logins_redis")]
+#[derive(Clone)]
+struct RedisStore {
+    log: Arc<Logger>,
+    // Reconnects by itself when the connection is lost.
+    con: ConnectionManager,
+    prefix: String,
+}
+
+/// Counts failed login attempts per IP, user or both.
+pub struct FailedLogins {
+    max_attempts: u32,
+    expires_after: Duration,
+    block_by: FailedLoginsPolicyType,
+    // The attempts counted by this replica alone, which is all of them without Redis and those made
+    // while Redis was unavailable with it.
+    memory: Mutex<HashMap<Key, Entry>>,
+    #[cfg(feature = "failed_logins_redis")]
+    redis: Option<RedisStore>,
+}
+
+impl std::fmt::Debug for FailedLogins {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut f = f.debug_struct("FailedLogins");
+        f.field("max_attempts", &self.max_attempts)
+            .field("expires_after", &self.expires_after)
+            .field("block_by", &self.block_by);
+        #[cfg(feature = "failed_logins_redis")]
+        f.field("shared", &self.redis.is_some());
+        f.finish()
+    }
+}
+
+impl FailedLogins {
+    pub fn new(
+        max_attempts: u32,
+        expires_after: Duration,
+        block_by: FailedLoginsPolicyType,
+    ) -> Self {
+        FailedLogins {
+            max_attempts,
+            expires_after,
+            block_by,
+            memory: Mutex::new(HashMap::new()),
+            #[cfg(feature = "failed_logins_redis")]
+            redis: None,
+        }
+    }
+
+    /// Counts the attempts in the Redis server at the given URL, in keys that start with the
+    /// prefix, so that they are shared with the other replicas. While Redis is unavailable the
+    /// attempts are counted in memory.
+    #[cfg(feature = "failed_logins_redis")]
+    pub async fn with_redis(
+        max_attempts: u32,
+        expires_after: Duration,
+        block_by: FailedLoginsPolicyType,
+        log: Arc<Logger>,
+        url: &str,
+        prefix: String,
+    ) -> Result<Self, String> {
+        let client = redis::Client::open(url).map_err(|e| e.to_string())?;
+        // Logins wait for Redis, so don't wait long when it is unavailable.
+        let config = ConnectionManagerConfig::new()
+            .set_connection_timeout(Some(Duration::from_secs(2)))
+            .set_response_timeout(Some(Duration::from_secs(2)))
+            .set_number_of_retries(1);
+        let con = ConnectionManager::new_with_config(client, config)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(FailedLogins {
+            redis: Some(RedisStore { log, con, prefix }),
+            ..FailedLogins::new(max_attempts, expires_after, block_by)
+        })
+    }
+
+    /// Tells what the policy blocks by, as given to --failed-logins-policy.
+    pub fn block_by(&self) -> &'static str {
+        match self.block_by {
+            FailedLoginsPolicyType::ip => "ip",
+            FailedLoginsPolicyType::user => "user",
+            FailedLoginsPolicyType::combination => "combination",
+        }
+    }
+
+    fn key(&self, ip: IpAddr, username: &str) -> Key {
+        match self.block_by {
+            FailedLoginsPolicyType::ip => Key {
+                ip: Some(ip),
+                username: None,
+            },
+            FailedLoginsPolicyType::user => Key {
+                ip: None,
+                username: Some(username.to_string()),
+            },
+            FailedLoginsPolicyType::combination => Key {
+                ip: Some(ip),
+                username: Some(username.to_string()),
+            },
+        }
+    }
+
+    fn lock_state(&self, attempts: u32) -> Option<LockState> {
+        match attempts {
+            a if a == self.max_attempts => Some(LockState::MaxFailuresReached),
+            a if a > self.max_attempts => Some(LockState::AlreadyLocked),
+            _ => None,
+        }
+    }
+
+    /// Registers a failed login and returns the lock state if the client is now locked out.
+    pub async fn failed(&self, ip: IpAddr, username: &str) -> Option<LockState> {
+        let key = self.key(ip, username);
+        #[cfg(feature = "failed_logins_redis")]
+        if let Some(redis) = &self.redis {
+            if let Ok(attempts) = redis.failed(&key, self.expires_after).await {
+                return self.lock_state(attempts);
+            }
+        }
+        let mut entries = self.memory.lock().await;
+        entries.retain(|_, e| e.last_attempt_at.elapsed() <= self.expires_after);
+        let entry = entries.entry(key).or_insert(Entry {
+            attempts: 0,
+            last_attempt_at: Instant::now(),
+        });
+        entry.attempts += 1;
+        entry.last_attempt_at = Instant::now();
+        self.lock_state(entry.attempts)
+    }
+
+    /// Registers a successful login and returns a lock state if the client is still locked out.
+    /// Otherwise the failed attempts are forgotten.
+    pub async fn success(&self, ip: IpAddr, username: &str) -> Option<LockState> {
+        let key = self.key(ip, username);
+        let locked = {
+            let mut entries = self.memory.lock().await;
+            let locked = entries.get(&key).is_some_and(|e| {
+                e.last_attempt_at.elapsed() <= self.expires_after && e.attempts >= self.max_attempts
+            });
+            if !locked {
+                entries.remove(&key);
+            }
+            locked
+        };
+        #[cfg(feature = "failed_logins_redis")]
+        let locked = match &self.redis {
+            Some(redis) if !locked => redis
+                .success(&key, self.max_attempts)
+                .await
+                .unwrap_or(false),
+            _ => locked,
+        };
+        locked.then_some(LockState::AlreadyLocked)
+    }
+
+    /// Lists the clients that are locked out.
+    pub async fn blocks(&self) -> Result<Vec<Block>, String> {
+        #[cfg_attr(not(feature = "failed_logins_redis"), allow(unused_mut))]
+        let mut entries: HashMap<Key, (u32, u64)> = self
+            .memory
+            .lock()
+            .await
+            .iter()
+            .filter_map(|(key, e)| {
+                let left = self
+                    .expires_after
+                    .checked_sub(e.last_attempt_at.elapsed())?;
+                Some((key.clone(), (e.attempts, left.as_secs())))
+            })
+            .collect();
+        #[cfg(feature = "failed_logins_redis")]
+        if let Some(redis) = &self.redis {
+            for (key, attempts, expires_in) in redis.entries(&self.block_by).await? {
+                let entry = entries.entry(key).or_insert((0, 0));
+                *entry = (entry.0.max(attempts), entry.1.max(expires_in));
+            }
+        }
+        let mut blocks: Vec<Block> = entries
+            .into_iter()
+            .filter(|(_, (attempts, _))| *attempts >= self.max_attempts)
+            .map(|(key, (attempts, expires_in))| Block {
+                ip: key.ip,
+                username: key.username,
+                attempts,
+                expires_in,
+            })
+            .collect();
+        blocks.sort_by(|a, b| (a.ip, &a.username).cmp(&(b.ip, &b.username)));
+        Ok(blocks)
+    }
+
+    /// Forgets the failed attempts of the given IP address or user, or of everyone if neither is
+    /// given, which lifts their blocks. Tells how many entries were removed. When Redis can't be
+    /// reached the attempts counted in memory are kept as well, so that the blocks stay in place.
+    pub async fn lift(&self, ip: Option<IpAddr>, username: Option<&str>) -> Result<usize, String> {
+        let matches = |key: &Key| {
+            ip.is_none_or(|ip| key.ip == Some(ip))
+                && username.is_none_or(|u| key.username.as_deref() == Some(u))
+        };
+        #[cfg(feature = "failed_logins_redis")]
+        let lifted = match &self.redis {
+            Some(redis) => redis.lift(&self.block_by, matches).await?,
+            None => 0,
+        };
+        #[cfg(not(feature = "failed_logins_redis"))]
+        let lifted = 0;
+        let mut entries = self.memory.lock().await;
+        let before = entries.len();
+        entries.retain(|key, _| !matches(key));
+        Ok(lifted + before - entries.len())
+    }
+}
+
+#[cfg(feature = "failed_logins_redis")]
+impl RedisStore {
+    // Counts a failed attempt and returns the attempts within the policy's period.
+    async fn failed(&self, key: &Key, expires_after: Duration) -> Result<u32, String> {
+        let name = self.name(key);
+        let result = redis::pipe()
+            .atomic()
+            .incr(&name, 1)
+            .expire(&name, expires_after.as_secs().max(1) as i64)
+            .ignore()
+            .query_async::<(u32,)>(&mut self.con.clone())
+            .await;
+        self.check(result).map(|(attempts,)| attempts)
+    }
+
+    // Tells whether the client is locked out and forgets its attempts if it isn't.
+    async fn success(&self, key: &Key, max_attempts: u32) -> Result<bool, String> {
+        let result = SUCCESS_SCRIPT
+            .key(self.name(key))
+            .arg(max_attempts)
+            .invoke_async(&mut self.con.clone())
+            .await;
+        self.check(result)
+    }
+
+    // Lists the entries with their attempts and the seconds until they expire.
+    async fn entries(
+        &self,
+        block_by: &FailedLoginsPolicyType,
+    ) -> Result<Vec<(Key, u32, u64)>, String> {
+        let result = async {
+            let mut con = self.con.clone();
+            let mut entries = vec![];
+            for (name, key) in self.keys(block_by).await? {
+                let (attempts, ttl): (Option<u32>, i64) = redis::pipe()
+                    .cmd("GET")
+                    .arg(&name)
+                    .cmd("TTL")
+                    .arg(&name)
+                    .query_async(&mut con)
+                    .await?;
+                if let Some(attempts) = attempts {
+                    entries.push((key, attempts, ttl.max(0) as u64));
+                }
+            }
+            Ok(entries)
+        }
+        .await;
+        self.check(result)
+    }
+
+    // Removes the entries that match and tells how many there were.
+    async fn lift(
+        &self,
+        block_by: &FailedLoginsPolicyType,
+        matches: impl Fn(&Key) -> bool,
+    ) -> Result<usize, String> {
+        let result = async {
+            let mut con = self.con.clone();
+            let mut lifted = 0;
+            for (name, key) in self.keys(block_by).await? {
+                if matches(&key) {
+                    lifted += redis::cmd("DEL")
+                        .arg(name)
+                        .query_async::<usize>(&mut con)
+                        .await?;
+                }
+            }
+            Ok(lifted)
+        }
+        .await;
+        self.check(result)
+    }
+
+    // The name of the Redis key of an entry, e.g. `unftp:failed-logins:ip:10.0.0.1:user:alice`.
+    fn name(&self, key: &Key) -> String {
+        let mut name = self.prefix.clone();
+        if let Some(ip) = key.ip {
+            name.push_str(&format!("ip:{}", ip));
+        }
+        if let Some(username) = &key.username {
+            if key.ip.is_some() {
+                name.push(':');
+            }
+            name.push_str(&format!("user:{}", username));
+        }
+        name
+    }
+
+    // Lists the Redis keys of the entries with the entries they are for.
+    async fn keys(
+        &self,
+        block_by: &FailedLoginsPolicyType,
+    ) -> redis::RedisResult<Vec<(String, Key)>> {
+        let pattern: String = self
+            .prefix
+            .chars()
+            .flat_map(|c| match c {
+                '*' | '?' | '[' | ']' | '\\' => vec!['\\', c],
+                c => vec![c],
+            })
+            .chain(['*'])
+            .collect();
+        let mut scan = redis::cmd("SCAN");
+        scan.cursor_arg(0)
+            .arg("MATCH")
+            .arg(pattern)
+            .arg("COUNT")
+            .arg(100);
+        let mut con = self.con.clone();
+        let mut iter = scan.iter_async::<String>(&mut con).await?;
+        let mut names = vec![];
+        while let Some(name) = iter.next_item().await {
+            names.push(name?);
+        }
+        Ok(names
+            .into_iter()
+            .filter_map(|name| {
+                let rest = name.strip_prefix(&self.prefix)?;
+                let key = match block_by {
+                    FailedLoginsPolicyType::ip => Key {
+                        ip: rest.strip_prefix("ip:")?.parse().ok(),
+                        username: None,
+                    },
+                    FailedLoginsPolicyType::user => Key {
+                        ip: None,
+                        username: Some(rest.strip_prefix("user:")?.to_string()),
+                    },
+                    FailedLoginsPolicyType::combination => {
+                        let (ip, username) = rest.strip_prefix("ip:")?.split_once(":user:")?;
+                        Key {
+                            ip: ip.parse().ok(),
+                            username: Some(username.to_string()),
+                        }
+                    }
+                };
+                Some((name, key))
+            })
+            .collect())
+    }
+
+    // Logs Redis errors, which the login checks don't report but handle by counting in memory.
+    fn check<T>(&self, result: redis::RedisResult<T>) -> Result<T, String> {
+        result.map_err(|e| {
+            slog::error!(
+                self.log,
+                "Could not use Redis for the failed logins policy: {}",
+                e
+            );
+            e.to_string()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FailedLogins, LockState};
+    use crate::args::FailedLoginsPolicyType;
+    use std::net::IpAddr;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn locks_after_max_attempts_like_libunftp() {
+        let fl = FailedLogins::new(2, Duration::from_secs(60), FailedLoginsPolicyType::ip);
+        let ip: IpAddr = [10, 0, 0, 1].into();
+
+        assert_eq!(fl.failed(ip, "alice").await, None);
+        assert_eq!(fl.success(ip, "bob").await, None);
+        assert_eq!(fl.failed(ip, "bob").await, None);
+        assert_eq!(
+            fl.failed(ip, "carol").await,
+            Some(LockState::MaxFailuresReached)
+        );
+        assert_eq!(
+            fl.success(ip, "alice").await,
+            Some(LockState::AlreadyLocked)
+        );
+        assert_eq!(fl.failed(ip, "alice").await, Some(LockState::AlreadyLocked));
+        assert_eq!(fl.success([10, 0, 0, 2].into(), "alice").await, None);
+    }
+
+    #[cfg(feature = "failed_logins_redis")]
+    #[tokio::test]
+    async fn attempts_are_counted_in_memory_while_redis_is_unavailable() {
+        use super::RedisStore;
+        use redis::aio::{ConnectionManager, ConnectionManagerConfig};
+        use std::sync::Arc;
+
+        // Nothing listens on port 1, so every Redis command fails.
+        let client = redis::Client::open("redis://127.0.0.1:1").unwrap();
+        let con = ConnectionManager::new_lazy_with_config(
+            client,
+            ConnectionManagerConfig::new().set_number_of_retries(0),
+        )
+        .unwrap();
+        let fl = FailedLogins {
+            redis: Some(RedisStore {
+                log: Arc::new(slog::Logger::root(slog::Discard, slog::o!())),
+                con,
+                prefix: "unftp:failed-logins:".to_string(),
+            }),
+            ..FailedLogins::new(2, Duration::from_secs(60), FailedLoginsPolicyType::user)
+        };
+        let ip: IpAddr = [10, 0, 0, 1].into();
+
+        assert_eq!(fl.failed(ip, "alice").await, None);
+        assert_eq!(
+            fl.failed(ip, "alice").await,
+            Some(LockState::MaxFailuresReached)
+        );
+        assert_eq!(
+            fl.success(ip, "alice").await,
+            Some(LockState::AlreadyLocked)
+        );
+        assert_eq!(fl.success(ip, "bob").await, None);
+        assert!(fl.blocks().await.is_err());
+        assert!(fl.lift(None, Some("alice")).await.is_err());
+        assert_eq!(
+            fl.success(ip, "alice").await,
+            Some(LockState::AlreadyLocked)
+        );
+    }
+
+    // Run with `cargo test --features failed_logins_redis -- --ignored` and a Redis server on
+    // localhost.
+    #[cfg(feature = "failed_logins_redis")]
+    #[tokio::test]
+    #[ignore = "needs a Redis server on localhost:6379"]
+    async fn redis_counts_are_shared_and_blocks_can_be_lifted() {
+        use std::sync::Arc;
+
+        let prefix = format!("unftp-test-{}:", std::process::id());
+        let replica = || {
+            FailedLogins::with_redis(
+                2,
+                Duration::from_secs(60),
+                FailedLoginsPolicyType::combination,
+                Arc::new(slog::Logger::root(slog::Discard, slog::o!())),
+                "redis://127.0.0.1:6379",
+                prefix.clone(),
+            )
+        };
+        let (a, b) = (replica().await.unwrap(), replica().await.unwrap());
+        let ip: IpAddr = "2001:db8::1".parse().unwrap();
+
+        assert_eq!(a.failed(ip, "alice").await, None);
+        assert_eq!(
+            b.failed(ip, "alice").await,
+            Some(LockState::MaxFailuresReached)
+        );
+        assert_eq!(a.success(ip, "alice").await, Some(LockState::AlreadyLocked));
+        assert_eq!(a.success(ip, "bob").await, None);
+
+        let blocks = b.blocks().await.unwrap();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].ip, Some(ip));
+        assert_eq!(blocks[0].username.as_deref(), Some("alice"));
+        assert!(blocks[0].expires_in <= 60);
+
+        assert_eq!(b.lift(None, Some("alice")).await.unwrap(), 1);
+        assert_eq!(a.success(ip, "alice").await, None);
+        assert!(a.blocks().await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,418 @@
+//! Runs external commands after files were uploaded, renamed or deleted.
+//!
+//! Hooks are configured in a JSON file given with `--hooks-file`. Every hook that matches an event
+//! is run with the event details in environment variables and the event JSON on stdin.
+
+use crate::{
+    args,
+    domain::events::{EventPatterns, FTPEvent, FTPEventPayload, TransferOutcome},
+};
+use clap::ArgMatches;
+use prometheus::{IntCounterVec, Opts};
+use serde::Deserialize;
+use slog::Logger;
+use std::{
+    path::{Path, PathBuf},
+    process::{Output, Stdio},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{io::AsyncWriteExt, process::Command, sync::Semaphore};
+
+// The event types hooks can be run for.
+const HOOK_EVENTS: &[&str] = &["put", "rename", "delete"];
+
+// Only this much of a command's stdout and stderr ends up in the log.
+const MAX_LOGGED_OUTPUT: usize = 4096;
+
+lazy_static! {
+    static ref HOOK_RUNS: IntCounterVec = prometheus::register_int_counter_vec!(
+        Opts::new(
+            "unftp_hook_runs_total",
+            "Hook runs by outcome: succeeded, failed, timeout or error."
+        ),
+        &["hook", "outcome"]
+    )
+    .unwrap();
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct HooksFile {
+    #[serde(default = "default_max_concurrent")]
+    max_concurrent: usize,
+    hooks: Vec<HookConfig>,
+}
+
+fn default_max_concurrent() -> usize {
+    4
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct HookConfig {
+    name: String,
+    events: Option<Vec<String>>,
+    username: Option<String>,
+    path: Option<String>,
+    command: Vec<String>,
+    #[serde(default = "default_timeout_secs")]
+    timeout_secs: u64,
+}
+
+fn default_timeout_secs() -> u64 {
+    60
+}
+
+#[derive(Debug)]
+struct Hook {
+    name: String,
+    events: Vec<String>,
+    patterns: EventPatterns,
+    command: Vec<String>,
+    timeout: Duration,
+}
+
+impl Hook {
+    fn from_config(cfg: HookConfig) -> Result<Hook, String> {
+        let events = match cfg.events {
+            Some(events) => {
+                if let Some(unknown) = events.iter().find(|e| !HOOK_EVENTS.contains(&e.as_str())) {
+                    return Err(format!(
+                        "hook '{}': unsupported event type '{}', use one of {}",
+                        cfg.name,
+                        unknown,
+                        HOOK_EVENTS.join(", ")
+                    ));
+                }
+                events
+            }
+            None => HOOK_EVENTS.iter().map(|e| e.to_string()).collect(),
+        };
+        if cfg.command.is_empty() {
+            return Err(format!("hook '{}': command cannot be empty", cfg.name));
+        }
+        Ok(Hook {
+            patterns: EventPatterns::new(cfg.username.as_deref(), cfg.path.as_deref())
+                .map_err(|e| format!("hook '{}': {}", cfg.name, e))?,
+            name: cfg.name,
+            events,
+            command: cfg.command,
+            timeout: Duration::from_secs(cfg.timeout_secs),
+        })
+    }
+
+    fn matches(&self, event: &FTPEvent) -> bool {
+        // Failed uploads are reported too, but there is nothing for a hook to pick up.
+        if let FTPEventPayload::Put { transfer, .. } = &event.payload {
+            if transfer.outcome == TransferOutcome::Failed {
+                return false;
+            }
+        }
+        let event_type = event.payload.event_type();
+        self.events.iter().any(|e| e == event_type) && self.patterns.matches(event)
+    }
+}
+
+/// Runs the configured hooks for data events.
+#[derive(Debug)]
+pub struct Hooks {
+    log: Arc<Logger>,
+    hooks: Vec<Arc<Hook>>,
+    slots: Arc<Semaphore>,
+    // The local directory that storage paths are relative to, if the storage back-end is the
+    // filesystem.
+    local_root: Option<PathBuf>,
+}
+
+impl Hooks {
+    /// Creates the hooks from the JSON configuration.
+    pub fn from_json(
+        log: Arc<Logger>,
+        json: &str,
+        local_root: Option<PathBuf>,
+    ) -> Result<Self, String> {
+        let file: HooksFile =
+            serde_json::from_str(json).map_err(|e| format!("could not parse hooks: {}", e))?;
+        if file.max_concurrent == 0 {
+            return Err("max_concurrent must be at least 1".to_string());
+        }
+        let hooks = file
+            .hooks
+            .into_iter()
+            .map(|cfg| Hook::from_config(cfg).map(Arc::new))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Hooks {
+            log,
+            hooks,
+            slots: Arc::new(Semaphore::new(file.max_concurrent)),
+            local_root,
+        })
+    }
+
+    /// Starts all hooks that match the event in the background. Hooks wait for a free slot when the
+    /// concurrency limit is reached.
+    pub fn trigger(&self, event: &FTPEvent) {
+        for hook in self.hooks.iter().filter(|h| h.matches(event)) {
+            let (hook, event, slots) = (hook.clone(), event.clone(), self.slots.clone());
+            let log = self.log.new(slog::o!("hook" => hook.name.clone()));
+            let local_root = self.local_root.clone();
+            tokio::spawn(async move {
+                let Ok(_slot) = slots.acquire_owned().await else {
+                    return;
+                };
+                let outcome = match run(&hook, &event, local_root.as_deref()).await {
+                    Ok(output) => {
+                        let stdout = truncated(&output.stdout);
+                        let stderr = truncated(&output.stderr);
+                        if output.status.success() {
+                            slog::info!(log, "Hook completed"; "status" => %output.status, "stdout" => stdout, "stderr" => stderr);
+                            "succeeded"
+                        } else {
+                            slog::warn!(log, "Hook failed"; "status" => %output.status, "stdout" => stdout, "stderr" => stderr);
+                            "failed"
+                        }
+                    }
+                    Err(HookError::Timeout) => {
+                        slog::error!(
+                            log,
+                            "Hook killed after running longer than {:?}",
+                            hook.timeout
+                        );
+                        "timeout"
+                    }
+                    Err(HookError::Io(e)) => {
+                        slog::error!(log, "Could not run hook: {}", e);
+                        "error"
+                    }
+                };
+                HOOK_RUNS.with_label_values(&[&hook.name, outcome]).inc();
+            });
+        }
+    }
+}
+
+/// Loads the hooks from the file given with `--hooks-file`, if any.
+pub fn load(log: &Logger, m: &ArgMatches) -> Result<Option<Arc<Hooks>>, String> {
+    let Some(hooks_file) = m.value_of(args::HOOKS_FILE) else {
+        return Ok(None);
+    };
+    let json = std::fs::read_to_string(hooks_file)
+        .map_err(|e| format!("could not read hooks file '{}': {}", hooks_file, e))?;
+    let local_root = match m.value_of(args::STORAGE_BACKEND_TYPE) {
+        Some("filesystem") | None => m.value_of(args::ROOT_DIR).map(PathBuf::from),
+        _ => None,
+    };
+    let hooks = Hooks::from_json(
+        Arc::new(log.new(slog::o!("module" => "hooks"))),
+        &json,
+        local_root,
+    )?;
+    Ok(Some(Arc::new(hooks)))
+}
+
+#[derive(Debug)]
+enum HookError {
+    Timeout,
+    Io(std::io::Error),
+}
+
+// Runs the hook's command and waits for it to finish or time out, in which case it is killed.
+async fn run(
+    hook: &Hook,
+    event: &FTPEvent,
+    local_root: Option<&Path>,
+) -> Result<Output, HookError> {
+    let mut child = Command::new(&hook.command[0])
+        .args(&hook.command[1..])
+        .envs(environment(event, local_root))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(HookError::Io)?;
+
+    let json = serde_json::to_vec(event).unwrap_or_default();
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let output = async move {
+        // The command may not read its input, so a broken pipe is not an error.
+        let _ = stdin.write_all(&json).await;
+        drop(stdin);
+        child.wait_with_output().await
+    };
+    match tokio::time::timeout(hook.timeout, output).await {
+        Ok(result) => result.map_err(HookError::Io),
+        Err(_) => Err(HookError::Timeout),
+    }
+}
+
+// The environment variables that describe the event to the command.
+fn environment(event: &FTPEvent, local_root: Option<&Path>) -> Vec<(&'static str, String)> {
+    let mut env = vec![("UNFTP_EVENT_TYPE", event.payload.event_type().to_string())];
+    if let Some(username) = &event.username {
+        env.push(("UNFTP_USERNAME", username.clone()));
+    }
+    if let Some(trace_id) = &event.trace_id {
+        env.push(("UNFTP_TRACE_ID", trace_id.clone()));
+    }
+    match &event.payload {
+        FTPEventPayload::Put { path, transfer } => {
+            env.push(("UNFTP_PATH", path.clone()));
+            env.push(("UNFTP_BYTES", transfer.bytes.to_string()));
+            if let Some(client_ip) = &transfer.client_ip {
+                env.push(("UNFTP_CLIENT_IP", client_ip.clone()));
+            }
+            if let Some(md5) = &transfer.md5 {
+                env.push(("UNFTP_MD5", md5.clone()));
+            }
+            if let Some(storage_path) = &transfer.storage_path {
+                env.push(("UNFTP_STORAGE_PATH", storage_path.clone()));
+                if let Some(root) = local_root {
+                    let local_path = root.join(storage_path.trim_start_matches('/'));
+                    env.push(("UNFTP_LOCAL_PATH", local_path.display().to_string()));
+                }
+            }
+        }
+        FTPEventPayload::Rename { from, to } => {
+            env.push(("UNFTP_PATH", to.clone()));
+            env.push(("UNFTP_FROM_PATH", from.clone()));
+        }
+        FTPEventPayload::Delete { path } => env.push(("UNFTP_PATH", path.clone())),
+        _ => {}
+    }
+    env
+}
+
+fn truncated(output: &[u8]) -> String {
+    let output = String::from_utf8_lossy(output);
+    let output = output.trim();
+    match output.char_indices().nth(MAX_LOGGED_OUTPUT) {
+        Some((i, _)) => format!("{}...", &output[..i]),
+        None => output.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{run, Hooks};
+    use crate::domain::events::{
+        FTPEvent, FTPEventPayload, NullEventDispatcher, TransferDetail, TransferOutcome,
+    };
+    use crate::domain::user::User;
+    use crate::notify::FTPListener;
+    use crate::storage::{Direction, Transfer, TransferMeter, TransferTracker};
+    use libunftp::notification::{DataEvent, DataListener, EventMeta};
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    fn put(username: &str, path: &str) -> FTPEvent {
+        FTPEvent {
+            schema_version: 2,
+            source_instance: "unFTP".to_string(),
+            hostname: "localhost".to_string(),
+            payload: FTPEventPayload::Put {
+                path: path.to_string(),
+                transfer: TransferDetail {
+                    bytes: 3,
+                    storage_path: Some(format!("/{}{}", username, path)),
+                    ..TransferDetail::default()
+                },
+            },
+            username: Some(username.to_string()),
+            trace_id: None,
+            sequence_number: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn matching_hooks_get_event_details() {
+        let log = Arc::new(slog::Logger::root(slog::Discard, slog::o!()));
+        let hooks = Hooks::from_json(
+            log,
+            r#"{"hooks": [{
+                "name": "unzip",
+                "events": ["put"],
+                "username": "acme-*",
+                "path": "/in/*.zip",
+                "command": ["sh", "-c", "test \"$UNFTP_LOCAL_PATH\" = /srv/ftp/acme-1/in/a.zip && grep -q '\"Put\"'"]
+            }]}"#,
+            Some(PathBuf::from("/srv/ftp")),
+        )
+        .unwrap();
+        let hook = &hooks.hooks[0];
+
+        assert!(!hook.matches(&put("other", "/in/a.zip")));
+        assert!(!hook.matches(&put("acme-1", "/in/sub/a.zip")));
+        let event = put("acme-1", "/in/a.zip");
+        assert!(hook.matches(&event));
+
+        let output = run(hook, &event, Some(Path::new("/srv/ftp")))
+            .await
+            .unwrap();
+        assert!(output.status.success());
+    }
+    #[tokio::test]
+    async fn hooks_match_uploads_relative_to_the_working_directory() {
+        let log = Arc::new(slog::Logger::root(slog::Discard, slog::o!()));
+        let marker = std::env::temp_dir().join(format!("unftp-hook-cwd-{}", std::process::id()));
+        let hooks = Hooks::from_json(
+            log,
+            &format!(
+                r#"{{"hooks": [{{
+                    "name": "unzip",
+                    "path": "/incoming/*.zip",
+                    "command": ["sh", "-c", "test \"$UNFTP_PATH\" = /incoming/a.zip && touch {}"]
+                }}]}}"#,
+                marker.display()
+            ),
+            None,
+        )
+        .unwrap();
+        let listener = FTPListener {
+            event_dispatcher: Arc::new(NullEventDispatcher {}),
+            instance_name: "unFTP".to_string(),
+            hostname: "localhost".to_string(),
+            transfers: Arc::new(TransferTracker::default()),
+            hooks: Some(Arc::new(hooks)),
+            logins: Arc::default(),
+        };
+        let user = User {
+            login_id: 1,
+            ..User::with_defaults("alice")
+        };
+        listener.logins.entered(&user);
+        listener.logins.take("alice", "trace");
+
+        // `CWD /incoming` followed by `STOR a.zip`.
+        listener.transfers.record(Transfer::new(
+            &user,
+            Direction::Put,
+            PathBuf::from("/incoming/a.zip"),
+            Instant::now(),
+            "filesystem",
+            &TransferMeter::new(false),
+            TransferOutcome::Succeeded,
+        ));
+        let meta = EventMeta {
+            username: "alice".to_string(),
+            trace_id: "trace".to_string(),
+            sequence_number: 1,
+        };
+        let put = DataEvent::Put {
+            path: "a.zip".to_string(),
+            bytes: 3,
+        };
+        listener.receive_data_event(put, meta).await;
+
+        for _ in 0..50 {
+            if marker.exists() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert!(marker.exists(), "the hook did not run for /incoming/a.zip");
+        std::fs::remove_file(&marker).unwrap();
+    }
+}
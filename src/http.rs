@@ -1,14 +1,18 @@
 //! Contains code pertaining to unFTPs HTTP service it exposes, including prometheus metrics.
-use crate::{app, metrics};
+use crate::{
+    accept::Probes, app, auth::LoginCache, failed_logins::FailedLogins, metrics, sessions::Sessions,
+};
 
 use http_body_util::combinators::UnsyncBoxBody;
 use http_body_util::{Empty, Full};
 use hyper::body::{Bytes, Incoming};
 use hyper::service::service_fn;
-use hyper::{Method, Request, Response, StatusCode};
+use hyper::{header, HeaderMap, Method, Request, Response, StatusCode};
+use sha2::{Digest, Sha256};
 use slog::*;
 use std::convert::Infallible;
 use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
 use std::{net::SocketAddr, result::Result};
 use tokio::{
     io::{AsyncBufReadExt, AsyncWriteExt},
@@ -19,12 +23,24 @@ const PATH_HOME: &str = "/";
 const PATH_METRICS: &str = "/metrics";
 const PATH_HEALTH: &str = "/health";
 const PATH_READINESS: &str = "/ready";
+const PATH_AUTH_CACHE: &str = "/auth-cache";
+const PATH_AUTH_CACHE_USERS: &str = "/auth-cache/users/";
+const PATH_SESSIONS: &str = "/sessions";
+const PATH_FAILED_LOGINS: &str = "/failed-logins";
+const PATH_FAILED_LOGINS_IPS: &str = "/failed-logins/ips/";
+const PATH_FAILED_LOGINS_USERS: &str = "/failed-logins/users/";
 
 // starts an HTTP server and exports Prometheus metrics.
+#[allow(clippy::too_many_arguments)]
 pub async fn start(
     log: &Logger,
     bind_addr: &str,
     ftp_addr: SocketAddr,
+    auth_cache: Option<Arc<LoginCache>>,
+    sessions: Arc<Sessions>,
+    failed_logins: Option<Arc<FailedLogins>>,
+    probes: Arc<Probes>,
+    admin_token: Option<Arc<str>>,
     mut shutdown: tokio::sync::broadcast::Receiver<()>,
     done: tokio::sync::mpsc::Sender<()>,
 ) -> Result<(), String> {
@@ -44,6 +60,15 @@ pub async fn start(
     info!(log, "Exposing Prometheus {} exporter endpoint.", app::NAME; "path" => PATH_METRICS);
     info!(log, "Exposing readiness endpoint."; "path" => PATH_READINESS);
     info!(log, "Exposing liveness endpoint."; "path" => PATH_HEALTH);
+    if admin_token.is_some() {
+        if auth_cache.is_some() {
+            info!(log, "Exposing authentication cache endpoint."; "path" => PATH_AUTH_CACHE);
+        }
+        info!(log, "Exposing sessions endpoint."; "path" => PATH_SESSIONS);
+        if failed_logins.is_some() {
+            info!(log, "Exposing failed logins endpoint."; "path" => PATH_FAILED_LOGINS);
+        }
+    }
 
     loop {
         tokio::select! {
@@ -59,9 +84,14 @@ pub async fn start(
 
                 let stream = hyper_util::rt::TokioIo::new(stream);
 
-                let conn = http_server.serve_connection_with_upgrades(stream, service_fn(move |req: Request<Incoming>| async move {
-                    let handler = HttpHandler { ftp_addr };
-                    handler.router(req).await
+                let auth_cache = auth_cache.clone();
+                let sessions = sessions.clone();
+                let failed_logins = failed_logins.clone();
+                let probes = probes.clone();
+                let admin_token = admin_token.clone();
+                let conn = http_server.serve_connection_with_upgrades(stream, service_fn(move |req: Request<Incoming>| {
+                    let handler = HttpHandler { ftp_addr, auth_cache: auth_cache.clone(), sessions: sessions.clone(), failed_logins: failed_logins.clone(), probes: probes.clone(), admin_token: admin_token.clone() };
+                    async move { handler.router(req).await }
                 }));
 
                 let conn = graceful.watch(conn.into_owned());
@@ -87,8 +117,22 @@ pub async fn start(
     Ok(())
 }
 
+// Tells whether the path is that of an endpoint that shows who is connected or changes what unFTP
+// lets in, which are only served with the admin token.
+fn is_admin_path(path: &str) -> bool {
+    path == PATH_SESSIONS
+        || path.starts_with(PATH_AUTH_CACHE)
+        || path.starts_with(PATH_FAILED_LOGINS)
+}
+
 struct HttpHandler {
     pub ftp_addr: SocketAddr,
+    pub auth_cache: Option<Arc<LoginCache>>,
+    pub sessions: Arc<Sessions>,
+    pub failed_logins: Option<Arc<FailedLogins>>,
+    pub probes: Arc<Probes>,
+    // Without it the admin endpoints are not found.
+    pub admin_token: Option<Arc<str>>,
 }
 
 impl HttpHandler {
@@ -98,6 +142,16 @@ impl HttpHandler {
     ) -> Result<Response<UnsyncBoxBody<Bytes, Infallible>>, http::Error> {
         let (parts, _) = req.into_parts();
 
+        if is_admin_path(parts.uri.path()) {
+            if let Err(status) = self.authorize(&parts.headers) {
+                let mut response = Response::builder().status(status);
+                if status == StatusCode::UNAUTHORIZED {
+                    response = response.header(header::WWW_AUTHENTICATE, "Bearer");
+                }
+                return response.body(UnsyncBoxBody::new(Empty::<Bytes>::new()));
+            }
+        }
+
         let response = match (parts.method, parts.uri.path()) {
             (Method::GET, PATH_HOME) | (Method::GET, "/index.html") => Ok(Response::new(
                 UnsyncBoxBody::new(Full::new(self.service_home())),
@@ -109,6 +163,14 @@ impl HttpHandler {
             (Method::GET, PATH_READINESS) => Response::builder()
                 .status(StatusCode::OK)
                 .body(UnsyncBoxBody::new(Empty::<Bytes>::new())),
+            (Method::DELETE, path) if path.starts_with(PATH_AUTH_CACHE) => self.invalidate(path),
+            (Method::GET, PATH_SESSIONS) => Response::builder()
+                .header(hyper::header::CONTENT_TYPE, "application/json")
+                .body(UnsyncBoxBody::new(Full::new(Bytes::from(
+                    serde_json::to_vec(&self.sessions.counts()).unwrap_or_default(),
+                )))),
+            (Method::GET, PATH_FAILED_LOGINS) => self.blocks().await,
+            (Method::DELETE, path) if path.starts_with(PATH_FAILED_LOGINS) => self.lift(path).await,
             _ => Response::builder()
                 .status(StatusCode::NOT_FOUND)
                 .body(UnsyncBoxBody::new(Empty::<Bytes>::new())),
@@ -117,11 +179,112 @@ impl HttpHandler {
         response
     }
 
+    // Checks the bearer token of a request to an admin endpoint.
+    fn authorize(&self, headers: &HeaderMap) -> Result<(), StatusCode> {
+        let Some(token) = &self.admin_token else {
+            return Err(StatusCode::NOT_FOUND);
+        };
+        let given = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or(StatusCode::UNAUTHORIZED)?;
+        // Comparing digests takes as long whichever character differs.
+        if Sha256::digest(given.as_bytes()) == Sha256::digest(token.as_bytes()) {
+            Ok(())
+        } else {
+            Err(StatusCode::UNAUTHORIZED)
+        }
+    }
+
     fn service_home(&self) -> Bytes {
         let index_html = include_str!(concat!(env!("PROJ_WEB_DIR"), "/index.html"));
         Bytes::from(index_html.replace("{{ .AppVersion }}", app::VERSION))
     }
 
+    // Empties the authentication cache, or only forgets the user in /auth-cache/users/{username}.
+    fn invalidate(
+        &self,
+        path: &str,
+    ) -> Result<Response<UnsyncBoxBody<Bytes, Infallible>>, http::Error> {
+        let status = match (&self.auth_cache, path.strip_prefix(PATH_AUTH_CACHE_USERS)) {
+            (None, _) => StatusCode::NOT_FOUND,
+            (Some(cache), None) if path == PATH_AUTH_CACHE => {
+                cache.clear();
+                StatusCode::NO_CONTENT
+            }
+            (Some(cache), Some(username)) if !username.is_empty() => {
+                let username = percent_encoding::percent_decode_str(username).decode_utf8_lossy();
+                cache.invalidate(&username);
+                StatusCode::NO_CONTENT
+            }
+            _ => StatusCode::NOT_FOUND,
+        };
+        Response::builder()
+            .status(status)
+            .body(UnsyncBoxBody::new(Empty::<Bytes>::new()))
+    }
+
+    // Lists the clients that are locked out by the failed logins policy.
+    async fn blocks(&self) -> Result<Response<UnsyncBoxBody<Bytes, Infallible>>, http::Error> {
+        let Some(failed_logins) = &self.failed_logins else {
+            return Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(UnsyncBoxBody::new(Empty::<Bytes>::new()));
+        };
+        match failed_logins.blocks().await {
+            Ok(blocks) => Response::builder()
+                .header(hyper::header::CONTENT_TYPE, "application/json")
+                .body(UnsyncBoxBody::new(Full::new(Bytes::from(
+                    serde_json::to_vec(&blocks).unwrap_or_default(),
+                )))),
+            Err(_) => Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .body(UnsyncBoxBody::new(Empty::<Bytes>::new())),
+        }
+    }
+
+    // Lifts all blocks of the failed logins policy, or only those of the IP address in
+    // /failed-logins/ips/{ip} or the user in /failed-logins/users/{username}.
+    async fn lift(
+        &self,
+        path: &str,
+    ) -> Result<Response<UnsyncBoxBody<Bytes, Infallible>>, http::Error> {
+        let target = if path == PATH_FAILED_LOGINS {
+            Some((None, None))
+        } else if let Some(ip) = path.strip_prefix(PATH_FAILED_LOGINS_IPS) {
+            let ip = percent_encoding::percent_decode_str(ip).decode_utf8_lossy();
+            match ip.parse::<IpAddr>() {
+                Ok(ip) => Some((Some(ip), None)),
+                Err(_) => {
+                    return Response::builder()
+                        .status(StatusCode::BAD_REQUEST)
+                        .body(UnsyncBoxBody::new(Empty::<Bytes>::new()))
+                }
+            }
+        } else {
+            path.strip_prefix(PATH_FAILED_LOGINS_USERS)
+                .filter(|username| !username.is_empty())
+                .map(|username| {
+                    let username =
+                        percent_encoding::percent_decode_str(username).decode_utf8_lossy();
+                    (None, Some(username.into_owned()))
+                })
+        };
+        let status = match (&self.failed_logins, target) {
+            (Some(failed_logins), Some((ip, username))) => {
+                match failed_logins.lift(ip, username.as_deref()).await {
+                    Ok(_) => StatusCode::NO_CONTENT,
+                    Err(_) => StatusCode::SERVICE_UNAVAILABLE,
+                }
+            }
+            _ => StatusCode::NOT_FOUND,
+        };
+        Response::builder()
+            .status(status)
+            .body(UnsyncBoxBody::new(Empty::<Bytes>::new()))
+    }
+
     async fn health(&self) -> Result<Response<UnsyncBoxBody<Bytes, Infallible>>, http::Error> {
         match self.ftp_probe().await {
             Ok(_) => Response::builder()
@@ -145,7 +308,7 @@ impl HttpHandler {
             self.ftp_addr
         };
 
-        let connection = tokio::net::TcpStream::connect(connect_to_addr).await?;
+        let connection = self.probes.connect(connect_to_addr).await?;
         let (rx, mut tx) = tokio::io::split(connection);
         let mut reader = tokio::io::BufReader::new(rx);
 
@@ -0,0 +1,202 @@
+//! Authentication against an Apache htpasswd file.
+//!
+//! Supports bcrypt (`htpasswd -B`), Argon2 and SHA-crypt (`$5$` and `$6$`) hashes. The file is
+//! read again when it changes, so users can be added or removed without a restart.
+
+use super::reloading::ReloadingFile;
+use argon2::{password_hash::PasswordHash, Argon2, PasswordVerifier};
+use async_trait::async_trait;
+use libunftp::auth::{AuthenticationError, Credentials, DefaultUser};
+use slog::Logger;
+use std::{collections::HashMap, path::Path, sync::Arc};
+
+/// A password hash as found in htpasswd files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Hash {
+    Bcrypt(String),
+    Argon2(String),
+    ShaCrypt256(String),
+    ShaCrypt512(String),
+    // Hashes like MD5 (apr1), SHA-1 and DES crypt are weak and not supported.
+    Unsupported,
+}
+
+impl Hash {
+    pub(crate) fn parse(hash: &str) -> Hash {
+        let hash = hash.to_string();
+        if ["$2a$", "$2b$", "$2x$", "$2y$"]
+            .iter()
+            .any(|p| hash.starts_with(p))
+        {
+            Hash::Bcrypt(hash)
+        } else if hash.starts_with("$argon2") {
+            Hash::Argon2(hash)
+        } else if hash.starts_with("$5$") {
+            Hash::ShaCrypt256(hash)
+        } else if hash.starts_with("$6$") {
+            Hash::ShaCrypt512(hash)
+        } else {
+            Hash::Unsupported
+        }
+    }
+
+    pub(crate) fn verify(&self, password: &str) -> bool {
+        match self {
+            Hash::Bcrypt(hash) => bcrypt::verify(password, hash).unwrap_or(false),
+            Hash::Argon2(hash) => PasswordHash::new(hash)
+                .map(|hash| {
+                    Argon2::default()
+                        .verify_password(password.as_bytes(), &hash)
+                        .is_ok()
+                })
+                .unwrap_or(false),
+            Hash::ShaCrypt256(hash) => sha_crypt::sha256_check(password, hash).is_ok(),
+            Hash::ShaCrypt512(hash) => sha_crypt::sha512_check(password, hash).is_ok(),
+            Hash::Unsupported => false,
+        }
+    }
+}
+
+/// An authenticator that checks passwords against the hashes in an htpasswd file.
+#[derive(Debug)]
+pub struct HtpasswdAuthenticator {
+    file: ReloadingFile<HashMap<String, Hash>>,
+}
+
+impl HtpasswdAuthenticator {
+    /// Reads the htpasswd file at the given path.
+    pub fn from_file<P: AsRef<Path>>(log: Arc<Logger>, path: P) -> Result<Self, String> {
+        let parse_log = log.clone();
+        let file = ReloadingFile::new(
+            log,
+            "htpasswd file",
+            path.as_ref().to_path_buf(),
+            move |path| {
+                let content = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+                Ok(parse(&parse_log, &content))
+            },
+        )?;
+        Ok(HtpasswdAuthenticator { file })
+    }
+}
+
+fn parse(log: &Logger, content: &str) -> HashMap<String, Hash> {
+    let mut users = HashMap::new();
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((username, hash)) = line.split_once(':') else {
+            slog::warn!(log, "Ignoring htpasswd line {} without a ':'", number + 1);
+            continue;
+        };
+        let hash = Hash::parse(hash);
+        if hash == Hash::Unsupported {
+            slog::warn!(log, "User '{}' in htpasswd file has an unsupported hash type, use bcrypt, argon2 or SHA-crypt", username);
+        }
+        // Like Apache, use the first entry if a user is listed more than once.
+        users.entry(username.to_string()).or_insert(hash);
+    }
+    users
+}
+
+#[async_trait]
+impl libunftp::auth::Authenticator<DefaultUser> for HtpasswdAuthenticator {
+    async fn authenticate(
+        &self,
+        username: &str,
+        creds: &Credentials,
+    ) -> Result<DefaultUser, AuthenticationError> {
+        let password = creds
+            .password
+            .clone()
+            .ok_or(AuthenticationError::BadPassword)?;
+        let hash = self
+            .file
+            .get()
+            .await
+            .get(username)
+            .cloned()
+            .ok_or(AuthenticationError::BadUser)?;
+        // Checking a hash takes a deliberately long time so keep it off the async workers.
+        let valid = tokio::task::spawn_blocking(move || hash.verify(&password))
+            .await
+            .map_err(|e| AuthenticationError::with_source("could not verify password", e))?;
+        if valid {
+            Ok(DefaultUser {})
+        } else {
+            Err(AuthenticationError::BadPassword)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HtpasswdAuthenticator;
+    use argon2::{
+        password_hash::{rand_core::OsRng, PasswordHasher, SaltString},
+        Argon2,
+    };
+    use libunftp::auth::{AuthenticationError, Authenticator, Credentials};
+    use std::sync::Arc;
+
+    fn creds(password: &str) -> Credentials {
+        Credentials {
+            certificate_chain: None,
+            password: Some(password.to_string()),
+            source_ip: std::net::Ipv4Addr::LOCALHOST.into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn checks_hashes_and_reloads_changes() {
+        let log = Arc::new(slog::Logger::root(slog::Discard, slog::o!()));
+        let path = std::env::temp_dir().join(format!("unftp-htpasswd-{}", std::process::id()));
+        let argon2 = Argon2::default()
+            .hash_password(b"argon", &SaltString::generate(&mut OsRng))
+            .unwrap()
+            .to_string();
+        let sha512 =
+            sha_crypt::sha512_simple("sha", &sha_crypt::Sha512Params::new(1000).unwrap()).unwrap();
+        std::fs::write(
+            &path,
+            format!(
+                "# comment\nbob:{}\ncarol:{}\ndave:{}\nerin:$apr1$x$y\n",
+                bcrypt::hash("bcrypt", 4).unwrap(),
+                argon2,
+                sha512
+            ),
+        )
+        .unwrap();
+        let auth = HtpasswdAuthenticator::from_file(log, &path).unwrap();
+
+        assert!(auth.authenticate("bob", &creds("bcrypt")).await.is_ok());
+        assert!(auth.authenticate("carol", &creds("argon")).await.is_ok());
+        assert!(auth.authenticate("dave", &creds("sha")).await.is_ok());
+        assert!(matches!(
+            auth.authenticate("bob", &creds("wrong")).await,
+            Err(AuthenticationError::BadPassword)
+        ));
+        assert!(matches!(
+            auth.authenticate("erin", &creds("anything")).await,
+            Err(AuthenticationError::BadPassword)
+        ));
+        assert!(matches!(
+            auth.authenticate("frank", &creds("bcrypt")).await,
+            Err(AuthenticationError::BadUser)
+        ));
+
+        std::fs::write(
+            &path,
+            format!("frank:{}\n", bcrypt::hash("new", 4).unwrap()),
+        )
+        .unwrap();
+        assert!(auth.authenticate("frank", &creds("new")).await.is_ok());
+        assert!(matches!(
+            auth.authenticate("bob", &creds("bcrypt")).await,
+            Err(AuthenticationError::BadUser)
+        ));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
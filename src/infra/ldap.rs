@@ -0,0 +1,521 @@
+//! Authentication and user details from an LDAP directory like OpenLDAP or Active Directory.
+//!
+//! Users are authenticated by binding with their password, either to a DN built from a template
+//! (bind mode) or to the DN found by searching for the username with a service account
+//! (search-then-bind mode).
+
+use crate::domain::user::{parse_vfs_permissions, User, UserDetailError, UserDetailProvider};
+use async_trait::async_trait;
+use ldap3::{
+    dn_escape, ldap_escape, Ldap, LdapConnAsync, LdapConnSettings, LdapError, Scope, SearchEntry,
+};
+use libunftp::auth::{AuthenticationError, Credentials, DefaultUser};
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use unftp_sbe_restrict::VfsOperations;
+
+// The result code LDAP servers return for a wrong DN or password.
+const INVALID_CREDENTIALS: u32 = 49;
+
+// Active Directory's userAccountControl flag for disabled accounts.
+const UAC_ACCOUNT_DISABLE: u32 = 0x2;
+
+/// Maps LDAP attributes to [`User`] fields.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AttributeMap {
+    pub name: Option<String>,
+    pub surname: Option<String>,
+    pub root: Option<String>,
+    pub vfs_perms: Option<String>,
+}
+
+impl Default for AttributeMap {
+    fn default() -> Self {
+        AttributeMap {
+            name: Some("givenName".to_string()),
+            surname: Some("sn".to_string()),
+            root: None,
+            vfs_perms: None,
+        }
+    }
+}
+
+impl std::str::FromStr for AttributeMap {
+    type Err = String;
+
+    /// Parses a comma separated list like `name=givenName,root=homeDirectory`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut map = AttributeMap {
+            name: None,
+            surname: None,
+            root: None,
+            vfs_perms: None,
+        };
+        for pair in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (field, attribute) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("expected field=attribute but got '{}'", pair))?;
+            let attribute = Some(attribute.trim().to_string());
+            match field.trim() {
+                "name" => map.name = attribute,
+                "surname" => map.surname = attribute,
+                "root" => map.root = attribute,
+                "vfs_perms" => map.vfs_perms = attribute,
+                other => {
+                    return Err(format!(
+                        "unknown user field '{}', use name, surname, root or vfs_perms",
+                        other
+                    ))
+                }
+            }
+        }
+        Ok(map)
+    }
+}
+
+/// The settings of the LDAP connection and how users are found.
+#[derive(Debug, Clone)]
+pub struct LdapSettings {
+    /// An ldap:// or ldaps:// URL.
+    pub url: String,
+    /// Whether to upgrade ldap:// connections with StartTLS.
+    pub starttls: bool,
+    /// A DN template with `{USER}` to bind with directly, e.g. `uid={USER},ou=people,dc=example,dc=com`.
+    pub user_dn: Option<String>,
+    /// The DN of the service account used for searching.
+    pub bind_dn: Option<String>,
+    pub bind_password: Option<String>,
+    /// Where to search for users in search-then-bind mode.
+    pub search_base: Option<String>,
+    /// The filter with `{USER}` used to find users, e.g. `(sAMAccountName={USER})`.
+    pub search_filter: String,
+    /// The user has to be a member of at least one of these groups, if any are given.
+    pub required_groups: Vec<String>,
+    /// The user attribute that lists the DNs of the groups the user is member of.
+    pub group_attribute: String,
+    pub attributes: AttributeMap,
+    /// The maximum number of idle connections to keep.
+    pub pool_size: usize,
+    /// The time limit for connecting and for every authentication or lookup.
+    pub timeout: Duration,
+}
+
+/// A client with a pool of connections to the LDAP server.
+#[derive(Debug)]
+pub struct LdapClient {
+    settings: LdapSettings,
+    idle: Mutex<Vec<Ldap>>,
+}
+
+impl LdapClient {
+    pub fn new(settings: LdapSettings) -> Result<Self, String> {
+        match (&settings.user_dn, &settings.search_base) {
+            (Some(_), Some(_)) => {
+                return Err(
+                    "please specify either a user DN template or a search base for LDAP, not both"
+                        .to_string(),
+                )
+            }
+            (None, None) => {
+                return Err(
+                    "please specify a user DN template or a search base for LDAP".to_string(),
+                )
+            }
+            (Some(template), None) if !template.contains("{USER}") => {
+                return Err("the LDAP user DN template must contain {USER}".to_string())
+            }
+            (None, Some(_)) if !settings.search_filter.contains("{USER}") => {
+                return Err("the LDAP search filter must contain {USER}".to_string())
+            }
+            _ => {}
+        }
+        if settings.bind_password.is_some() && settings.bind_dn.is_none() {
+            return Err("an LDAP bind password was given without a bind DN".to_string());
+        }
+        Ok(LdapClient {
+            settings,
+            idle: Mutex::new(Vec::new()),
+        })
+    }
+
+    async fn connect(&self) -> Result<Ldap, LdapError> {
+        let conn_settings = LdapConnSettings::new()
+            .set_conn_timeout(self.settings.timeout)
+            .set_starttls(self.settings.starttls);
+        let (conn, ldap) = LdapConnAsync::with_settings(conn_settings, &self.settings.url).await?;
+        tokio::spawn(async move {
+            // The connection ends with an error when the server closes it, which is expected for
+            // idle connections.
+            let _ = conn.drive().await;
+        });
+        Ok(ldap)
+    }
+
+    // Takes an idle connection from the pool or opens a new one.
+    async fn checkout(&self) -> Result<Ldap, LdapError> {
+        loop {
+            let idle = self.idle.lock().unwrap().pop();
+            match idle {
+                Some(mut ldap) => {
+                    if !ldap.is_closed() {
+                        return Ok(ldap);
+                    }
+                }
+                None => return self.connect().await,
+            }
+        }
+    }
+
+    // Returns a connection that is in a known good state to the pool.
+    fn checkin(&self, ldap: Ldap) {
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < self.settings.pool_size {
+            idle.push(ldap);
+        }
+    }
+
+    // Binds as the service account, or anonymously if there is none.
+    async fn bind_service_account(&self, ldap: &mut Ldap) -> Result<(), LdapError> {
+        ldap.simple_bind(
+            self.settings.bind_dn.as_deref().unwrap_or(""),
+            self.settings.bind_password.as_deref().unwrap_or(""),
+        )
+        .await?
+        .success()?;
+        Ok(())
+    }
+
+    fn attributes(&self) -> Vec<&str> {
+        let a = &self.settings.attributes;
+        [
+            a.name.as_deref(),
+            a.surname.as_deref(),
+            a.root.as_deref(),
+            a.vfs_perms.as_deref(),
+            Some(self.settings.group_attribute.as_str()),
+            Some("userAccountControl"),
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+
+    // Finds the user's entry. In bind mode the entry is read directly from the templated DN.
+    async fn find_user(
+        &self,
+        ldap: &mut Ldap,
+        username: &str,
+    ) -> Result<Option<SearchEntry>, LdapError> {
+        let (base, scope, filter) = match &self.settings.search_base {
+            Some(base) => (
+                base.clone(),
+                Scope::Subtree,
+                search_filter(&self.settings.search_filter, username),
+            ),
+            None => (
+                user_dn(
+                    self.settings.user_dn.as_deref().unwrap_or_default(),
+                    username,
+                ),
+                Scope::Base,
+                "(objectClass=*)".to_string(),
+            ),
+        };
+        let result = ldap
+            .search(&base, scope, &filter, self.attributes())
+            .await?;
+        // In bind mode a missing entry results in 'no such object'.
+        if self.settings.search_base.is_none() && result.1.rc == 32 {
+            return Ok(None);
+        }
+        let (mut entries, _) = result.success()?;
+        match entries.len() {
+            0 => Ok(None),
+            1 => Ok(Some(SearchEntry::construct(entries.remove(0)))),
+            n => Err(LdapError::from(std::io::Error::other(format!(
+                "the LDAP search for '{}' returned {} entries",
+                username, n
+            )))),
+        }
+    }
+
+    async fn authenticate(
+        &self,
+        username: &str,
+        password: &str,
+    ) -> Result<(), AuthenticationError> {
+        // An empty password would make the bind unauthenticated, which always succeeds.
+        if password.is_empty() {
+            return Err(AuthenticationError::BadPassword);
+        }
+        let mut ldap = self.checkout().await.map_err(ldap_failure)?;
+        let result = tokio::time::timeout(
+            self.settings.timeout,
+            self.bind_user(&mut ldap, username, password),
+        )
+        .await
+        .map_err(|_| AuthenticationError::new("LDAP authentication timed out"))?;
+        // Only connections that are back in a known state go back to the pool.
+        if !matches!(result, Err(AuthenticationError::ImplPropagated(..))) {
+            if let Ok(()) = self.bind_service_account(&mut ldap).await {
+                self.checkin(ldap);
+            }
+        }
+        result
+    }
+
+    async fn bind_user(
+        &self,
+        ldap: &mut Ldap,
+        username: &str,
+        password: &str,
+    ) -> Result<(), AuthenticationError> {
+        let mut entry = None;
+        let dn = match &self.settings.user_dn {
+            Some(template) => user_dn(template, username),
+            None => {
+                self.bind_service_account(ldap)
+                    .await
+                    .map_err(ldap_failure)?;
+                let found = self
+                    .find_user(ldap, username)
+                    .await
+                    .map_err(ldap_failure)?
+                    .ok_or(AuthenticationError::BadUser)?;
+                let dn = found.dn.clone();
+                entry = Some(found);
+                dn
+            }
+        };
+
+        let bind = ldap
+            .simple_bind(&dn, password)
+            .await
+            .map_err(ldap_failure)?;
+        match bind.rc {
+            0 => {}
+            INVALID_CREDENTIALS => return Err(AuthenticationError::BadPassword),
+            _ => return Err(ldap_failure(LdapError::from(bind))),
+        }
+
+        if self.settings.required_groups.is_empty() {
+            return Ok(());
+        }
+        // In bind mode the user reads their own entry.
+        let entry = match entry {
+            Some(entry) => entry,
+            None => self
+                .find_user(ldap, username)
+                .await
+                .map_err(ldap_failure)?
+                .ok_or(AuthenticationError::BadUser)?,
+        };
+        if is_member(
+            &entry,
+            &self.settings.group_attribute,
+            &self.settings.required_groups,
+        ) {
+            Ok(())
+        } else {
+            Err(AuthenticationError::new(format!(
+                "user '{}' is not a member of any of the required LDAP groups",
+                username
+            )))
+        }
+    }
+
+    async fn lookup(&self, username: &str) -> Result<User, UserDetailError> {
+        let mut ldap = self
+            .checkout()
+            .await
+            .map_err(|e| UserDetailError::with_source("could not connect to LDAP", e))?;
+        let found = tokio::time::timeout(self.settings.timeout, async {
+            self.bind_service_account(&mut ldap).await?;
+            self.find_user(&mut ldap, username).await
+        })
+        .await
+        .map_err(|_| UserDetailError::Generic("LDAP user lookup timed out".to_string()))?
+        .map_err(|e| UserDetailError::with_source("LDAP user lookup failed", e))?;
+        self.checkin(ldap);
+        found
+            .map(|entry| to_user(username, &entry, &self.settings.attributes))
+            .ok_or_else(|| UserDetailError::UserNotFound {
+                username: username.to_string(),
+            })
+    }
+}
+
+fn ldap_failure(e: LdapError) -> AuthenticationError {
+    AuthenticationError::with_source("LDAP error", e)
+}
+
+fn user_dn(template: &str, username: &str) -> String {
+    template.replace("{USER}", &dn_escape(username))
+}
+
+fn search_filter(template: &str, username: &str) -> String {
+    template.replace("{USER}", &ldap_escape(username))
+}
+
+fn first<'a>(entry: &'a SearchEntry, attribute: &Option<String>) -> Option<&'a String> {
+    attribute
+        .as_ref()
+        .and_then(|a| entry.attrs.get(a))
+        .and_then(|values| values.first())
+}
+
+// DNs are compared case insensitively as directories treat them that way.
+fn is_member(entry: &SearchEntry, group_attribute: &str, required: &[String]) -> bool {
+    entry.attrs.get(group_attribute).is_some_and(|groups| {
+        groups
+            .iter()
+            .any(|g| required.iter().any(|r| r.eq_ignore_ascii_case(g)))
+    })
+}
+
+fn to_user(username: &str, entry: &SearchEntry, attributes: &AttributeMap) -> User {
+    let account_enabled = entry
+        .attrs
+        .get("userAccountControl")
+        .and_then(|v| v.first())
+        .and_then(|v| v.parse::<u32>().ok())
+        .is_none_or(|uac| uac & UAC_ACCOUNT_DISABLE == 0);
+    User {
+        name: first(entry, &attributes.name).cloned(),
+        surname: first(entry, &attributes.surname).cloned(),
+        account_enabled,
+        vfs_permissions: attributes
+            .vfs_perms
+            .as_ref()
+            .and_then(|a| entry.attrs.get(a))
+            .map_or(VfsOperations::all(), |perms| {
+                parse_vfs_permissions(VfsOperations::all(), perms)
+            }),
+        root: first(entry, &attributes.root).map(PathBuf::from),
+        ..User::with_defaults(username)
+    }
+}
+
+/// Authenticates users by binding to the LDAP server with their password.
+#[derive(Debug)]
+pub struct LdapAuthenticator {
+    client: Arc<LdapClient>,
+}
+
+impl LdapAuthenticator {
+    pub fn new(client: Arc<LdapClient>) -> Self {
+        LdapAuthenticator { client }
+    }
+}
+
+#[async_trait]
+impl libunftp::auth::Authenticator<DefaultUser> for LdapAuthenticator {
+    async fn authenticate(
+        &self,
+        username: &str,
+        creds: &Credentials,
+    ) -> Result<DefaultUser, AuthenticationError> {
+        let password = creds
+            .password
+            .as_deref()
+            .ok_or(AuthenticationError::BadPassword)?;
+        self.client.authenticate(username, password).await?;
+        Ok(DefaultUser {})
+    }
+}
+
+/// A [`UserDetailProvider`] that maps the attributes of the user's LDAP entry to the [`User`].
+#[derive(Debug)]
+pub struct LdapUserProvider {
+    client: Arc<LdapClient>,
+}
+
+impl LdapUserProvider {
+    pub fn new(client: Arc<LdapClient>) -> Self {
+        LdapUserProvider { client }
+    }
+}
+
+#[async_trait]
+impl UserDetailProvider for LdapUserProvider {
+    async fn provide_user_detail(&self, username: &str) -> Result<User, UserDetailError> {
+        self.client.lookup(username).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_member, search_filter, to_user, user_dn, AttributeMap};
+    use ldap3::SearchEntry;
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+    use unftp_sbe_restrict::VfsOperations;
+
+    fn entry(attrs: &[(&str, &[&str])]) -> SearchEntry {
+        SearchEntry {
+            dn: "uid=alice,ou=people,dc=example,dc=com".to_string(),
+            attrs: attrs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.iter().map(|s| s.to_string()).collect()))
+                .collect(),
+            bin_attrs: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn usernames_are_escaped_in_templates() {
+        assert_eq!(
+            search_filter("(uid={USER})", "a*)(uid=*"),
+            "(uid=a\\2a\\29\\28uid=\\2a)"
+        );
+        assert_eq!(
+            user_dn("uid={USER},dc=example,dc=com", "a,dc=evil"),
+            "uid=a\\2cdc\\3devil,dc=example,dc=com"
+        );
+    }
+
+    #[test]
+    fn entries_map_to_users() {
+        let attributes: AttributeMap = "name=givenName,root=homeDirectory,vfs_perms=ftpPerms"
+            .parse()
+            .unwrap();
+        let user = to_user(
+            "alice",
+            &entry(&[
+                ("givenName", &["Alice"]),
+                ("sn", &["Smith"]),
+                ("homeDirectory", &["/alice"]),
+                ("ftpPerms", &["none", "+get", "+list"]),
+                ("userAccountControl", &["514"]),
+                (
+                    "memberOf",
+                    &["CN=FTP Users,OU=Groups,DC=corp,DC=example,DC=com"],
+                ),
+            ]),
+            &attributes,
+        );
+        assert_eq!(user.name.as_deref(), Some("Alice"));
+        assert_eq!(user.surname, None);
+        assert_eq!(user.root, Some(PathBuf::from("/alice")));
+        assert_eq!(
+            user.vfs_permissions,
+            VfsOperations::GET | VfsOperations::LIST
+        );
+        assert!(!user.account_enabled);
+
+        let e = entry(&[(
+            "memberOf",
+            &["CN=FTP Users,OU=Groups,DC=corp,DC=example,DC=com"],
+        )]);
+        assert!(is_member(
+            &e,
+            "memberOf",
+            &["cn=ftp users,ou=groups,dc=corp,dc=example,dc=com".to_string()]
+        ));
+        assert!(!is_member(&e, "memberOf", &["cn=admins".to_string()]));
+        assert!("home=x".parse::<AttributeMap>().is_err());
+    }
+}
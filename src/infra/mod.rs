@@ -1,8 +1,20 @@
 //! Infra contains infrastructure specific implementations of things in the [`domain`](crate::domain)
 //! module.
+pub mod htpasswd;
+#[cfg(feature = "auth_jwt")]
+pub mod jwt;
+#[cfg(feature = "auth_ldap")]
+pub mod ldap;
 mod pubsub;
+pub(crate) mod reloading;
+mod routing;
+#[cfg(feature = "auth_sql")]
+pub mod sql;
 pub mod userdetail_http;
 pub mod usrdetail_json;
+mod webhook;
 mod workload_identity;
 
-pub use pubsub::PubsubEventDispatcher;
+pub use pubsub::{BatchSettings, PubsubAuth, PubsubEventDispatcher, MAX_BATCH_MESSAGES};
+pub use routing::RoutingEventDispatcher;
+pub use webhook::WebhookEventDispatcher;
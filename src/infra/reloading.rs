@@ -0,0 +1,121 @@
+//! Files like the user file, the htpasswd file and the IP filter file that are read again when
+//! they change, so that they can be edited without a restart.
+
+use slog::Logger;
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::SystemTime,
+};
+
+// What identifies a version of a file: its modification time, size and, on Unix, inode. The inode
+// changes when Kubernetes swaps the symlinks of a ConfigMap, even if the time and size don't.
+type FileVersion = (Option<SystemTime>, u64, u64);
+
+fn file_version(meta: &fs::Metadata) -> FileVersion {
+    #[cfg(unix)]
+    let inode = std::os::unix::fs::MetadataExt::ino(meta);
+    #[cfg(not(unix))]
+    let inode = 0;
+    (meta.modified().ok(), meta.len(), inode)
+}
+
+type Parser<T> = Arc<dyn Fn(&Path) -> Result<T, String> + Send + Sync>;
+
+// The value as last read from the file, along with what identifies that version of the file.
+struct Loaded<T> {
+    version: Option<FileVersion>,
+    value: Arc<T>,
+}
+
+/// The contents of a file, parsed into a `T`, that is read again when the file changes. If the
+/// changed file can't be read or parsed, the previous contents are kept.
+pub struct ReloadingFile<T> {
+    log: Arc<Logger>,
+    // What the file is, like "user file", for messages.
+    what: &'static str,
+    path: PathBuf,
+    parse: Parser<T>,
+    loaded: RwLock<Arc<Loaded<T>>>,
+}
+
+impl<T> fmt::Debug for ReloadingFile<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReloadingFile")
+            .field("what", &self.what)
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+impl<T: Send + Sync + 'static> ReloadingFile<T> {
+    /// Reads the file at the given path with `parse`, which gets the path of the file to read.
+    pub fn new<F>(
+        log: Arc<Logger>,
+        what: &'static str,
+        path: PathBuf,
+        parse: F,
+    ) -> Result<Self, String>
+    where
+        F: Fn(&Path) -> Result<T, String> + Send + Sync + 'static,
+    {
+        let parse: Parser<T> = Arc::new(parse);
+        let loaded = read(&path, &parse)
+            .map_err(|e| format!("could not load {} '{}': {}", what, path.display(), e))?;
+        Ok(ReloadingFile {
+            log,
+            what,
+            path,
+            parse,
+            loaded: RwLock::new(Arc::new(loaded)),
+        })
+    }
+
+    /// Returns the current contents, reading the file again first if it changed.
+    pub async fn get(&self) -> Arc<T> {
+        let current = self.loaded.read().unwrap().clone();
+        let version = match tokio::fs::metadata(&self.path).await {
+            Ok(meta) => Some(file_version(&meta)),
+            Err(e) => {
+                slog::warn!(self.log, "Could not check {} for changes: {}", self.what, e; "path" => %self.path.display());
+                return current.value.clone();
+            }
+        };
+        if version == current.version {
+            return current.value.clone();
+        }
+        let (path, parse) = (self.path.clone(), self.parse.clone());
+        let reloaded = tokio::task::spawn_blocking(move || read(&path, &parse))
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|r| r);
+        let loaded = match reloaded {
+            Ok(loaded) => {
+                slog::info!(self.log, "Reloaded {}", self.what; "path" => %self.path.display());
+                Arc::new(loaded)
+            }
+            Err(e) => {
+                // Don't try again until the file changes once more.
+                slog::error!(self.log, "Could not reload {}, keeping the previous contents: {}", self.what, e; "path" => %self.path.display());
+                Arc::new(Loaded {
+                    version,
+                    value: current.value.clone(),
+                })
+            }
+        };
+        *self.loaded.write().unwrap() = loaded.clone();
+        loaded.value.clone()
+    }
+}
+
+// Reads and parses the file. The version is taken before reading so that a change while reading is
+// picked up the next time.
+fn read<T>(path: &Path, parse: &Parser<T>) -> Result<Loaded<T>, String> {
+    let version = fs::metadata(path).map(|m| file_version(&m)).ok();
+    let value = parse(path)?;
+    Ok(Loaded {
+        version,
+        value: Arc::new(value),
+    })
+}
@@ -0,0 +1,362 @@
+//! An [EventDispatcher] that fans events out to several destinations based on routing rules read
+//! from a JSON file.
+//!
+//! Routes are evaluated in order. Every route that matches an event gets a copy of it, except that
+//! a matching route with a `drop` destination discards the event for all routes after it.
+
+use crate::domain::events::{
+    EventDispatcher, EventPatterns, EventPublisher, FTPEvent, FTPEventPayload,
+};
+use crate::infra::{PubsubAuth, PubsubEventDispatcher, WebhookEventDispatcher};
+use async_trait::async_trait;
+use prometheus::{IntCounterVec, Opts};
+use serde::Deserialize;
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use strum::VariantNames;
+use tokio::sync::Semaphore;
+
+// Can be used in the 'events' field of a route next to the event types, for all security events.
+const SECURITY_EVENTS: &str = "security";
+
+lazy_static! {
+    static ref ROUTE_EVENTS: IntCounterVec = prometheus::register_int_counter_vec!(
+        Opts::new(
+            "unftp_event_route_events_total",
+            "Events handled per route by outcome: delivered, failed, dropped or queue_full."
+        ),
+        &["route", "outcome"]
+    )
+    .unwrap();
+    static ref ROUTE_RETRIES: IntCounterVec = prometheus::register_int_counter_vec!(
+        Opts::new(
+            "unftp_event_route_retries_total",
+            "Delivery retries per route."
+        ),
+        &["route"]
+    )
+    .unwrap();
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct RoutesFile {
+    routes: Vec<RouteConfig>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct RouteConfig {
+    name: String,
+    #[serde(default, rename = "match")]
+    matcher: MatchConfig,
+    destination: DestinationConfig,
+    #[serde(default)]
+    retries: u32,
+    #[serde(default = "default_retry_backoff_ms")]
+    retry_backoff_ms: u64,
+    #[serde(default = "default_max_pending")]
+    max_pending: usize,
+}
+
+fn default_retry_backoff_ms() -> u64 {
+    500
+}
+
+fn default_max_pending() -> usize {
+    1000
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct MatchConfig {
+    events: Option<Vec<String>>,
+    username: Option<String>,
+    path: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum DestinationConfig {
+    Drop,
+    Pubsub {
+        project: String,
+        topic: String,
+        base_url: Option<String>,
+        key_file: Option<String>,
+        #[serde(default)]
+        no_auth: bool,
+    },
+    Webhook {
+        url: String,
+        #[serde(default)]
+        headers: HashMap<String, String>,
+    },
+}
+
+#[derive(Debug)]
+struct Route {
+    name: String,
+    events: Option<Vec<String>>,
+    patterns: EventPatterns,
+    // None means the event is dropped.
+    destination: Option<Arc<dyn EventPublisher<FTPEvent>>>,
+    retries: u32,
+    retry_backoff: Duration,
+    // A permit for every event that is being delivered or waits for a retry.
+    pending: Arc<Semaphore>,
+}
+
+impl Route {
+    fn from_config(log: &Arc<slog::Logger>, cfg: RouteConfig) -> Result<Route, String> {
+        if let Some(events) = &cfg.matcher.events {
+            if let Some(unknown) = events.iter().find(|e| {
+                e.as_str() != SECURITY_EVENTS && !FTPEventPayload::VARIANTS.contains(&e.as_str())
+            }) {
+                return Err(format!(
+                    "route '{}': unknown event type '{}'",
+                    cfg.name, unknown
+                ));
+            }
+        }
+        if cfg.max_pending == 0 {
+            return Err(format!(
+                "route '{}': max_pending must be at least 1",
+                cfg.name
+            ));
+        }
+        let patterns =
+            EventPatterns::new(cfg.matcher.username.as_deref(), cfg.matcher.path.as_deref())
+                .map_err(|e| format!("route '{}': {}", cfg.name, e))?;
+
+        let route_log = Arc::new(log.new(slog::o!("route" => cfg.name.clone())));
+        let destination: Option<Arc<dyn EventPublisher<FTPEvent>>> = match cfg.destination {
+            DestinationConfig::Drop => None,
+            DestinationConfig::Pubsub {
+                project,
+                topic,
+                base_url,
+                key_file,
+                no_auth,
+            } => {
+                let auth = match (key_file, no_auth) {
+                    (Some(_), true) => {
+                        return Err(format!(
+                            "route '{}': specify either key_file or no_auth, not both",
+                            cfg.name
+                        ))
+                    }
+                    (Some(key_file), false) => PubsubAuth::from_key_file(&key_file)?,
+                    (None, true) => PubsubAuth::None,
+                    (None, false) => PubsubAuth::WorkloadIdentity,
+                };
+                let dispatcher = match base_url {
+                    Some(base_url) => {
+                        PubsubEventDispatcher::with_api_base(route_log, project, topic, base_url)
+                    }
+                    None => PubsubEventDispatcher::new(route_log, project, topic),
+                };
+                Some(Arc::new(dispatcher.with_auth(auth)))
+            }
+            DestinationConfig::Webhook { url, headers } => Some(Arc::new(
+                WebhookEventDispatcher::new(route_log, url, headers),
+            )),
+        };
+
+        Ok(Route {
+            name: cfg.name,
+            events: cfg.matcher.events,
+            patterns,
+            destination,
+            retries: cfg.retries,
+            retry_backoff: Duration::from_millis(cfg.retry_backoff_ms),
+            pending: Arc::new(Semaphore::new(cfg.max_pending)),
+        })
+    }
+
+    fn matches(&self, event: &FTPEvent) -> bool {
+        let event_type_ok = self.events.as_ref().is_none_or(|events| {
+            events.iter().any(|e| {
+                e == event.payload.event_type()
+                    || (e == SECURITY_EVENTS && event.payload.is_security_event())
+            })
+        });
+        event_type_ok && self.patterns.matches(event)
+    }
+
+    // Publishes with retries and exponential back-off as configured for this route.
+    async fn deliver(
+        &self,
+        log: &slog::Logger,
+        publisher: &dyn EventPublisher<FTPEvent>,
+        event: &FTPEvent,
+    ) {
+        let mut backoff = self.retry_backoff;
+        let mut attempt = 0;
+        loop {
+            match publisher.publish(event).await {
+                Ok(()) => {
+                    ROUTE_EVENTS
+                        .with_label_values(&[&self.name, "delivered"])
+                        .inc();
+                    return;
+                }
+                Err(e) if attempt < self.retries => {
+                    attempt += 1;
+                    slog::warn!(log, "Could not dispatch event, retrying in {:?}: {}", backoff, e; "route" => &self.name, "attempt" => attempt);
+                    ROUTE_RETRIES.with_label_values(&[&self.name]).inc();
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                Err(e) => {
+                    slog::error!(log, "Could not dispatch event: {}", e; "route" => &self.name);
+                    ROUTE_EVENTS
+                        .with_label_values(&[&self.name, "failed"])
+                        .inc();
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Dispatches events to the destinations of all routes that match them.
+#[derive(Debug)]
+pub struct RoutingEventDispatcher {
+    log: Arc<slog::Logger>,
+    routes: Vec<Arc<Route>>,
+}
+
+impl RoutingEventDispatcher {
+    /// Creates the dispatcher from the JSON routes in the given file.
+    pub fn from_json(log: Arc<slog::Logger>, json: &str) -> Result<Self, String> {
+        let file: RoutesFile =
+            serde_json::from_str(json).map_err(|e| format!("could not parse routes: {}", e))?;
+        let routes = file
+            .routes
+            .into_iter()
+            .map(|cfg| Route::from_config(&log, cfg).map(Arc::new))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(RoutingEventDispatcher { log, routes })
+    }
+}
+
+#[async_trait]
+impl EventDispatcher<FTPEvent> for RoutingEventDispatcher {
+    async fn dispatch(&self, event: FTPEvent) {
+        for route in self.routes.iter().filter(|r| r.matches(&event)) {
+            let Some(publisher) = route.destination.clone() else {
+                ROUTE_EVENTS
+                    .with_label_values(&[&route.name, "dropped"])
+                    .inc();
+                return;
+            };
+            // Each route delivers on its own so that a slow or failing one doesn't hold up the
+            // others or the FTP session. When too many of its events are still pending, new ones
+            // are discarded rather than piling up.
+            let Ok(permit) = route.pending.clone().try_acquire_owned() else {
+                slog::warn!(self.log, "Too many events pending, discarding event"; "route" => &route.name);
+                ROUTE_EVENTS
+                    .with_label_values(&[&route.name, "queue_full"])
+                    .inc();
+                continue;
+            };
+            let (route, log, event) = (route.clone(), self.log.clone(), event.clone());
+            tokio::spawn(async move {
+                route.deliver(&log, publisher.as_ref(), &event).await;
+                drop(permit);
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RoutingEventDispatcher, ROUTE_EVENTS};
+    use crate::domain::events::{EventDispatcher, FTPEvent, FTPEventPayload, TransferDetail};
+    use std::sync::Arc;
+
+    fn event(username: &str, payload: FTPEventPayload) -> FTPEvent {
+        FTPEvent {
+            schema_version: 2,
+            source_instance: "unFTP".to_string(),
+            hostname: "localhost".to_string(),
+            payload,
+            username: Some(username.to_string()),
+            trace_id: None,
+            sequence_number: None,
+        }
+    }
+
+    fn put(path: &str) -> FTPEventPayload {
+        FTPEventPayload::Put {
+            path: path.to_string(),
+            transfer: TransferDetail::default(),
+        }
+    }
+
+    #[test]
+    fn routes_match_on_event_type_username_and_path() {
+        let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+        let log = Arc::new(slog::Logger::root(slog::Discard, slog::o!()));
+        let dispatcher = RoutingEventDispatcher::from_json(
+            log,
+            r#"{"routes": [
+                {"name": "invoices", "match": {"events": ["put"], "path": "/invoices/**", "username": "acme-*"},
+                 "destination": {"type": "webhook", "url": "http://localhost/hook"}},
+                {"name": "security", "match": {"events": ["security"]}, "destination": {"type": "drop"}}
+            ]}"#,
+        )
+        .unwrap();
+        let (invoices, security) = (&dispatcher.routes[0], &dispatcher.routes[1]);
+
+        assert!(invoices.matches(&event("acme-1", put("/invoices/2024/a.pdf"))));
+        assert!(!invoices.matches(&event("other", put("/invoices/a.pdf"))));
+        assert!(!invoices.matches(&event("acme-1", put("/other/a.pdf"))));
+        let login_failed = FTPEventPayload::LoginFailed {
+            source_ip: "127.0.0.1".to_string(),
+            reason: "bad password".to_string(),
+        };
+        assert_eq!(login_failed.event_type(), "loginFailed");
+        assert!(!invoices.matches(&event("acme-1", login_failed.clone())));
+        assert!(security.matches(&event("acme-1", login_failed)));
+    }
+
+    #[test]
+    fn unknown_event_types_are_rejected() {
+        let log = Arc::new(slog::Logger::root(slog::Discard, slog::o!()));
+        let result = RoutingEventDispatcher::from_json(
+            log,
+            r#"{"routes": [{"name": "x", "match": {"events": ["upload"]}, "destination": {"type": "drop"}}]}"#,
+        );
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn events_are_discarded_when_too_many_are_pending() {
+        let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+        // A webhook that never answers keeps the first event pending.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut connections = vec![];
+            while let Ok((stream, _)) = listener.accept().await {
+                connections.push(stream);
+            }
+        });
+        let log = Arc::new(slog::Logger::root(slog::Discard, slog::o!()));
+        let dispatcher = RoutingEventDispatcher::from_json(
+            log,
+            &format!(
+                r#"{{"routes": [{{"name": "slow", "destination": {{"type": "webhook", "url": "http://{}/hook"}}, "max_pending": 1}}]}}"#,
+                addr
+            ),
+        )
+        .unwrap();
+        let queue_full = ROUTE_EVENTS.with_label_values(&["slow", "queue_full"]);
+
+        dispatcher.dispatch(event("alice", put("/a.txt"))).await;
+        assert_eq!(queue_full.get(), 0);
+        dispatcher.dispatch(event("alice", put("/b.txt"))).await;
+        assert_eq!(queue_full.get(), 1);
+    }
+}
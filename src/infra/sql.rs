@@ -0,0 +1,291 @@
+//! Authentication and user details from a SQLite or PostgreSQL database.
+//!
+//! Users live in the `unftp_users` table created by the migrations in the `migrations` directory.
+//! Passwords are stored as bcrypt, Argon2 or SHA-crypt hashes, the same as in htpasswd files.
+
+use super::htpasswd::Hash;
+use crate::domain::user::{
+    parse_ip_net, parse_vfs_permissions, User, UserDetailError, UserDetailProvider,
+};
+use async_trait::async_trait;
+use libunftp::auth::{AuthenticationError, Credentials, DefaultUser};
+use sqlx::{
+    any::{AnyPoolOptions, AnyRow},
+    migrate::Migrator,
+    AnyPool, Row,
+};
+use std::{path::PathBuf, time::Duration};
+use unftp_sbe_restrict::VfsOperations;
+
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
+lazy_static! {
+    // Checked instead when there is no hash for the user, so that unknown users take as long as known
+    // ones and can't be told apart by the time the answer takes.
+    static ref DUMMY_HASH: Hash = Hash::Bcrypt(
+        bcrypt::hash(rand::random::<[u8; 16]>(), bcrypt::DEFAULT_COST)
+            .expect("a bcrypt hash of random bytes")
+    );
+}
+
+// Booleans are selected as integers because SQLite has no boolean type of its own.
+const USER_COLUMNS: &str = "username, name, surname, \
+    CASE WHEN account_enabled THEN 1 ELSE 0 END AS account_enabled, \
+    root, vfs_perms, allowed_ips, max_sessions, max_download_bps, max_upload_bps";
+
+/// The settings of the database connection pool.
+#[derive(Debug, Clone)]
+pub struct SqlSettings {
+    /// A `sqlite://` or `postgres://` URL.
+    pub url: String,
+    /// The maximum number of connections.
+    pub pool_size: u32,
+    /// How long to wait for a connection.
+    pub timeout: Duration,
+}
+
+/// Creates a pool that connects to the database when first used.
+pub fn connect(settings: &SqlSettings) -> Result<AnyPool, String> {
+    sqlx::any::install_default_drivers();
+    AnyPoolOptions::new()
+        .max_connections(settings.pool_size)
+        .acquire_timeout(settings.timeout)
+        .connect_lazy(&settings.url)
+        .map_err(|e| format!("invalid database URL: {}", e))
+}
+
+/// Creates or updates the tables that hold the users.
+pub async fn migrate(pool: &AnyPool) -> Result<(), String> {
+    MIGRATOR
+        .run(pool)
+        .await
+        .map_err(|e| format!("could not migrate the user database: {}", e))
+}
+
+/// Checks passwords against the hashes in the database.
+#[derive(Debug)]
+pub struct SqlAuthenticator {
+    pool: AnyPool,
+}
+
+impl SqlAuthenticator {
+    pub fn new(pool: AnyPool) -> Self {
+        SqlAuthenticator { pool }
+    }
+}
+
+#[async_trait]
+impl libunftp::auth::Authenticator<DefaultUser> for SqlAuthenticator {
+    async fn authenticate(
+        &self,
+        username: &str,
+        creds: &Credentials,
+    ) -> Result<DefaultUser, AuthenticationError> {
+        let password = creds
+            .password
+            .clone()
+            .ok_or(AuthenticationError::BadPassword)?;
+        let row = sqlx::query("SELECT password_hash FROM unftp_users WHERE username = $1")
+            .bind(username)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(|e| {
+                AuthenticationError::with_source("could not query the user database", e)
+            })?;
+        let hash: Option<String> = match &row {
+            Some(row) => row
+                .try_get("password_hash")
+                .map_err(|e| AuthenticationError::with_source("invalid password hash", e))?,
+            None => None,
+        };
+        let checked = hash.as_deref().map(Hash::parse);
+        // Checking a hash takes a deliberately long time so keep it off the async workers.
+        let valid = tokio::task::spawn_blocking(move || {
+            checked.as_ref().unwrap_or(&DUMMY_HASH).verify(&password)
+        })
+        .await
+        .map_err(|e| AuthenticationError::with_source("could not verify password", e))?;
+        match (row, hash) {
+            (None, _) => Err(AuthenticationError::BadUser),
+            (Some(_), Some(_)) if valid => Ok(DefaultUser {}),
+            _ => Err(AuthenticationError::BadPassword),
+        }
+    }
+}
+
+/// A [`UserDetailProvider`] that reads the [`User`] from the database.
+#[derive(Debug)]
+pub struct SqlUserProvider {
+    pool: AnyPool,
+}
+
+impl SqlUserProvider {
+    pub fn new(pool: AnyPool) -> Self {
+        SqlUserProvider { pool }
+    }
+}
+
+#[async_trait]
+impl UserDetailProvider for SqlUserProvider {
+    async fn provide_user_detail(&self, username: &str) -> Result<User, UserDetailError> {
+        let query = format!(
+            "SELECT {} FROM unftp_users WHERE username = $1",
+            USER_COLUMNS
+        );
+        sqlx::query(&query)
+            .bind(username)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(|e| UserDetailError::with_source("could not query the user database", e))?
+            .ok_or_else(|| UserDetailError::UserNotFound {
+                username: username.to_string(),
+            })
+            .and_then(|row| to_user(&row))
+    }
+
+    async fn list_users(&self) -> Result<Vec<User>, UserDetailError> {
+        let query = format!("SELECT {} FROM unftp_users ORDER BY username", USER_COLUMNS);
+        sqlx::query(&query)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|e| UserDetailError::with_source("could not query the user database", e))?
+            .iter()
+            .map(to_user)
+            .collect()
+    }
+}
+
+fn to_user(row: &AnyRow) -> Result<User, UserDetailError> {
+    let column = |name: &str| -> Result<Option<String>, UserDetailError> {
+        row.try_get(name)
+            .map_err(|e| UserDetailError::with_source("invalid user in the database", e))
+    };
+    let list = |value: Option<String>| -> Option<Vec<String>> {
+        value.map(|v| {
+            v.split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(str::to_string)
+                .collect()
+        })
+    };
+    let username = column("username")?.unwrap_or_default();
+    let number = |name: &str| -> Result<Option<i64>, UserDetailError> {
+        row.try_get(name)
+            .map_err(|e| UserDetailError::with_source("invalid user in the database", e))
+    };
+    let account_enabled: i64 = row
+        .try_get("account_enabled")
+        .map_err(|e| UserDetailError::with_source("invalid user in the database", e))?;
+    let max_sessions = number("max_sessions")?
+        .map(u32::try_from)
+        .transpose()
+        .map_err(|_| UserDetailError::new(format!("user '{}': invalid max_sessions", username)))?;
+    let allowed_ips = list(column("allowed_ips")?)
+        .map(|ips| ips.iter().map(|ip| parse_ip_net(ip)).collect())
+        .transpose()
+        .map_err(|e| UserDetailError::new(format!("user '{}': {}", username, e)))?;
+    Ok(User {
+        name: column("name")?,
+        surname: column("surname")?,
+        account_enabled: account_enabled != 0,
+        vfs_permissions: list(column("vfs_perms")?).map_or(VfsOperations::all(), |p| {
+            parse_vfs_permissions(VfsOperations::all(), &p)
+        }),
+        root: column("root")?.map(PathBuf::from),
+        allowed_ips,
+        // Like in the JSON user file, a limit of 0 means no limit.
+        max_download_bps: number("max_download_bps")?
+            .filter(|&bps| bps > 0)
+            .map(|bps| bps as u64),
+        max_upload_bps: number("max_upload_bps")?
+            .filter(|&bps| bps > 0)
+            .map(|bps| bps as u64),
+        max_sessions,
+        ..User::with_defaults(username)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{connect, migrate, SqlAuthenticator, SqlSettings, SqlUserProvider};
+    use crate::domain::user::{UserDetailError, UserDetailProvider};
+    use libunftp::auth::{AuthenticationError, Authenticator, Credentials};
+    use std::{path::PathBuf, time::Duration};
+    use unftp_sbe_restrict::VfsOperations;
+
+    fn creds(password: &str) -> Credentials {
+        Credentials {
+            certificate_chain: None,
+            password: Some(password.to_string()),
+            source_ip: std::net::Ipv4Addr::LOCALHOST.into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn users_are_read_from_the_database() {
+        let path = std::env::temp_dir().join(format!("unftp-users-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let pool = connect(&SqlSettings {
+            url: format!("sqlite://{}?mode=rwc", path.display()),
+            pool_size: 2,
+            timeout: Duration::from_secs(5),
+        })
+        .unwrap();
+        migrate(&pool).await.unwrap();
+        sqlx::query(
+            "INSERT INTO unftp_users (username, password_hash, root, vfs_perms, allowed_ips, \
+             max_sessions, max_download_bps) \
+             VALUES ($1, $2, 'alice', '-mkdir,-del', '10.0.0.0/8', 2, 1048576)",
+        )
+        .bind("alice")
+        .bind(bcrypt::hash("secret", 4).unwrap())
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query("INSERT INTO unftp_users (username, account_enabled) VALUES ('bob', FALSE)")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let auth = SqlAuthenticator::new(pool.clone());
+        assert!(auth.authenticate("alice", &creds("secret")).await.is_ok());
+        assert!(matches!(
+            auth.authenticate("alice", &creds("wrong")).await,
+            Err(AuthenticationError::BadPassword)
+        ));
+        assert!(matches!(
+            auth.authenticate("bob", &creds("secret")).await,
+            Err(AuthenticationError::BadPassword)
+        ));
+        // Quotes in the username are just part of the name.
+        assert!(matches!(
+            auth.authenticate("x' OR '1'='1", &creds("secret")).await,
+            Err(AuthenticationError::BadUser)
+        ));
+
+        let users = SqlUserProvider::new(pool);
+        let alice = users.provide_user_detail("alice").await.unwrap();
+        assert_eq!(alice.root, Some(PathBuf::from("alice")));
+        assert!(alice.account_enabled);
+        assert!(!alice.vfs_permissions.contains(VfsOperations::MK_DIR));
+        assert!(alice.vfs_permissions.contains(VfsOperations::PUT));
+        assert_eq!(alice.allowed_ips.unwrap().len(), 1);
+        assert_eq!(alice.max_sessions, Some(2));
+        assert_eq!(alice.max_download_bps, Some(1048576));
+        assert_eq!(alice.max_upload_bps, None);
+        assert!(
+            !users
+                .provide_user_detail("bob")
+                .await
+                .unwrap()
+                .account_enabled
+        );
+        assert!(matches!(
+            users.provide_user_detail("carol").await,
+            Err(UserDetailError::UserNotFound { .. })
+        ));
+        assert_eq!(users.list_users().await.unwrap().len(), 2);
+        let _ = std::fs::remove_file(&path);
+    }
+}
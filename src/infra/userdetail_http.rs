@@ -4,37 +4,247 @@
 use crate::domain::user::{User, UserDetailError, UserDetailProvider};
 use crate::infra::usrdetail_json::JsonUserProvider;
 use async_trait::async_trait;
-use http::{Method, Request};
-use http_body_util::{BodyExt, Empty};
+use data_encoding::BASE64;
+use http::{header, Method, Request, StatusCode};
+use http_body_util::{BodyExt, Full};
 use hyper::body::Bytes;
-use hyper_util::client::legacy::Client;
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use hyper_util::client::legacy::{connect::HttpConnector, Client};
 use hyper_util::rt::TokioExecutor;
-use url::form_urlencoded;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+use std::{
+    fmt::{Debug, Formatter},
+    net::IpAddr,
+    path::PathBuf,
+    time::Duration,
+};
+
+/// How unFTP authenticates itself to the user detail service.
+#[derive(Clone, Default)]
+pub enum HttpAuth {
+    #[default]
+    None,
+    /// Sends an `Authorization: Bearer` header with the token.
+    Bearer(String),
+    /// Sends an `Authorization: Basic` header with the username and password.
+    Basic { username: String, password: String },
+    /// Sends a header of your choice, like `X-Api-Key`.
+    Header { name: String, value: String },
+}
+
+impl Debug for HttpAuth {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HttpAuth::None => f.write_str("None"),
+            HttpAuth::Bearer(_) => f.write_str("Bearer(..)"),
+            HttpAuth::Basic { username, .. } => write!(f, "Basic({}, ..)", username),
+            HttpAuth::Header { name, .. } => write!(f, "Header({}, ..)", name),
+        }
+    }
+}
+
+/// Where and how to fetch user details.
+#[derive(Debug, Clone)]
+pub struct HttpUserDetailSettings {
+    /// The URL, with `{USER}` and `{IP}` placeholders. Without placeholders the username is
+    /// appended.
+    pub url: String,
+    pub method: Method,
+    /// The request body, with `{USER}` and `{IP}` placeholders.
+    pub body: Option<String>,
+    pub auth: HttpAuth,
+    /// A PEM file with the CA certificates to trust instead of the system ones.
+    pub ca_file: Option<PathBuf>,
+    /// PEM files with a client certificate chain and its private key for mutual TLS.
+    pub client_cert: Option<(PathBuf, PathBuf)>,
+    pub timeout: Duration,
+    /// How many times failed requests are tried again.
+    pub retries: u32,
+}
+
+impl HttpUserDetailSettings {
+    /// GET requests to the URL without authentication.
+    pub fn from_url(url: impl Into<String>) -> Self {
+        HttpUserDetailSettings {
+            url: url.into(),
+            method: Method::GET,
+            body: None,
+            auth: HttpAuth::None,
+            ca_file: None,
+            client_cert: None,
+            timeout: Duration::from_secs(5),
+            retries: 2,
+        }
+    }
+}
 
 /// A libunftp [`UserDetail`](libunftp::auth::UserDetail) provider that obtains user detail
 /// over HTTP.
 #[derive(Debug)]
 pub struct HTTPUserDetailProvider {
-    url: String,
-    #[allow(dead_code)]
-    header_name: Option<String>,
+    settings: HttpUserDetailSettings,
+    client: Client<HttpsConnector<HttpConnector>, Full<Bytes>>,
 }
 
 impl HTTPUserDetailProvider {
-    /// Creates a provider that will obtain user detail from the specified URL.
-    pub fn new(url: impl Into<String>) -> HTTPUserDetailProvider {
-        HTTPUserDetailProvider {
-            url: url.into(),
-            header_name: None,
+    /// Creates a provider that will obtain user detail as described by the settings.
+    pub fn new(settings: HttpUserDetailSettings) -> Result<HTTPUserDetailProvider, String> {
+        let builder = HttpsConnectorBuilder::new();
+        let builder = match (&settings.ca_file, &settings.client_cert) {
+            (None, None) => builder
+                .with_native_roots()
+                .map_err(|e| format!("could not load root certificates: {}", e))?,
+            (ca_file, client_cert) => builder.with_tls_config(tls_config(ca_file, client_cert)?),
+        };
+        let https = builder.https_or_http().enable_http1().build();
+        Ok(HTTPUserDetailProvider {
+            settings,
+            client: Client::builder(TokioExecutor::new()).build(https),
+        })
+    }
+
+    fn request(&self, username: &str, ip: &str) -> Result<Request<Full<Bytes>>, UserDetailError> {
+        let url = &self.settings.url;
+        let uri = if url.contains("{USER}") || url.contains("{IP}") {
+            let encode = |s: &str| utf8_percent_encode(s, NON_ALPHANUMERIC).to_string();
+            url.replace("{USER}", &encode(username))
+                .replace("{IP}", &encode(ip))
+        } else {
+            format!("{}{}", url, utf8_percent_encode(username, NON_ALPHANUMERIC))
+        };
+        // The body is usually JSON, so the values are escaped like JSON strings.
+        let body = self
+            .settings
+            .body
+            .as_ref()
+            .map_or_else(String::new, |body| {
+                let escape = |s: &str| serde_json::to_string(s).unwrap_or_default();
+                let (user, ip) = (escape(username), escape(ip));
+                body.replace("{USER}", &user[1..user.len() - 1])
+                    .replace("{IP}", &ip[1..ip.len() - 1])
+            });
+        let mut req = Request::builder()
+            .method(self.settings.method.clone())
+            .uri(uri)
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::ACCEPT, "application/json");
+        req = match &self.settings.auth {
+            HttpAuth::None => req,
+            HttpAuth::Bearer(token) => {
+                req.header(header::AUTHORIZATION, format!("Bearer {}", token))
+            }
+            HttpAuth::Basic { username, password } => req.header(
+                header::AUTHORIZATION,
+                format!(
+                    "Basic {}",
+                    BASE64.encode(format!("{}:{}", username, password).as_bytes())
+                ),
+            ),
+            HttpAuth::Header { name, value } => req.header(name.as_str(), value.as_str()),
+        };
+        req.body(Full::new(Bytes::from(body)))
+            .map_err(|e| UserDetailError::with_source("error creating request", e))
+    }
+
+    // Does a single request. The error tells whether trying again makes sense.
+    async fn fetch(&self, username: &str, ip: &str) -> Result<User, (bool, UserDetailError)> {
+        let req = self.request(username, ip).map_err(|e| (false, e))?;
+        let resp = self.client.request(req).await.map_err(|e| {
+            (
+                true,
+                UserDetailError::with_source("error doing HTTP request", e),
+            )
+        })?;
+
+        let status = resp.status();
+        if status == StatusCode::NOT_FOUND {
+            return Err((
+                false,
+                UserDetailError::UserNotFound {
+                    username: username.to_string(),
+                },
+            ));
+        }
+        if !status.is_success() {
+            let retry = status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS;
+            return Err((
+                retry,
+                UserDetailError::new(format!("user detail service replied with {}", status)),
+            ));
         }
+
+        let body_bytes = BodyExt::collect(resp.into_body())
+            .await
+            .map_err(|e| (true, UserDetailError::with_source("error parsing body", e)))?
+            .to_bytes();
+
+        let json_str = std::str::from_utf8(body_bytes.as_ref()).map_err(|e| {
+            (
+                false,
+                UserDetailError::with_source("body is not a valid UTF string", e),
+            )
+        })?;
+
+        // The service may return the user by itself or in a list like the JSON user file.
+        let json_usr_provider = if json_str.trim_start().starts_with('{') {
+            JsonUserProvider::from_json(&format!("[{}]", json_str))
+        } else {
+            JsonUserProvider::from_json(json_str)
+        }
+        .map_err(|e| (false, UserDetailError::Generic(e)))?;
+
+        json_usr_provider
+            .provide_user_detail(username)
+            .await
+            .map_err(|e| (false, e))
     }
 }
 
-impl Default for HTTPUserDetailProvider {
-    fn default() -> Self {
-        HTTPUserDetailProvider {
-            url: "http://localhost:8080/users/".to_string(),
-            header_name: None,
+// A TLS configuration that trusts the given CA, or the system CAs, and authenticates with the
+// given client certificate.
+fn tls_config(
+    ca_file: &Option<PathBuf>,
+    client_cert: &Option<(PathBuf, PathBuf)>,
+) -> Result<rustls::ClientConfig, String> {
+    let mut roots = rustls::RootCertStore::empty();
+    match ca_file {
+        Some(ca_file) => {
+            for cert in CertificateDer::pem_file_iter(ca_file)
+                .map_err(|e| format!("could not read CA file '{}': {}", ca_file.display(), e))?
+            {
+                let cert = cert.map_err(|e| {
+                    format!("could not read CA file '{}': {}", ca_file.display(), e)
+                })?;
+                roots.add(cert).map_err(|e| {
+                    format!("invalid certificate in '{}': {}", ca_file.display(), e)
+                })?;
+            }
+        }
+        None => {
+            let native = rustls_native_certs::load_native_certs();
+            roots.add_parsable_certificates(native.certs);
+        }
+    }
+    let builder = rustls::ClientConfig::builder().with_root_certificates(roots);
+    match client_cert {
+        None => Ok(builder.with_no_client_auth()),
+        Some((cert_file, key_file)) => {
+            let certs = CertificateDer::pem_file_iter(cert_file)
+                .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                .map_err(|e| {
+                    format!(
+                        "could not read client certificate '{}': {}",
+                        cert_file.display(),
+                        e
+                    )
+                })?;
+            let key = PrivateKeyDer::from_pem_file(key_file).map_err(|e| {
+                format!("could not read client key '{}': {}", key_file.display(), e)
+            })?;
+            builder
+                .with_client_auth_cert(certs, key)
+                .map_err(|e| format!("invalid client certificate or key: {}", e))
         }
     }
 }
@@ -42,39 +252,110 @@ impl Default for HTTPUserDetailProvider {
 #[async_trait]
 impl UserDetailProvider for HTTPUserDetailProvider {
     async fn provide_user_detail(&self, username: &str) -> Result<User, UserDetailError> {
-        let _url_suffix: String = form_urlencoded::byte_serialize(username.as_bytes()).collect();
-        let req = Request::builder()
-            .method(Method::GET)
-            .header("Content-type", "application/json")
-            .uri(format!("{}{}", self.url, username))
-            .body(Empty::<Bytes>::new())
-            .map_err(|e| UserDetailError::with_source("error creating request", e))?;
-
-        let https = hyper_rustls::HttpsConnectorBuilder::new()
-            .with_native_roots()
-            .expect("no native root CA certificates found")
-            .https_or_http()
-            .enable_http1()
-            .build();
-
-        let client = Client::builder(TokioExecutor::new()).build(https);
-
-        let resp = client
-            .request(req)
-            .await
-            .map_err(|e| UserDetailError::with_source("error doing HTTP request", e))?;
+        self.provide_user_detail_for(username, None).await
+    }
 
-        let body_bytes = BodyExt::collect(resp.into_body())
-            .await
-            .map_err(|e| UserDetailError::with_source("error parsing body", e))?
-            .to_bytes();
+    async fn provide_user_detail_for(
+        &self,
+        username: &str,
+        source_ip: Option<IpAddr>,
+    ) -> Result<User, UserDetailError> {
+        let ip = source_ip.map(|ip| ip.to_string()).unwrap_or_default();
+        let mut attempt = 0;
+        loop {
+            let result = tokio::time::timeout(self.settings.timeout, self.fetch(username, &ip))
+                .await
+                .unwrap_or_else(|_| {
+                    Err((
+                        true,
+                        UserDetailError::new("timed out waiting for the user detail service"),
+                    ))
+                });
+            match result {
+                Ok(user) => return Ok(user),
+                Err((true, _)) if attempt < self.settings.retries => {
+                    tokio::time::sleep(Duration::from_millis(100 << attempt.min(6))).await;
+                    attempt += 1;
+                }
+                Err((_, e)) => return Err(e),
+            }
+        }
+    }
+}
 
-        let json_str = std::str::from_utf8(body_bytes.as_ref())
-            .map_err(|e| UserDetailError::with_source("body is not a valid UTF string", e))?;
+#[cfg(test)]
+mod tests {
+    use super::{HTTPUserDetailProvider, HttpAuth, HttpUserDetailSettings};
+    use crate::domain::user::{UserDetailError, UserDetailProvider};
+    use http::{Method, StatusCode};
+    use http_body_util::{BodyExt, Full};
+    use hyper::{body::Bytes, server::conn::http1, service::service_fn, Request, Response};
+    use hyper_util::rt::TokioIo;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
 
-        let json_usr_provider =
-            JsonUserProvider::from_json(json_str).map_err(UserDetailError::Generic)?;
+    // Serves alice as a single object to POST requests with the right token and body, failing the
+    // first request.
+    async fn serve_users() -> String {
+        let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let requests = requests.clone();
+                tokio::spawn(http1::Builder::new().serve_connection(
+                    TokioIo::new(stream),
+                    service_fn(move |req: Request<hyper::body::Incoming>| {
+                        let requests = requests.clone();
+                        async move {
+                            let authorized = req.headers().get("authorization")
+                                == Some(&"Bearer s3cret".parse().unwrap());
+                            let path = req.uri().path().to_string();
+                            let body = req.into_body().collect().await?.to_bytes();
+                            let (status, body) = if requests.fetch_add(1, Ordering::SeqCst) == 0 {
+                                (StatusCode::SERVICE_UNAVAILABLE, "")
+                            } else if !authorized {
+                                (StatusCode::UNAUTHORIZED, "")
+                            } else if path == "/users/al%20ice" && body == r#"{"ip": "10.0.0.1"}"# {
+                                (StatusCode::OK, r#"{"username": "al ice", "root": "alice"}"#)
+                            } else {
+                                (StatusCode::NOT_FOUND, "")
+                            };
+                            let mut resp = Response::new(Full::new(Bytes::from(body)));
+                            *resp.status_mut() = status;
+                            Ok::<_, hyper::Error>(resp)
+                        }
+                    }),
+                ));
+            }
+        });
+        format!("http://{}/users/{{USER}}", addr)
+    }
 
-        json_usr_provider.provide_user_detail(username).await
+    #[tokio::test]
+    async fn users_are_fetched_with_templates_auth_and_retries() {
+        let provider = HTTPUserDetailProvider::new(HttpUserDetailSettings {
+            method: Method::POST,
+            body: Some(r#"{"ip": "{IP}"}"#.to_string()),
+            auth: HttpAuth::Bearer("s3cret".to_string()),
+            ..HttpUserDetailSettings::from_url(serve_users().await)
+        })
+        .unwrap();
+
+        let user = provider
+            .provide_user_detail_for("al ice", Some("10.0.0.1".parse().unwrap()))
+            .await
+            .unwrap();
+        assert_eq!(user.root, Some("alice".into()));
+        assert!(matches!(
+            provider
+                .provide_user_detail_for("bob", Some("10.0.0.1".parse().unwrap()))
+                .await,
+            Err(UserDetailError::UserNotFound { .. })
+        ));
     }
 }
@@ -1,16 +1,36 @@
-use crate::domain::user::{User, UserDetailError, UserDetailProvider};
+use super::reloading::ReloadingFile;
+use crate::domain::schedule::LoginSchedule;
+use crate::domain::user::{
+    parse_ip_net, parse_vfs_permissions, validate_vfs_permissions, TotpSecret, User,
+    UserDetailError, UserDetailProvider,
+};
 use async_trait::async_trait;
-use serde::Deserialize;
-use std::path::PathBuf;
+use base64::{engine, Engine};
+use chrono::{DateTime, Utc};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serde::{de::IgnoredAny, Deserialize};
+use slog::Logger;
+use std::{
+    collections::HashSet,
+    fs,
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 use unftp_sbe_restrict::VfsOperations;
 
 /// A [`UserDetailProvider`] that gets user details from a JSON file.
-#[derive(Debug, Deserialize)]
+#[derive(Debug)]
 pub struct JsonUserProvider {
     users: Vec<UserJsonObj>,
+    // What users may do before their vfs_perms are applied.
+    default_perms: VfsOperations,
 }
 
+// Unknown fields are rejected so that a misspelled setting doesn't go unnoticed. The fields of the
+// credentials file are allowed, since the same file is often used for both.
 #[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
 struct UserJsonObj {
     username: String,
     name: Option<String>,
@@ -20,12 +40,71 @@ struct UserJsonObj {
     allowed_mime_types: Option<Vec<String>>,
     root: Option<String>,
     account_enabled: Option<bool>,
+    totp_secret: Option<String>,
+    allowed_ips: Option<Vec<String>>,
+    login_schedule: Option<LoginSchedule>,
+    valid_from: Option<DateTime<Utc>>,
+    valid_until: Option<DateTime<Utc>>,
+    password_changed_at: Option<DateTime<Utc>>,
+    max_password_age: Option<u32>,
+    cert_identities: Option<Vec<String>>,
+    cert_login: Option<bool>,
+    max_download_bps: Option<u64>,
+    max_upload_bps: Option<u64>,
+    max_sessions: Option<u32>,
+    #[serde(rename = "password")]
+    _password: Option<IgnoredAny>,
+    #[serde(rename = "pbkdf2_salt")]
+    _pbkdf2_salt: Option<IgnoredAny>,
+    #[serde(rename = "pbkdf2_key")]
+    _pbkdf2_key: Option<IgnoredAny>,
+    #[serde(rename = "pbkdf2_iter")]
+    _pbkdf2_iter: Option<IgnoredAny>,
+    #[serde(rename = "client_cert")]
+    _client_cert: Option<IgnoredAny>,
+    #[serde(rename = "allowed_ip_ranges")]
+    _allowed_ip_ranges: Option<IgnoredAny>,
 }
 
 impl JsonUserProvider {
     pub fn from_json(json: &str) -> std::result::Result<JsonUserProvider, String> {
-        let v: Vec<UserJsonObj> = serde_json::from_str(json).map_err(|e| format!("{:?}", e))?;
-        Ok(JsonUserProvider { users: v })
+        Self::from_json_with_default_perms(json, VfsOperations::all())
+    }
+
+    /// Like [`from_json`](Self::from_json), but users start out with the given permissions
+    /// instead of all of them before their `vfs_perms` are applied.
+    pub fn from_json_with_default_perms(
+        json: &str,
+        default_perms: VfsOperations,
+    ) -> std::result::Result<JsonUserProvider, String> {
+        let v: Vec<UserJsonObj> = serde_json::from_str(json).map_err(|e| e.to_string())?;
+        let mut usernames = HashSet::new();
+        for u in &v {
+            if !usernames.insert(u.username.as_str()) {
+                return Err(format!("user '{}' is listed more than once", u.username));
+            }
+            if let Some(perms) = &u.vfs_perms {
+                validate_vfs_permissions(perms)
+                    .map_err(|e| format!("user '{}': {}", u.username, e))?;
+            }
+            if let Some(secret) = &u.totp_secret {
+                TotpSecret::new(secret.as_str())
+                    .map_err(|e| format!("user '{}': {}", u.username, e))?;
+            }
+            for ip in u.allowed_ips.iter().flatten() {
+                parse_ip_net(ip).map_err(|e| format!("user '{}': {}", u.username, e))?;
+            }
+            if u.max_password_age.is_some() && u.password_changed_at.is_none() {
+                return Err(format!(
+                    "user '{}': max_password_age needs password_changed_at",
+                    u.username
+                ));
+            }
+        }
+        Ok(JsonUserProvider {
+            users: v,
+            default_perms,
+        })
     }
 }
 
@@ -38,40 +117,212 @@ impl UserDetailProvider for JsonUserProvider {
             .ok_or(UserDetailError::UserNotFound {
                 username: String::from(username),
             })
-            .map(|u| {
-                let u = u.clone();
-                User {
-                    username: u.username,
-                    name: u.name,
-                    surname: u.surname,
-                    account_enabled: u.account_enabled.unwrap_or(true),
-                    vfs_permissions: u.vfs_perms.map_or(VfsOperations::all(), |p| {
-                        p.iter()
-                            .fold(VfsOperations::all(), |ops, s| match s.as_str() {
-                                "none" => VfsOperations::empty(),
-                                "all" => VfsOperations::all(),
-                                "-mkdir" => ops - VfsOperations::MK_DIR,
-                                "-rmdir" => ops - VfsOperations::RM_DIR,
-                                "-del" => ops - VfsOperations::DEL,
-                                "-ren" => ops - VfsOperations::RENAME,
-                                "-md5" => ops - VfsOperations::MD5,
-                                "-get" => ops - VfsOperations::GET,
-                                "-put" => ops - VfsOperations::PUT,
-                                "-list" => ops - VfsOperations::LIST,
-                                "+mkdir" => ops | VfsOperations::MK_DIR,
-                                "+rmdir" => ops | VfsOperations::RM_DIR,
-                                "+del" => ops | VfsOperations::DEL,
-                                "+ren" => ops | VfsOperations::RENAME,
-                                "+md5" => ops | VfsOperations::MD5,
-                                "+get" => ops | VfsOperations::GET,
-                                "+put" => ops | VfsOperations::PUT,
-                                "+list" => ops | VfsOperations::LIST,
-                                _ => ops,
-                            })
-                    }),
-                    allowed_mime_types: None,
-                    root: u.root.map(PathBuf::from),
-                }
-            })
+            .map(|u| to_user(u, self.default_perms))
+    }
+
+    async fn list_users(&self) -> Result<Vec<User>, UserDetailError> {
+        Ok(self
+            .users
+            .iter()
+            .map(|u| to_user(u, self.default_perms))
+            .collect())
+    }
+}
+
+/// A [`UserDetailProvider`] that gets user details from a JSON user file and reads the file again
+/// when it changes, so that users can be added or changed without a restart.
+#[derive(Debug)]
+pub struct JsonFileUserProvider {
+    file: ReloadingFile<JsonUserProvider>,
+}
+
+impl JsonFileUserProvider {
+    /// Reads the user file at the given path, which may be plain, gzipped or gzipped and base64
+    /// encoded JSON. Users start out with the given permissions before their `vfs_perms` are
+    /// applied.
+    pub fn from_file<P: AsRef<Path>>(
+        log: Arc<Logger>,
+        path: P,
+        default_perms: VfsOperations,
+    ) -> Result<Self, String> {
+        let file =
+            ReloadingFile::new(log, "user file", path.as_ref().to_path_buf(), move |path| {
+                let json = load_user_file(path).map_err(|e| e.to_string())?;
+                JsonUserProvider::from_json_with_default_perms(&json, default_perms)
+            })?;
+        Ok(JsonFileUserProvider { file })
+    }
+}
+
+#[async_trait]
+impl UserDetailProvider for JsonFileUserProvider {
+    async fn provide_user_detail(&self, username: &str) -> Result<User, UserDetailError> {
+        self.file.get().await.provide_user_detail(username).await
+    }
+
+    async fn list_users(&self) -> Result<Vec<User>, UserDetailError> {
+        self.file.get().await.list_users().await
+    }
+}
+
+/// How a JSON user or credentials file is stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileEncoding {
+    Plain,
+    Gzip,
+    /// Gzipped and then base64 encoded, which is useful for overcoming ConfigMap size limits in
+    /// Kubernetes.
+    GzipBase64,
+}
+
+impl FileEncoding {
+    /// Tells the encoding from the first bytes of a file.
+    pub fn detect(data: &[u8]) -> FileEncoding {
+        if data.starts_with(&[0x1F, 0x8B, 0x8]) {
+            FileEncoding::Gzip
+        } else if data.starts_with(b"H4sI") {
+            // The 3 gzip magic bytes translate to "H4sI" in base64
+            FileEncoding::GzipBase64
+        } else {
+            FileEncoding::Plain
+        }
+    }
+}
+
+/// Decodes the contents of a user or credentials file, which can be plaintext, gzipped or
+/// gzipped and base64 encoded.
+pub fn decode(data: &[u8]) -> Result<String, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let gzdata = match FileEncoding::detect(data) {
+        FileEncoding::Plain => return Ok(String::from_utf8(data.to_vec())?),
+        FileEncoding::Gzip => data.to_vec(),
+        FileEncoding::GzipBase64 => {
+            let mut b = data.to_vec();
+            b.retain(|&x| x != b'\n' && x != b'\r');
+            engine::general_purpose::STANDARD.decode(b)?
+        }
+    };
+    let mut d = GzDecoder::new(&gzdata[..]);
+    let mut s = String::new();
+    d.read_to_string(&mut s)?;
+    Ok(s)
+}
+
+/// Encodes JSON for a user or credentials file.
+pub fn encode(json: &str, encoding: FileEncoding) -> std::io::Result<Vec<u8>> {
+    if encoding == FileEncoding::Plain {
+        return Ok(json.as_bytes().to_vec());
+    }
+    let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+    gz.write_all(json.as_bytes())?;
+    let gzdata = gz.finish()?;
+    Ok(match encoding {
+        FileEncoding::GzipBase64 => engine::general_purpose::STANDARD
+            .encode(gzdata)
+            .into_bytes(),
+        _ => gzdata,
+    })
+}
+
+fn load_user_file(
+    path: &Path,
+) -> Result<std::string::String, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    decode(&fs::read(path)?)
+}
+
+fn to_user(u: &UserJsonObj, default_perms: VfsOperations) -> User {
+    let u = u.clone();
+    User {
+        username: u.username,
+        name: u.name,
+        surname: u.surname,
+        account_enabled: u.account_enabled.unwrap_or(true),
+        vfs_permissions: u
+            .vfs_perms
+            .map_or(default_perms, |p| parse_vfs_permissions(default_perms, &p)),
+        allowed_mime_types: None,
+        root: u.root.map(PathBuf::from),
+        source_ip: None,
+        auth_steps: None,
+        login_id: 0,
+        totp_secret: u.totp_secret.and_then(|s| TotpSecret::new(s).ok()),
+        allowed_ips: u
+            .allowed_ips
+            .map(|ips| ips.iter().filter_map(|ip| parse_ip_net(ip).ok()).collect()),
+        login_schedule: u.login_schedule,
+        valid_from: u.valid_from,
+        valid_until: u.valid_until,
+        password_changed_at: u.password_changed_at,
+        max_password_age: u.max_password_age,
+        cert_identities: u.cert_identities,
+        cert_login: u.cert_login.unwrap_or(false),
+        max_download_bps: u.max_download_bps.filter(|&bps| bps > 0),
+        max_upload_bps: u.max_upload_bps.filter(|&bps| bps > 0),
+        max_sessions: u.max_sessions,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode, FileEncoding, JsonFileUserProvider, JsonUserProvider};
+    use crate::domain::user::UserDetailProvider;
+    use std::sync::Arc;
+    use unftp_sbe_restrict::VfsOperations;
+
+    #[tokio::test]
+    async fn changes_to_the_user_file_are_picked_up() {
+        let log = Arc::new(slog::Logger::root(slog::Discard, slog::o!()));
+        let path = std::env::temp_dir().join(format!("unftp-users-{}.json", std::process::id()));
+        std::fs::write(&path, r#"[{"username": "alice"}]"#).unwrap();
+        let users = JsonFileUserProvider::from_file(log, &path, VfsOperations::all()).unwrap();
+        assert!(users.provide_user_detail("alice").await.is_ok());
+        assert!(users.provide_user_detail("bob").await.is_err());
+
+        // Kubernetes ConfigMaps are often too small for the plain file, so it can be gzipped and
+        // base64 encoded.
+        let encoded = encode(
+            r#"[{"username": "alice"}, {"username": "bob", "root": "bob"}]"#,
+            FileEncoding::GzipBase64,
+        )
+        .unwrap();
+        std::fs::write(&path, encoded).unwrap();
+        assert_eq!(
+            users.provide_user_detail("bob").await.unwrap().root,
+            Some("bob".into())
+        );
+
+        // A broken file leaves the users as they were.
+        std::fs::write(&path, r#"[{"username": "#).unwrap();
+        assert!(users.provide_user_detail("bob").await.is_ok());
+        assert_eq!(users.list_users().await.unwrap().len(), 2);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn user_files_are_checked_strictly() {
+        let err = JsonUserProvider::from_json(r#"[{"username": "alice", "vfs_prems": ["-put"]}]"#)
+            .unwrap_err();
+        assert!(err.contains("vfs_prems"), "{}", err);
+        let err =
+            JsonUserProvider::from_json(r#"[{"username": "alice", "vfs_perms": ["-delete"]}]"#)
+                .unwrap_err();
+        assert!(err.contains("-delete"), "{}", err);
+        let err = JsonUserProvider::from_json(r#"[{"username": "alice"}, {"username": "alice"}]"#)
+            .unwrap_err();
+        assert!(err.contains("more than once"), "{}", err);
+        // The fields of the credentials file are fine.
+        assert!(JsonUserProvider::from_json(r#"[{"username": "alice", "password": "x"}]"#).is_ok());
+
+        let users = JsonUserProvider::from_json_with_default_perms(
+            r#"[{"username": "alice"}, {"username": "bob", "vfs_perms": ["+get", "+list"]}]"#,
+            VfsOperations::empty(),
+        )
+        .unwrap();
+        let alice = users.provide_user_detail("alice").await.unwrap();
+        assert!(alice.vfs_permissions.is_empty());
+        let bob = users.provide_user_detail("bob").await.unwrap();
+        assert_eq!(
+            bob.vfs_permissions,
+            VfsOperations::GET | VfsOperations::LIST
+        );
     }
 }
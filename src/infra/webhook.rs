@@ -0,0 +1,91 @@
+//! An [EventPublisher] that POSTs events as JSON to an HTTP endpoint.
+
+use crate::domain::events::{EventDispatcher, EventPublisher, FTPEvent};
+use async_trait::async_trait;
+use http::{header, Method, Request, Uri};
+use http_body_util::Full;
+use hyper::body::Bytes;
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Sends every event in a separate POST request with the event JSON as body. The event type is
+/// added in the `X-Unftp-Event-Type` header.
+#[derive(Debug)]
+pub struct WebhookEventDispatcher {
+    log: Arc<slog::Logger>,
+    url: String,
+    headers: HashMap<String, String>,
+    client: Client<HttpsConnector<HttpConnector>, Full<Bytes>>,
+}
+
+impl WebhookEventDispatcher {
+    /// Creates a dispatcher that posts to the given URL, adding the given extra headers.
+    pub fn new(
+        log: Arc<slog::Logger>,
+        url: impl Into<String>,
+        headers: HashMap<String, String>,
+    ) -> Self {
+        let https = HttpsConnectorBuilder::new()
+            .with_native_roots()
+            .expect("no native root CA certificates found")
+            .https_or_http()
+            .enable_http1()
+            .build();
+
+        WebhookEventDispatcher {
+            log,
+            url: url.into(),
+            headers,
+            client: Client::builder(TokioExecutor::new()).build(https),
+        }
+    }
+}
+
+#[async_trait]
+impl EventPublisher<FTPEvent> for WebhookEventDispatcher {
+    async fn publish(&self, event: &FTPEvent) -> Result<(), String> {
+        let body =
+            serde_json::to_vec(event).map_err(|e| format!("error marshalling event: {}", e))?;
+
+        let mut builder = Request::builder()
+            .uri(
+                Uri::from_maybe_shared(self.url.clone())
+                    .map_err(|e| format!("invalid webhook URI: {}", e))?,
+            )
+            .method(Method::POST)
+            .header(header::CONTENT_TYPE, "application/json")
+            .header("X-Unftp-Event-Type", event.payload.event_type());
+        for (name, value) in &self.headers {
+            builder = builder.header(name.as_str(), value.as_str());
+        }
+        let request = builder
+            .body(Full::new(Bytes::from(body)))
+            .map_err(|e| format!("error with webhook request: {}", e))?;
+
+        let response = self
+            .client
+            .request(request)
+            .await
+            .map_err(|e| format!("error sending webhook request: {}", e))?;
+        if !response.status().is_success() {
+            return Err(format!(
+                "bad HTTP status code received: {}",
+                response.status()
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl EventDispatcher<FTPEvent> for WebhookEventDispatcher {
+    async fn dispatch(&self, event: FTPEvent) {
+        if let Err(e) = self.publish(&event).await {
+            slog::error!(self.log, "Could not dispatch event to webhook: {}", e);
+        }
+    }
+}
@@ -0,0 +1,147 @@
+//! Allows or denies clients by their IP address.
+//!
+//! The filter is applied when a control connection is accepted, see the accept module. In proxy
+//! protocol mode this is the address from the PROXY header rather than the one of the load balancer.
+
+use crate::domain::user::parse_ip_net;
+use crate::infra::reloading::ReloadingFile;
+use ipnet::IpNet;
+use prometheus::IntCounter;
+use slog::Logger;
+use std::{fs, net::IpAddr, path::PathBuf, sync::Arc};
+
+lazy_static! {
+    static ref IP_FILTER_DENIED: IntCounter = prometheus::register_int_counter!(
+        "unftp_ip_filter_denied_total",
+        "Connections that were rejected because the client's IP address is not allowed."
+    )
+    .unwrap();
+}
+
+/// Networks that clients may or may not connect from.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Rules {
+    pub allow: Vec<IpNet>,
+    pub deny: Vec<IpNet>,
+}
+
+impl Rules {
+    /// Parses a comma separated list of networks in CIDR notation or single addresses.
+    pub fn parse_list(list: &str) -> Result<Vec<IpNet>, String> {
+        list.split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(parse_ip_net)
+            .collect()
+    }
+
+    // Parses an IP filter file, which has an `allow` or `deny` rule with a network per line. Empty
+    // lines and lines starting with `#` are skipped.
+    fn parse_file(text: &str) -> Result<Rules, String> {
+        let mut rules = Rules::default();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (action, net) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let net = parse_ip_net(net.trim()).map_err(|e| format!("line {}: {}", n + 1, e))?;
+            match action {
+                "allow" => rules.allow.push(net),
+                "deny" => rules.deny.push(net),
+                other => {
+                    return Err(format!(
+                        "line {}: unknown rule '{}', expected 'allow' or 'deny'",
+                        n + 1,
+                        other
+                    ))
+                }
+            }
+        }
+        Ok(rules)
+    }
+}
+
+/// Decides which clients may connect, from the rules given on the command line and those in an IP
+/// filter file that is read again when it changes.
+#[derive(Debug)]
+pub struct IpFilter {
+    log: Arc<Logger>,
+    rules: Rules,
+    file: Option<ReloadingFile<Rules>>,
+}
+
+impl IpFilter {
+    pub fn new(log: Arc<Logger>, rules: Rules, file: Option<PathBuf>) -> Result<Self, String> {
+        let file = file
+            .map(|path| {
+                ReloadingFile::new(log.clone(), "IP filter file", path, |path| {
+                    let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+                    Rules::parse_file(&text)
+                })
+            })
+            .transpose()?;
+        Ok(IpFilter { log, rules, file })
+    }
+
+    /// Tells whether a client may connect from the given address. A deny rule that matches wins over
+    /// an allow rule, and if there are allow rules one of them must match. Denied clients are logged
+    /// and counted.
+    pub async fn allows(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        let from_file = match &self.file {
+            Some(file) => Some(file.get().await),
+            None => None,
+        };
+        let rules = [Some(&self.rules), from_file.as_deref()];
+        let rules = rules.iter().flatten();
+        let denied = rules
+            .clone()
+            .any(|r| r.deny.iter().any(|n| n.contains(&ip)));
+        let has_allow = rules.clone().any(|r| !r.allow.is_empty());
+        let allowed = rules
+            .clone()
+            .any(|r| r.allow.iter().any(|n| n.contains(&ip)));
+        if denied || (has_allow && !allowed) {
+            IP_FILTER_DENIED.inc();
+            slog::warn!(self.log, "Client IP address is not allowed by the IP filter"; "source_ip" => %ip);
+            return false;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{IpFilter, Rules};
+    use std::net::IpAddr;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn deny_wins_and_the_file_is_reloaded() {
+        let log = Arc::new(slog::Logger::root(slog::Discard, slog::o!()));
+        let path = std::env::temp_dir().join(format!("unftp-ip-filter-{}", std::process::id()));
+        std::fs::write(&path, "# office\nallow 10.0.0.0/8\n").unwrap();
+        let rules = Rules {
+            allow: vec![],
+            deny: Rules::parse_list("10.6.6.6, 2001:db8::/32").unwrap(),
+        };
+        let filter = IpFilter::new(log, rules, Some(path.clone())).unwrap();
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+
+        assert!(filter.allows(ip("10.1.2.3")).await);
+        assert!(filter.allows(ip("::ffff:10.1.2.3")).await);
+        assert!(!filter.allows(ip("10.6.6.6")).await);
+        assert!(!filter.allows(ip("192.168.1.1")).await);
+
+        std::fs::write(&path, "allow 192.168.0.0/16\n").unwrap();
+        assert!(filter.allows(ip("192.168.1.1")).await);
+        assert!(!filter.allows(ip("10.1.2.3")).await);
+
+        // A broken file keeps the previous rules.
+        std::fs::write(&path, "permit 10.0.0.0/8\n# longer\n").unwrap();
+        assert!(filter.allows(ip("192.168.1.1")).await);
+        assert!(Rules::parse_file("permit 10.0.0.0/8").is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
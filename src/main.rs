@@ -3,49 +3,55 @@ extern crate lazy_static;
 
 extern crate clap;
 
+mod accept;
 mod app;
 mod args;
 mod auth;
+mod cache;
+mod client_cert;
 mod domain;
+mod expiry;
+mod failed_logins;
+mod hooks;
 mod http;
 mod infra;
+mod ip_filter;
 mod logging;
 mod metrics;
 mod notify;
+mod sessions;
 mod storage;
+mod totp;
+mod user_cmd;
 
-use crate::infra::userdetail_http::HTTPUserDetailProvider;
-use crate::{
-    app::libunftp_version, args::FtpsClientAuthType, auth::DefaultUserProvider, notify::FTPListener,
-};
+use crate::infra::userdetail_http::{HTTPUserDetailProvider, HttpAuth, HttpUserDetailSettings};
+use crate::{app::libunftp_version, args::FtpsClientAuthType, notify::FTPListener};
 use ::http::Method;
 use args::AuthType;
-use auth::LookupAuthenticator;
-use base64::{engine, Engine};
+use auth::{ControlFlag, LookupAuthenticator};
 use clap::ArgMatches;
-use domain::events::{EventDispatcher, FTPEvent, FTPEventPayload};
+use domain::events::{EventDispatcher, FTPEvent, FTPEventPayload, EVENT_SCHEMA_VERSION};
 use domain::user;
-use flate2::read::GzDecoder;
-use infra::usrdetail_json::JsonUserProvider;
+use failed_logins::FailedLogins;
+use infra::usrdetail_json::JsonFileUserProvider;
 use libunftp::{
     auth as auth_spi,
     notification::{DataListener, PresenceListener},
     options,
-    options::{
-        FailedLoginsBlock, FailedLoginsPolicy, FtpsClientAuth, FtpsRequired, SiteMd5, TlsFlags,
-    },
+    options::{FtpsClientAuth, FtpsRequired, SiteMd5, TlsFlags},
     storage::StorageBackend,
     ServerBuilder,
 };
 use slog::*;
-use std::io::{Read, Seek};
 use std::{
-    env, fs,
+    env,
+    fmt::Display,
     net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs},
     path::PathBuf,
     process,
     process::Command,
     result::Result,
+    str::FromStr,
     sync::Arc,
     time::Duration,
 };
@@ -53,47 +59,15 @@ use std::{
 use unftp_auth_pam as pam;
 #[cfg(feature = "sbe_gcs")]
 use unftp_sbe_gcs::options::AuthMethod;
-use unftp_sbe_restrict::RestrictingVfs;
+use unftp_sbe_restrict::{RestrictingVfs, VfsOperations};
 use unftp_sbe_rooter::RooterVfs;
 
-fn load_user_file(
-    path: &str,
-) -> Result<std::string::String, Box<dyn std::error::Error + Send + Sync + 'static>> {
-    let mut f = fs::File::open(path)?;
-
-    // The user file can be plaintext, gzipped, or gzipped+base64-encoded
-    // The gzip-base64 format is useful for overcoming configmap size limits in Kubernetes
-    let mut magic: [u8; 4] = [0; 4];
-    let n = f.read(&mut magic[..])?;
-    let is_gz = n > 2 && magic[0] == 0x1F && magic[1] == 0x8B && magic[2] == 0x8;
-    // the 3 magic bytes translate to "H4sI" in base64
-    let is_base64gz =
-        n > 3 && magic[0] == b'H' && magic[1] == b'4' && magic[2] == b's' && magic[3] == b'I';
-
-    f.rewind()?;
-    if is_gz | is_base64gz {
-        let mut gzdata: Vec<u8> = Vec::new();
-        if is_base64gz {
-            let mut b = Vec::new();
-            f.read_to_end(&mut b)?;
-            b.retain(|&x| x != b'\n' && x != b'\r');
-            gzdata = engine::general_purpose::STANDARD.decode(b)?;
-        } else {
-            f.read_to_end(&mut gzdata)?;
-        }
-        let mut d = GzDecoder::new(&gzdata[..]);
-        let mut s = String::new();
-        d.read_to_string(&mut s)?;
-        Ok(s)
-    } else {
-        let mut s = String::new();
-        f.read_to_string(&mut s)?;
-        Ok(s)
-    }
-}
-
 fn make_auth(
+    log: &Logger,
     m: &clap::ArgMatches,
+    listener: Arc<FTPListener>,
+    failed_logins: Option<Arc<FailedLogins>>,
+    cache: Option<Arc<auth::LoginCache>>,
 ) -> Result<Arc<dyn auth_spi::Authenticator<user::User> + Send + Sync + 'static>, String> {
     let default_auth_type = AuthType::Anonymous.to_string();
     let input_auth_type = m.value_of(args::AUTH_TYPE).unwrap_or(&default_auth_type);
@@ -104,19 +78,88 @@ fn make_auth(
         }
     };
 
+    // The back-ends in use: the one given with --auth-type, or the steps of the chain.
+    let chain = match auth_type_variant {
+        AuthType::Chain => parse_auth_chain(m)?,
+        _ => vec![],
+    };
+    let uses = |auth_type: AuthType| match auth_type_variant {
+        AuthType::Chain => chain.iter().any(|(t, _)| *t == auth_type),
+        _ => auth_type_variant == auth_type,
+    };
+
+    // The LDAP connection pool is shared by the authenticator and the user detail provider.
+    #[cfg(feature = "auth_ldap")]
+    let ldap_client = if uses(AuthType::Ldap) || m.is_present(args::USR_LDAP) {
+        Some(make_ldap_client(m)?)
+    } else {
+        None
+    };
+
+    // Like the LDAP pool, the database pool is shared by the authenticator and the user detail
+    // provider.
+    #[cfg(feature = "auth_sql")]
+    let sql_pool = if uses(AuthType::Sql) || m.is_present(args::USR_SQL) {
+        Some(make_sql_pool(m)?)
+    } else {
+        None
+    };
+
+    let make = |auth_type: AuthType| -> Result<LookupAuthenticator, String> {
+        match auth_type {
+            AuthType::Anonymous => make_anon_auth(),
+            AuthType::Chain => {
+                Err("an authentication chain cannot contain another chain".to_string())
+            }
+            AuthType::Htpasswd => make_htpasswd_auth(log, m),
+            #[cfg(feature = "auth_pam")]
+            AuthType::Pam => make_pam_auth(m),
+            #[cfg(feature = "auth_rest")]
+            AuthType::Rest => make_rest_auth(m),
+            #[cfg(feature = "auth_jsonfile")]
+            AuthType::Json => make_json_auth(m),
+            #[cfg(feature = "auth_ldap")]
+            AuthType::Ldap => Ok(LookupAuthenticator::new(
+                infra::ldap::LdapAuthenticator::new(ldap_client.clone().unwrap()),
+            )),
+            #[cfg(feature = "auth_jwt")]
+            AuthType::Jwt => make_jwt_auth(log, m),
+            #[cfg(feature = "auth_sql")]
+            AuthType::Sql => Ok(LookupAuthenticator::new(infra::sql::SqlAuthenticator::new(
+                sql_pool.clone().unwrap(),
+            ))),
+        }
+    };
     let mut auth: LookupAuthenticator = match auth_type_variant {
-        AuthType::Anonymous => make_anon_auth(),
-        #[cfg(feature = "auth_pam")]
-        AuthType::Pam => make_pam_auth(m),
-        #[cfg(feature = "auth_rest")]
-        AuthType::Rest => make_rest_auth(m),
-        #[cfg(feature = "auth_jsonfile")]
-        AuthType::Json => make_json_auth(m),
-    }?;
+        AuthType::Chain => {
+            let steps = chain
+                .iter()
+                .map(|(auth_type, flag)| Ok((auth_type.to_string(), *flag, make(*auth_type)?)))
+                .collect::<Result<Vec<_>, String>>()?;
+            LookupAuthenticator::chain(Arc::new(log.new(o!("module" => "auth-chain"))), steps)
+        }
+        _ => make(auth_type_variant)?,
+    };
+
+    if auth_type_variant != AuthType::Chain && m.is_present(args::AUTH_CHAIN) {
+        return Err(format!(
+            "parameter {} set while auth_type is set to {}",
+            args::AUTH_CHAIN,
+            auth_type_variant
+        ));
+    }
+
+    if !uses(AuthType::Htpasswd) && m.is_present(args::AUTH_HTPASSWD_PATH) {
+        return Err(format!(
+            "parameter {} set while auth_type is set to {}",
+            args::AUTH_HTPASSWD_PATH,
+            auth_type_variant
+        ));
+    }
 
     #[cfg(feature = "auth_pam")]
     {
-        if auth_type_variant != AuthType::Pam && m.is_present(args::AUTH_PAM_SERVICE) {
+        if !uses(AuthType::Pam) && m.is_present(args::AUTH_PAM_SERVICE) {
             return Err(format!(
                 "parameter {} set while auth_type is set to {}",
                 args::AUTH_PAM_SERVICE,
@@ -127,7 +170,7 @@ fn make_auth(
 
     #[cfg(feature = "auth_jsonfile")]
     {
-        if auth_type_variant != AuthType::Json && m.is_present(args::AUTH_JSON_PATH) {
+        if !uses(AuthType::Json) && m.is_present(args::AUTH_JSON_PATH) {
             return Err(format!(
                 "parameter {} set while auth_type is set to {}",
                 args::AUTH_JSON_PATH,
@@ -138,7 +181,7 @@ fn make_auth(
 
     #[cfg(feature = "auth_rest")]
     {
-        if auth_type_variant != AuthType::Rest
+        if !uses(AuthType::Rest)
             && [
                 args::AUTH_REST_URL,
                 args::AUTH_REST_REGEX,
@@ -154,18 +197,132 @@ fn make_auth(
         }
     }
 
-    auth.set_usr_detail(
+    #[cfg(feature = "auth_ldap")]
+    {
+        if !uses(AuthType::Ldap)
+            && !m.is_present(args::USR_LDAP)
+            && [
+                args::AUTH_LDAP_URL,
+                args::AUTH_LDAP_USER_DN,
+                args::AUTH_LDAP_SEARCH_BASE,
+            ]
+            .iter()
+            .any(|&arg| m.is_present(arg))
+        {
+            return Err(format!(
+                "LDAP auth parameter(s) set while auth_type is set to {}",
+                auth_type_variant
+            ));
+        }
+        if m.is_present(args::USR_LDAP) {
+            if m.is_present(args::USR_JSON_PATH) || m.is_present(args::USR_HTTP_URL) {
+                return Err(format!(
+                    "please specify only one of '{}', '{}' or '{}'",
+                    args::USR_JSON_PATH,
+                    args::USR_HTTP_URL,
+                    args::USR_LDAP
+                ));
+            }
+            auth.set_usr_detail(Box::new(infra::ldap::LdapUserProvider::new(
+                ldap_client.unwrap(),
+            )));
+        }
+    }
+
+    #[cfg(feature = "auth_jwt")]
+    {
+        if !uses(AuthType::Jwt)
+            && [
+                args::AUTH_JWT_JWKS_URL,
+                args::AUTH_JWT_JWKS_FILE,
+                args::AUTH_JWT_ISSUER,
+                args::AUTH_JWT_AUDIENCE,
+            ]
+            .iter()
+            .any(|&arg| m.is_present(arg))
+        {
+            return Err(format!(
+                "JWT auth parameter(s) set while auth_type is set to {}",
+                auth_type_variant
+            ));
+        }
+    }
+
+    #[cfg(feature = "auth_sql")]
+    {
+        if !uses(AuthType::Sql)
+            && !m.is_present(args::USR_SQL)
+            && [args::AUTH_SQL_URL, args::AUTH_SQL_MIGRATE]
+                .iter()
+                .any(|&arg| m.is_present(arg))
+        {
+            return Err(format!(
+                "SQL auth parameter(s) set while auth_type is set to {}",
+                auth_type_variant
+            ));
+        }
+        if m.is_present(args::USR_SQL) {
+            let others: &[&str] = &[
+                args::USR_JSON_PATH,
+                args::USR_HTTP_URL,
+                #[cfg(feature = "auth_ldap")]
+                args::USR_LDAP,
+            ];
+            if others.iter().any(|&arg| m.is_present(arg)) {
+                return Err(format!(
+                    "please specify only one of '{}' or '{}'",
+                    others.join("', '"),
+                    args::USR_SQL
+                ));
+            }
+            auth.set_usr_detail(Box::new(infra::sql::SqlUserProvider::new(
+                sql_pool.unwrap(),
+            )));
+        }
+    }
+
+    let usr_detail_args: &[&str] = &[
+        #[cfg(feature = "auth_ldap")]
+        args::USR_LDAP,
+        #[cfg(feature = "auth_sql")]
+        args::USR_SQL,
+    ];
+    let usr_detail_set = usr_detail_args.iter().any(|&arg| m.is_present(arg));
+
+    if m.is_present(args::USR_EXPIRY_WARNING_DAYS) && !m.is_present(args::USR_JSON_PATH) {
+        return Err(format!(
+            "--{} needs the users from --{}",
+            args::USR_EXPIRY_WARNING_DAYS,
+            args::USR_JSON_PATH
+        ));
+    }
+
+    // Without a user detail provider users get the default settings, or the ones the
+    // authenticator found.
+    if !usr_detail_set {
         match (
             m.value_of(args::USR_JSON_PATH),
             m.value_of(args::USR_HTTP_URL),
         ) {
             (Some(path), None) => {
-                let json: String = load_user_file(path)
-                    .map_err(|e| format!("could not load user file '{}': {}", path, e))?;
-                Box::new(JsonUserProvider::from_json(json.as_str())?)
+                let provider = Arc::new(JsonFileUserProvider::from_file(
+                    Arc::new(log.new(o!("module" => "usr-json"))),
+                    path,
+                    match m.value_of(args::USR_VFS_PERMS_DEFAULT) {
+                        Some("none") => VfsOperations::empty(),
+                        _ => VfsOperations::all(),
+                    },
+                )?);
+                auth.set_usr_detail(Box::new(provider.clone()));
+                if m.is_present(args::USR_EXPIRY_WARNING_DAYS) {
+                    let days = parse_number(m, args::USR_EXPIRY_WARNING_DAYS)?;
+                    expiry::spawn_warnings(Arc::new(log.clone()), provider, listener.clone(), days);
+                }
             }
-            (None, Some(url)) => Box::new(HTTPUserDetailProvider::new(url)),
-            (None, None) => Box::new(DefaultUserProvider {}),
+            (None, Some(url)) => auth.set_usr_detail(Box::new(HTTPUserDetailProvider::new(
+                http_usr_detail_settings(m, url)?,
+            )?)),
+            (None, None) => {}
             _ => {
                 return Err(format!(
                     "please specify either '{}' or '{}' but not both",
@@ -173,15 +330,186 @@ fn make_auth(
                     args::USR_HTTP_URL
                 ));
             }
-        },
-    );
+        }
+    }
+    auth.set_totp(totp::Totp::new(parse_number(m, args::AUTH_TOTP_SKEW)?));
+    if let Some(sources) = m.value_of(args::FTPS_CLIENT_CERT_IDENTITY) {
+        if m.value_of(args::FTPS_CLIENT_AUTH) == Some("off") {
+            return Err(format!(
+                "--{} needs --{} to be 'request' or 'require'",
+                args::FTPS_CLIENT_CERT_IDENTITY,
+                args::FTPS_CLIENT_AUTH
+            ));
+        }
+        auth.set_client_cert_identity(
+            client_cert::parse_sources(sources)
+                .map_err(|e| format!("invalid --{}: {}", args::FTPS_CLIENT_CERT_IDENTITY, e))?,
+        );
+    }
+    if let Some(cache) = cache {
+        auth.set_cache(cache);
+    }
+    if let Some(pattern) = m.value_of(args::USR_ROOT_PATTERN) {
+        auth.set_root_pattern(pattern.to_string());
+    }
+    auth.set_logger(Arc::new(log.clone()));
+    auth.set_event_listener(listener);
+    if let Some(failed_logins) = failed_logins {
+        auth.set_failed_logins(failed_logins);
+    }
     Ok(Arc::new(auth))
 }
 
+// Creates the IP filter if --allow-ips, --deny-ips or --ip-filter-file is set.
+fn make_ip_filter(
+    log: &Logger,
+    m: &clap::ArgMatches,
+) -> Result<Option<Arc<ip_filter::IpFilter>>, String> {
+    let list = |arg: &str| match m.value_of(arg) {
+        Some(list) => {
+            ip_filter::Rules::parse_list(list).map_err(|e| format!("invalid --{}: {}", arg, e))
+        }
+        None => Ok(vec![]),
+    };
+    let rules = ip_filter::Rules {
+        allow: list(args::ALLOW_IPS)?,
+        deny: list(args::DENY_IPS)?,
+    };
+    let file = m.value_of(args::IP_FILTER_FILE).map(PathBuf::from);
+    if rules == ip_filter::Rules::default() && file.is_none() {
+        return Ok(None);
+    }
+    let filter = ip_filter::IpFilter::new(Arc::new(log.clone()), rules, file)?;
+    Ok(Some(Arc::new(filter)))
+}
+
+// Creates the cache of user details and password checks if --auth-cache-ttl is set.
+fn make_auth_cache(m: &clap::ArgMatches) -> Result<Option<Arc<auth::LoginCache>>, String> {
+    if !m.is_present(args::AUTH_CACHE_TTL) {
+        return Ok(None);
+    }
+    Ok(Some(Arc::new(auth::LoginCache::new(
+        cache::CacheSettings {
+            ttl: Duration::from_secs(parse_number(m, args::AUTH_CACHE_TTL)?),
+            negative_ttl: Duration::from_secs(parse_number(m, args::AUTH_CACHE_NEGATIVE_TTL)?),
+            max_entries: parse_number(m, args::AUTH_CACHE_SIZE)?,
+        },
+    ))))
+}
+
+// Gathers the --usr-http-* arguments.
+fn http_usr_detail_settings(
+    m: &clap::ArgMatches,
+    url: &str,
+) -> Result<HttpUserDetailSettings, String> {
+    let auth = if let Some(token) = m.value_of(args::USR_HTTP_AUTH_BEARER) {
+        HttpAuth::Bearer(token.to_string())
+    } else if let Some(basic) = m.value_of(args::USR_HTTP_AUTH_BASIC) {
+        let (username, password) = basic.split_once(':').ok_or_else(|| {
+            format!(
+                "--{} should look like USER:PASSWORD",
+                args::USR_HTTP_AUTH_BASIC
+            )
+        })?;
+        HttpAuth::Basic {
+            username: username.to_string(),
+            password: password.to_string(),
+        }
+    } else if let Some(header) = m.value_of(args::USR_HTTP_AUTH_HEADER) {
+        let (name, value) = header
+            .split_once(':')
+            .filter(|(name, _)| {
+                ::http::header::HeaderName::from_bytes(name.trim().as_bytes()).is_ok()
+            })
+            .ok_or_else(|| {
+                format!(
+                    "--{} should look like 'Name: value'",
+                    args::USR_HTTP_AUTH_HEADER
+                )
+            })?;
+        HttpAuth::Header {
+            name: name.trim().to_string(),
+            value: value.trim().to_string(),
+        }
+    } else {
+        HttpAuth::None
+    };
+    Ok(HttpUserDetailSettings {
+        method: m.value_of(args::USR_HTTP_METHOD).unwrap().parse().unwrap(),
+        body: m.value_of(args::USR_HTTP_BODY).map(str::to_string),
+        auth,
+        ca_file: m.value_of(args::USR_HTTP_CA_FILE).map(PathBuf::from),
+        client_cert: m
+            .value_of(args::USR_HTTP_CLIENT_CERT)
+            .zip(m.value_of(args::USR_HTTP_CLIENT_KEY))
+            .map(|(cert, key)| (PathBuf::from(cert), PathBuf::from(key))),
+        timeout: Duration::from_secs(parse_number(m, args::USR_HTTP_TIMEOUT)?),
+        retries: parse_number(m, args::USR_HTTP_RETRIES)?,
+        ..HttpUserDetailSettings::from_url(url)
+    })
+}
+
+// Parses the steps given with --auth-chain, like 'json:sufficient,pam:required'.
+fn parse_auth_chain(m: &clap::ArgMatches) -> Result<Vec<(AuthType, ControlFlag)>, String> {
+    let spec = m.value_of(args::AUTH_CHAIN).ok_or_else(|| {
+        format!(
+            "please provide the authentication steps by specifying --{}",
+            args::AUTH_CHAIN
+        )
+    })?;
+    let mut steps: Vec<(AuthType, ControlFlag)> = vec![];
+    for step in spec.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let (auth_type, flag) = step.split_once(':').ok_or_else(|| {
+            format!(
+                "authentication step '{}' needs a flag: required, sufficient or optional",
+                step
+            )
+        })?;
+        let auth_type = auth_type
+            .parse::<AuthType>()
+            .map_err(|_| format!("unknown auth type in --{}: {}", args::AUTH_CHAIN, auth_type))?;
+        let flag = flag.parse::<ControlFlag>().map_err(|_| {
+            format!(
+                "unknown flag '{}' for authentication step '{}', use required, sufficient or optional",
+                flag, auth_type
+            )
+        })?;
+        if auth_type == AuthType::Chain {
+            return Err("an authentication chain cannot contain another chain".to_string());
+        }
+        if steps.iter().any(|(t, _)| *t == auth_type) {
+            return Err(format!(
+                "auth type {} is used more than once in --{}",
+                auth_type,
+                args::AUTH_CHAIN
+            ));
+        }
+        steps.push((auth_type, flag));
+    }
+    if steps.is_empty() {
+        return Err(format!("--{} cannot be empty", args::AUTH_CHAIN));
+    }
+    Ok(steps)
+}
+
 fn make_anon_auth() -> Result<LookupAuthenticator, String> {
     Ok(LookupAuthenticator::new(auth_spi::AnonymousAuthenticator))
 }
 
+fn make_htpasswd_auth(log: &Logger, m: &clap::ArgMatches) -> Result<LookupAuthenticator, String> {
+    let path = m.value_of(args::AUTH_HTPASSWD_PATH).ok_or_else(|| {
+        format!(
+            "please provide the htpasswd file by specifying --{}",
+            args::AUTH_HTPASSWD_PATH
+        )
+    })?;
+    let authenticator = infra::htpasswd::HtpasswdAuthenticator::from_file(
+        Arc::new(log.new(o!("module" => "htpasswd"))),
+        path,
+    )?;
+    Ok(LookupAuthenticator::new(authenticator))
+}
+
 #[cfg(feature = "auth_pam")]
 fn make_pam_auth(m: &clap::ArgMatches) -> Result<LookupAuthenticator, String> {
     if let Some(service) = m.value_of(args::AUTH_PAM_SERVICE) {
@@ -196,7 +524,6 @@ fn make_pam_auth(m: &clap::ArgMatches) -> Result<LookupAuthenticator, String> {
 
 #[cfg(feature = "auth_rest")]
 fn make_rest_auth(m: &clap::ArgMatches) -> Result<LookupAuthenticator, String> {
-    use std::str::FromStr;
     match (
         m.value_of(args::AUTH_REST_URL),
         m.value_of(args::AUTH_REST_REGEX),
@@ -244,6 +571,113 @@ fn make_rest_auth(m: &clap::ArgMatches) -> Result<LookupAuthenticator, String> {
     }
 }
 
+#[cfg(feature = "auth_sql")]
+fn make_sql_pool(m: &clap::ArgMatches) -> Result<sqlx::AnyPool, String> {
+    let url = m.value_of(args::AUTH_SQL_URL).ok_or_else(|| {
+        format!(
+            "please provide the user database URL by specifying --{}",
+            args::AUTH_SQL_URL
+        )
+    })?;
+    infra::sql::connect(&infra::sql::SqlSettings {
+        url: url.to_string(),
+        pool_size: parse_number(m, args::AUTH_SQL_POOL_SIZE)?,
+        timeout: Duration::from_secs(parse_number(m, args::AUTH_SQL_TIMEOUT)?),
+    })
+}
+
+#[cfg(feature = "auth_ldap")]
+fn make_ldap_client(m: &clap::ArgMatches) -> Result<Arc<infra::ldap::LdapClient>, String> {
+    let url = m.value_of(args::AUTH_LDAP_URL).ok_or_else(|| {
+        format!(
+            "please provide the LDAP server URL by specifying --{}",
+            args::AUTH_LDAP_URL
+        )
+    })?;
+    let settings = infra::ldap::LdapSettings {
+        url: url.to_string(),
+        starttls: m.is_present(args::AUTH_LDAP_STARTTLS),
+        user_dn: m.value_of(args::AUTH_LDAP_USER_DN).map(String::from),
+        bind_dn: m.value_of(args::AUTH_LDAP_BIND_DN).map(String::from),
+        bind_password: m.value_of(args::AUTH_LDAP_BIND_PASSWORD).map(String::from),
+        search_base: m.value_of(args::AUTH_LDAP_SEARCH_BASE).map(String::from),
+        search_filter: m
+            .value_of(args::AUTH_LDAP_SEARCH_FILTER)
+            .unwrap()
+            .to_string(),
+        required_groups: m
+            .value_of(args::AUTH_LDAP_REQUIRED_GROUPS)
+            .map(|groups| {
+                groups
+                    .split(';')
+                    .map(str::trim)
+                    .filter(|g| !g.is_empty())
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default(),
+        group_attribute: m
+            .value_of(args::AUTH_LDAP_GROUP_ATTRIBUTE)
+            .unwrap()
+            .to_string(),
+        attributes: m
+            .value_of(args::USR_LDAP_ATTRIBUTES)
+            .unwrap()
+            .parse()
+            .map_err(|e| format!("invalid value for --{}: {}", args::USR_LDAP_ATTRIBUTES, e))?,
+        pool_size: parse_number(m, args::AUTH_LDAP_POOL_SIZE)?,
+        timeout: Duration::from_secs(parse_number(m, args::AUTH_LDAP_TIMEOUT)?),
+    };
+    Ok(Arc::new(infra::ldap::LdapClient::new(settings)?))
+}
+
+#[cfg(feature = "auth_jwt")]
+fn make_jwt_auth(log: &Logger, m: &clap::ArgMatches) -> Result<LookupAuthenticator, String> {
+    use infra::jwt::{ClaimMap, JwksSource, JwtAuthenticator, JwtSettings};
+    let jwks = match (
+        m.value_of(args::AUTH_JWT_JWKS_URL),
+        m.value_of(args::AUTH_JWT_JWKS_FILE),
+    ) {
+        (Some(url), None) => JwksSource::Url(url.to_string()),
+        (None, Some(path)) => JwksSource::File(PathBuf::from(path)),
+        _ => {
+            return Err(format!(
+                "please specify either --{} or --{}",
+                args::AUTH_JWT_JWKS_URL,
+                args::AUTH_JWT_JWKS_FILE
+            ))
+        }
+    };
+    let required = |arg: &str| -> Result<String, String> {
+        m.value_of(arg)
+            .map(String::from)
+            .ok_or_else(|| format!("--{} is required when using jwt auth", arg))
+    };
+    let role_perms = match m.value_of(args::AUTH_JWT_ROLE_PERMS) {
+        Some(mapping) => ClaimMap::parse_role_perms(mapping)
+            .map_err(|e| format!("invalid --{}: {}", args::AUTH_JWT_ROLE_PERMS, e))?,
+        None => vec![],
+    };
+    let settings = JwtSettings {
+        jwks,
+        issuer: required(args::AUTH_JWT_ISSUER)?,
+        audience: required(args::AUTH_JWT_AUDIENCE)?,
+        claims: ClaimMap {
+            username: m
+                .value_of(args::AUTH_JWT_USERNAME_CLAIM)
+                .unwrap()
+                .to_string(),
+            root: m.value_of(args::AUTH_JWT_ROOT_CLAIM).map(String::from),
+            roles: m.value_of(args::AUTH_JWT_ROLES_CLAIM).unwrap().to_string(),
+            role_perms,
+        },
+        leeway: parse_number(m, args::AUTH_JWT_LEEWAY)?,
+        refresh_interval: Duration::from_secs(parse_number(m, args::AUTH_JWT_JWKS_REFRESH)?),
+    };
+    let authenticator = JwtAuthenticator::new(Arc::new(log.new(o!("module" => "jwt"))), settings)?;
+    Ok(LookupAuthenticator::with_user_details(authenticator))
+}
+
 #[cfg(feature = "auth_jsonfile")]
 fn make_json_auth(m: &clap::ArgMatches) -> Result<LookupAuthenticator, String> {
     let path = m.value_of(args::AUTH_JSON_PATH).ok_or_else(|| {
@@ -265,20 +699,35 @@ type VfsProducer = Box<
 >;
 
 // Creates the filesystem storage back-end
-fn fs_storage_backend(log: &Logger, m: &clap::ArgMatches) -> VfsProducer {
+fn fs_storage_backend(
+    log: &Logger,
+    m: &clap::ArgMatches,
+    transfers: Arc<storage::TransferTracker>,
+    home: Option<Arc<storage::HomeSkeleton>>,
+    throttle: Arc<storage::Throttle>,
+) -> VfsProducer {
     let p: PathBuf = m.value_of(args::ROOT_DIR).unwrap().into();
     let sub_log = Arc::new(log.new(o!("module" => "storage")));
     Box::new(move || {
         RooterVfs::new(RestrictingVfs::new(storage::ChoosingVfs {
             inner: storage::InnerVfs::File(unftp_sbe_fs::Filesystem::new(p.clone()).unwrap()),
             log: sub_log.clone(),
+            transfers: transfers.clone(),
+            home: home.clone().map(storage::CreateHome::new),
+            throttle: throttle.session(),
         }))
     })
 }
 
 // Creates the GCS storage back-end
 #[cfg(feature = "sbe_gcs")]
-fn gcs_storage_backend(log: &Logger, m: &clap::ArgMatches) -> Result<VfsProducer, String> {
+fn gcs_storage_backend(
+    log: &Logger,
+    m: &clap::ArgMatches,
+    transfers: Arc<storage::TransferTracker>,
+    home: Option<Arc<storage::HomeSkeleton>>,
+    throttle: Arc<storage::Throttle>,
+) -> Result<VfsProducer, String> {
     let bucket: String = m
         .value_of(args::GCS_BUCKET)
         .ok_or_else(|| {
@@ -343,12 +792,21 @@ fn gcs_storage_backend(log: &Logger, m: &clap::ArgMatches) -> Result<VfsProducer
                 auth_method.clone(),
             )),
             log: sub_log.clone(),
+            transfers: transfers.clone(),
+            home: home.clone().map(storage::CreateHome::new),
+            throttle: throttle.session(),
         }))
     }))
 }
 
 #[cfg(feature = "sbe_azblob")]
-pub fn azblob_storage_backend(log: &Logger, m: &clap::ArgMatches) -> Result<VfsProducer, String> {
+pub fn azblob_storage_backend(
+    log: &Logger,
+    m: &clap::ArgMatches,
+    transfers: Arc<storage::TransferTracker>,
+    home: Option<Arc<storage::HomeSkeleton>>,
+    throttle: Arc<storage::Throttle>,
+) -> Result<VfsProducer, String> {
     let mut b = opendal::services::Azblob::default();
     if let Some(val) = m.value_of(args::AZBLOB_ROOT) {
         b = b.root(val);
@@ -384,12 +842,21 @@ pub fn azblob_storage_backend(log: &Logger, m: &clap::ArgMatches) -> Result<VfsP
         RooterVfs::new(RestrictingVfs::new(storage::ChoosingVfs {
             inner: storage::InnerVfs::OpenDAL(sbe.clone()),
             log: sub_log.clone(),
+            transfers: transfers.clone(),
+            home: home.clone().map(storage::CreateHome::new),
+            throttle: throttle.session(),
         }))
     }))
 }
 
 #[cfg(feature = "sbe_iso")]
-pub fn iso_storage_backend(log: &Logger, m: &clap::ArgMatches) -> Result<VfsProducer, String> {
+pub fn iso_storage_backend(
+    log: &Logger,
+    m: &clap::ArgMatches,
+    transfers: Arc<storage::TransferTracker>,
+    home: Option<Arc<storage::HomeSkeleton>>,
+    throttle: Arc<storage::Throttle>,
+) -> Result<VfsProducer, String> {
     let iso_file = m.value_of(args::ISO_FILE).ok_or_else(|| {
         format!(
             "parameter {} is required when storage backend type is 'iso'",
@@ -404,36 +871,115 @@ pub fn iso_storage_backend(log: &Logger, m: &clap::ArgMatches) -> Result<VfsProd
         RooterVfs::new(RestrictingVfs::new(storage::ChoosingVfs {
             inner: storage::InnerVfs::Iso(iso_storage.clone()),
             log: sub_log.clone(),
+            transfers: transfers.clone(),
+            home: home.clone().map(storage::CreateHome::new),
+            throttle: throttle.session(),
         }))
     }))
 }
 
 // starts the FTP server as a Tokio task.
+#[allow(clippy::too_many_arguments)]
 fn start_ftp(
     log: &Logger,
     root_log: &Logger,
     m: &clap::ArgMatches,
+    auth_cache: Option<Arc<auth::LoginCache>>,
+    sessions: Arc<sessions::Sessions>,
+    failed_logins: Option<Arc<FailedLogins>>,
+    gate: Arc<accept::Gate>,
     shutdown: tokio::sync::broadcast::Receiver<()>,
     done: tokio::sync::mpsc::Sender<()>,
 ) -> Result<(), String> {
     let event_dispatcher =
         notify::create_event_dispatcher(Arc::new(log.new(o!("module" => "storage"))), m)?;
+    let transfers = Arc::new(storage::TransferTracker::default());
+    let t = transfers.clone();
+    let home = if m.is_present(args::USR_CREATE_HOME) {
+        Some(Arc::new(match m.value_of(args::USR_HOME_SKELETON) {
+            Some(dirs) => storage::HomeSkeleton::parse(dirs)
+                .map_err(|e| format!("invalid --{}: {}", args::USR_HOME_SKELETON, e))?,
+            None => storage::HomeSkeleton::default(),
+        }))
+    } else {
+        None
+    };
+    let throttle = Arc::new(storage::Throttle::new(
+        storage::Rates {
+            download: parse_bps(m, args::THROTTLE_DOWNLOAD_BPS)?,
+            upload: parse_bps(m, args::THROTTLE_UPLOAD_BPS)?,
+        },
+        storage::Rates {
+            download: parse_bps(m, args::THROTTLE_SESSION_DOWNLOAD_BPS)?,
+            upload: parse_bps(m, args::THROTTLE_SESSION_UPLOAD_BPS)?,
+        },
+    ));
     let svc = |prod: VfsProducer| {
-        start_ftp_with_storage(log, root_log, m, prod, event_dispatcher, shutdown, done)
+        start_ftp_with_storage(
+            log,
+            root_log,
+            m,
+            prod,
+            event_dispatcher,
+            t,
+            auth_cache,
+            sessions,
+            failed_logins,
+            gate,
+            shutdown,
+            done,
+        )
     };
 
     match m.value_of(args::STORAGE_BACKEND_TYPE) {
-        None | Some("filesystem") => svc(fs_storage_backend(root_log, m)),
+        None | Some("filesystem") => {
+            svc(fs_storage_backend(root_log, m, transfers, home, throttle))
+        }
         #[cfg(feature = "sbe_gcs")]
-        Some("gcs") => svc(gcs_storage_backend(root_log, m)?),
+        Some("gcs") => svc(gcs_storage_backend(root_log, m, transfers, home, throttle)?),
         #[cfg(feature = "sbe_azblob")]
-        Some("azblob") => svc(azblob_storage_backend(root_log, m)?),
+        Some("azblob") => svc(azblob_storage_backend(
+            root_log, m, transfers, home, throttle,
+        )?),
         #[cfg(feature = "sbe_iso")]
-        Some("iso") => svc(iso_storage_backend(root_log, m)?),
+        Some("iso") => svc(iso_storage_backend(root_log, m, transfers, home, throttle)?),
         Some(x) => Err(format!("unknown storage back-end type {}", x)),
     }
 }
 
+// Parses the value of a numeric argument. The argument needs to have a value e.g. a default one.
+fn parse_number<T>(m: &clap::ArgMatches, arg: &str) -> Result<T, String>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    let value = m.value_of(arg).unwrap();
+    value.parse::<T>().map_err(|e| {
+        format!(
+            "unable to parse given value '{}' for --{}: {}. Please use a numeric value",
+            value, arg, e
+        )
+    })
+}
+
+// Parses a session limit, if it's given.
+fn parse_max_sessions(m: &clap::ArgMatches, arg: &str) -> Result<Option<u32>, String> {
+    m.value_of(arg).map(|_| parse_number(m, arg)).transpose()
+}
+
+// Parses a bytes per second limit, if it's given.
+fn parse_bps(m: &clap::ArgMatches, arg: &str) -> Result<Option<u64>, String> {
+    m.value_of(arg)
+        .map(|bps| match bps.parse::<u64>() {
+            Ok(bps) if bps > 0 => Ok(bps),
+            _ => Err(format!(
+                "unable to parse given value '{}' for --{}. Please use a positive number of bytes per second",
+                bps, arg
+            )),
+        })
+        .transpose()
+}
+
 fn resolve_dns(log: &Logger, dns_name: &str) -> Result<Ipv4Addr, String> {
     slog::info!(log, "Resolving domain name '{}'", dns_name);
     // Normalize the address. If lookup_host (https://doc.rust-lang.org/1.6.0/std/net/fn.lookup_host.html)
@@ -475,13 +1021,92 @@ fn get_passive_host_option(
     }
 }
 
+// Parses the failed logins policy (anti-bruteforce) settings into the counter that unFTP enforces
+// the policy with.
+async fn failed_logins_policy(
+    log: &Logger,
+    arg_matches: &ArgMatches,
+) -> Result<Option<FailedLogins>, String> {
+    let Some(arg) = arg_matches.value_of(args::FAILED_LOGINS_POLICY) else {
+        return Ok(None);
+    };
+    let max_attempts: u32 = parse_number(arg_matches, args::FAILED_MAX_ATTEMPTS)?;
+    let expires_after: u32 = parse_number(arg_matches, args::FAILED_EXPIRE_AFTER)?;
+
+    let policy_type = arg.parse::<args::FailedLoginsPolicyType>()?;
+    info!(
+        log,
+        "Using failed logins policy to block by {} after {} attempts and expires after {} seconds",
+        match policy_type {
+            args::FailedLoginsPolicyType::ip => "IP",
+            args::FailedLoginsPolicyType::user => "username",
+            args::FailedLoginsPolicyType::combination => "username and IP",
+        },
+        max_attempts,
+        expires_after
+    );
+
+    #[cfg(feature = "failed_logins_redis")]
+    if let Some(url) = arg_matches.value_of(args::FAILED_REDIS_URL) {
+        let failed_logins = FailedLogins::with_redis(
+            max_attempts,
+            Duration::from_secs(expires_after.into()),
+            policy_type,
+            Arc::new(log.clone()),
+            url,
+            arg_matches
+                .value_of(args::FAILED_REDIS_PREFIX)
+                .unwrap()
+                .to_string(),
+        )
+        .await
+        .map_err(|e| format!("could not connect to --{}: {}", args::FAILED_REDIS_URL, e))?;
+        info!(log, "Sharing failed login attempts through Redis");
+        return Ok(Some(failed_logins));
+    }
+    Ok(Some(FailedLogins::new(
+        max_attempts,
+        Duration::from_secs(expires_after.into()),
+        policy_type,
+    )))
+}
+
+// Parses the FTPS requirements for the control and data channel respectively.
+fn ftps_required_options(arg_matches: &ArgMatches) -> Result<(FtpsRequired, FtpsRequired), String> {
+    let ftps_req_args: Result<Vec<FtpsRequired>, String> = [
+        args::FTPS_REQUIRED_ON_CONTROL_CHANNEL,
+        args::FTPS_REQUIRED_ON_DATA_CHANNEL,
+    ]
+    .iter()
+    .map(|arg| -> Result<FtpsRequired, String> {
+        let ftps_required = match arg_matches.value_of(arg) {
+            None => libunftp::options::FtpsRequired::None,
+            Some(str) => match str.parse::<args::FtpsRequiredType>()? {
+                args::FtpsRequiredType::all => libunftp::options::FtpsRequired::All,
+                args::FtpsRequiredType::accounts => libunftp::options::FtpsRequired::Accounts,
+                args::FtpsRequiredType::none => libunftp::options::FtpsRequired::None,
+            },
+        };
+        Ok(ftps_required)
+    })
+    .collect();
+    let ftps_req_args = ftps_req_args?;
+    Ok((ftps_req_args[0], ftps_req_args[1]))
+}
+
 // Given a storage back-end, starts the FTP server as a Tokio task.
+#[allow(clippy::too_many_arguments)]
 fn start_ftp_with_storage<S>(
     log: &Logger,
     root_log: &Logger,
     arg_matches: &ArgMatches,
     storage_backend: Box<dyn (Fn() -> S) + Send + Sync>,
     event_dispatcher: Arc<dyn EventDispatcher<FTPEvent>>,
+    transfers: Arc<storage::TransferTracker>,
+    auth_cache: Option<Arc<auth::LoginCache>>,
+    sessions: Arc<sessions::Sessions>,
+    failed_logins: Option<Arc<FailedLogins>>,
+    gate: Arc<accept::Gate>,
     mut shutdown: tokio::sync::broadcast::Receiver<()>,
     done: tokio::sync::mpsc::Sender<()>,
 ) -> Result<(), String>
@@ -548,151 +1173,49 @@ where
         .unwrap()
         .to_owned();
 
-    let authenticator = make_auth(arg_matches)?;
-
-    let l = log.clone();
-
+    let logins = Arc::new(storage::LoginTracker::default());
     let listener = Arc::new(FTPListener {
         event_dispatcher: event_dispatcher.clone(),
         instance_name: instance_name.clone(),
         hostname: hostname.clone(),
+        transfers,
+        hooks: hooks::load(log, arg_matches)?,
+        logins: logins.clone(),
     });
+    notify::report_failed_transfers(&listener);
 
-    let mut server = ServerBuilder::with_authenticator(storage_backend, authenticator)
-        .greeting("Welcome to unFTP")
-        .passive_ports(start_port..=end_port)
-        .idle_session_timeout(idle_timeout)
-        .logger(root_log.new(o!("lib" => "libunftp")))
-        .passive_host(passive_host)
-        .sitemd5(md5_setting)
-        .notify_data(listener.clone() as Arc<dyn DataListener>)
-        .notify_presence(listener as Arc<dyn PresenceListener>)
-        .shutdown_indicator(async move {
-            shutdown.recv().await.ok();
-            info!(l, "Shutting down FTP server");
-            libunftp::options::Shutdown::new().grace_period(Duration::from_secs(11))
-        })
-        .metrics();
-
-    // Setup proxy protocol mode.
-    if let Some(port) = arg_matches.value_of(args::PROXY_EXTERNAL_CONTROL_PORT) {
-        let port_num = String::from(port).parse::<u16>().map_err(|e| {
-            format!(
-                "unable to parse proxy protocol external control port {}: {}",
-                port, e
-            )
-        })?;
-        server = server.proxy_protocol_mode(port_num);
-    }
-
-    // Set up failed logins policy (anti-bruteforce)
-    if let Some(arg) = arg_matches.value_of(args::FAILED_LOGINS_POLICY) {
-        let max_attempts_str = arg_matches.value_of(args::FAILED_MAX_ATTEMPTS).unwrap();
-        let max_attempts = String::from(max_attempts_str)
-            .parse::<u32>()
-            .map_err(move |e| {
-                format!(
-                    "unable to parse given value '{}' for --{}: {}. Please use a numeric value",
-                    max_attempts_str,
-                    args::FAILED_MAX_ATTEMPTS,
-                    e
-                )
-            })?;
-
-        let expires_after_str = arg_matches.value_of(args::FAILED_EXPIRE_AFTER).unwrap();
-        let expires_after = String::from(expires_after_str)
-            .parse::<u32>()
-            .map_err(move |e| {
-                format!(
-                    "unable to parse given value '{}' for --{}: {}. Please use a numeric value",
-                    expires_after_str,
-                    args::FAILED_EXPIRE_AFTER,
-                    e
-                )
-            })?;
-
-        let policy = match arg.parse::<args::FailedLoginsPolicyType>()? {
-            args::FailedLoginsPolicyType::ip => {
-                info!(
-                    log,
-                    "Using failed logins policy to block by IP after {} attempts and expires after {} seconds",
-                    max_attempts,
-                    expires_after
-                );
-                FailedLoginsPolicy::new(
-                    max_attempts,
-                    Duration::from_secs(expires_after.into()),
-                    FailedLoginsBlock::IP,
-                )
-            }
-            args::FailedLoginsPolicyType::user => {
-                info!(
-                    log,
-                    "Using failed logins policy to block by username after {} attempts and expires after {} seconds",
-                    max_attempts,
-                    expires_after
-                );
-                FailedLoginsPolicy::new(
-                    max_attempts,
-                    Duration::from_secs(expires_after.into()),
-                    FailedLoginsBlock::User,
-                )
-            }
-            args::FailedLoginsPolicyType::combination => {
-                info!(
-                    log,
-                    "Using failed logins policy to block by username and IP after {} attempts and expires after {} seconds",
-                    max_attempts,
-                    expires_after
-                );
-                FailedLoginsPolicy::new(
-                    max_attempts,
-                    Duration::from_secs(expires_after.into()),
-                    FailedLoginsBlock::UserAndIP,
-                )
-            }
-        };
+    let authenticator = make_auth(
+        log,
+        arg_matches,
+        listener.clone(),
+        failed_logins,
+        auth_cache,
+    )?;
 
-        server = server.failed_logins_policy(policy);
-    }
+    let storage_backend: Arc<dyn (Fn() -> storage::SessionVfs<S>) + Send + Sync> =
+        Arc::new(move || {
+            storage::SessionVfs::new(storage_backend(), sessions.clone(), logins.clone())
+        });
+    let libunftp_log =
+        notify::watch_tls_failures(root_log.new(o!("lib" => "libunftp")), listener.clone());
 
     // Setup FTPS
-    server = match (
+    let ftps = match (
         arg_matches.value_of(args::FTPS_CERTS_FILE),
         arg_matches.value_of(args::FTPS_KEY_FILE),
     ) {
         (Some(certs_file), Some(key_file)) => {
             info!(log, "FTPS enabled");
-            let server = server.ftps(certs_file, key_file);
-            let ftps_req_args: Result<Vec<FtpsRequired>, String> = [
-                args::FTPS_REQUIRED_ON_CONTROL_CHANNEL,
-                args::FTPS_REQUIRED_ON_DATA_CHANNEL,
-            ]
-            .iter()
-            .map(|arg| -> Result<FtpsRequired, String> {
-                let ftps_required = match arg_matches.value_of(arg) {
-                    None => libunftp::options::FtpsRequired::None,
-                    Some(str) => match str.parse::<args::FtpsRequiredType>()? {
-                        args::FtpsRequiredType::all => libunftp::options::FtpsRequired::All,
-                        args::FtpsRequiredType::accounts => {
-                            libunftp::options::FtpsRequired::Accounts
-                        }
-                        args::FtpsRequiredType::none => libunftp::options::FtpsRequired::None,
-                    },
-                };
-                Ok(ftps_required)
-            })
-            .collect();
-            let ftps_req_args = ftps_req_args?;
-            let (ftps_required_control, ftps_required_data) = (ftps_req_args[0], ftps_req_args[1]);
+            let (ftps_required_control, ftps_required_data) = ftps_required_options(arg_matches)?;
 
             info!(log, "FTPS requirement for clients on control channel: {}", ftps_required_control; "mode" => format!("{:?}", ftps_required_control));
             info!(log, "FTPS requirement for clients on data channel: {}", ftps_required_data; "mode" => format!("{:?}", ftps_required_data));
-            server
-                .ftps_required(ftps_required_control, ftps_required_data)
-                .ftps_tls_flags(
-                    TlsFlags::V1_2 | TlsFlags::RESUMPTION_SESS_ID | TlsFlags::RESUMPTION_TICKETS,
-                )
+            Some((
+                PathBuf::from(certs_file),
+                PathBuf::from(key_file),
+                ftps_required_control,
+                ftps_required_data,
+            ))
         }
         (Some(_), None) | (None, Some(_)) => {
             warn!(
@@ -701,23 +1224,23 @@ where
                 args::FTPS_CERTS_FILE,
                 args::FTPS_KEY_FILE
             );
-            server
+            None
         }
         _ => {
             warn!(log, "FTPS not enabled");
-            server
+            None
         }
     };
 
     // MTLS
-    server = match (
+    let (client_auth, trust_store) = match (
         arg_matches
             .value_of(args::FTPS_CLIENT_AUTH)
             .unwrap()
             .parse::<args::FtpsClientAuthType>()?,
         arg_matches.value_of(args::FTPS_TRUST_STORE),
     ) {
-        (FtpsClientAuthType::off, _) => server.ftps_client_auth(FtpsClientAuth::Off),
+        (FtpsClientAuthType::off, _) => (FtpsClientAuth::Off, None),
         (FtpsClientAuthType::request, None) | (FtpsClientAuthType::require, None) => {
             warn!(
                 log,
@@ -725,7 +1248,7 @@ where
                 args::FTPS_CLIENT_AUTH,
                 args::FTPS_TRUST_STORE
             );
-            server.ftps_client_auth(FtpsClientAuth::Off)
+            (FtpsClientAuth::Off, None)
         }
         (FtpsClientAuthType::request, Some(file)) => {
             if !PathBuf::from(file).exists() {
@@ -734,9 +1257,7 @@ where
                     args::FTPS_TRUST_STORE
                 ));
             }
-            server
-                .ftps_client_auth(FtpsClientAuth::Request)
-                .ftps_trust_store(file)
+            (FtpsClientAuth::Request, Some(PathBuf::from(file)))
         }
         (FtpsClientAuthType::require, Some(file)) => {
             if !PathBuf::from(file).exists() {
@@ -745,28 +1266,118 @@ where
                     args::FTPS_TRUST_STORE
                 ));
             }
-            server
-                .ftps_client_auth(FtpsClientAuth::Require)
-                .ftps_trust_store(file)
+            (FtpsClientAuth::Require, Some(PathBuf::from(file)))
         }
     };
 
-    let server = server
-        .build()
-        .map_err(|e| format!("Could not build server: {}", e))?;
+    // unFTP accepts the connections itself and serves each with a server of its own, see the accept
+    // module.
+    let new_builder = move || {
+        let storage_backend = storage_backend.clone();
+        let mut server = ServerBuilder::with_authenticator(
+            Box::new(move || storage_backend()),
+            authenticator.clone(),
+        )
+        .greeting("Welcome to unFTP")
+        .passive_ports(start_port..=end_port)
+        .idle_session_timeout(idle_timeout)
+        .logger(libunftp_log.clone())
+        .passive_host(passive_host.clone())
+        .sitemd5(md5_setting)
+        .notify_data(listener.clone() as Arc<dyn DataListener>)
+        .notify_presence(listener.clone() as Arc<dyn PresenceListener>)
+        .metrics();
+        if let Some((certs_file, key_file, required_control, required_data)) = &ftps {
+            server = server
+                .ftps(certs_file.clone(), key_file.clone())
+                .ftps_required(*required_control, *required_data)
+                .ftps_tls_flags(
+                    TlsFlags::V1_2 | TlsFlags::RESUMPTION_SESS_ID | TlsFlags::RESUMPTION_TICKETS,
+                );
+        }
+        server = server.ftps_client_auth(client_auth);
+        if let Some(file) = &trust_store {
+            server = server.ftps_trust_store(file.clone());
+        }
+        server
+    };
 
+    let addr: SocketAddr = addr
+        .parse()
+        .map_err(|e| format!("could not parse FTP address {}: {}", addr, e))?;
+    let grace_period = Duration::from_secs(11);
     let log = log.clone();
-    tokio::spawn(async move {
-        if let Err(e) = server.listen(addr).await {
-            error!(log, "FTP server error: {:?}", e)
+    match arg_matches.value_of(args::PROXY_EXTERNAL_CONTROL_PORT) {
+        // Setup proxy protocol mode.
+        Some(port) => {
+            let port_num = String::from(port).parse::<u16>().map_err(|e| {
+                format!(
+                    "unable to parse proxy protocol external control port {}: {}",
+                    port, e
+                )
+            })?;
+            // libunftp gets the connections that are let in on a free port of the loopback interface.
+            let backend = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+                .and_then(|l| l.local_addr())
+                .map_err(|e| format!("could not find a port for the FTP server: {}", e))?;
+            let front_door = accept::front_door(
+                log.clone(),
+                addr,
+                backend,
+                port_num,
+                gate,
+                shutdown.resubscribe(),
+            );
+            let l = log.clone();
+            let server = new_builder()
+                .proxy_protocol_mode(port_num)
+                .shutdown_indicator(async move {
+                    shutdown.recv().await.ok();
+                    info!(l, "Shutting down FTP server");
+                    options::Shutdown::new().grace_period(grace_period)
+                })
+                .build()
+                .map_err(|e| format!("Could not build server: {}", e))?;
+            let l = log.clone();
+            tokio::spawn(async move {
+                if let Err(e) = front_door.await {
+                    error!(l, "FTP server error: {}", e)
+                }
+            });
+            tokio::spawn(async move {
+                if let Err(e) = server.listen(backend.to_string()).await {
+                    error!(log, "FTP server error: {:?}", e)
+                }
+                info!(log, "FTP exiting");
+                drop(done)
+            });
         }
-        info!(log, "FTP exiting");
-        drop(done)
-    });
+        None => {
+            // Building one server up front stops unFTP on errors like unreadable certificates.
+            new_builder()
+                .build()
+                .map_err(|e| format!("Could not build server: {}", e))?;
+            let new_server = move || {
+                new_builder()
+                    .build()
+                    .map_err(|e| format!("Could not build server: {}", e))
+            };
+            tokio::spawn(async move {
+                let serving =
+                    accept::serve(log.clone(), addr, gate, new_server, shutdown, grace_period);
+                if let Err(e) = serving.await {
+                    error!(log, "FTP server error: {}", e)
+                }
+                info!(log, "FTP exiting");
+                drop(done)
+            });
+        }
+    }
 
     tokio::spawn(async move {
         event_dispatcher
             .dispatch(FTPEvent {
+                schema_version: EVENT_SCHEMA_VERSION,
                 source_instance: instance_name,
                 hostname,
                 payload: FTPEventPayload::Startup {
@@ -837,12 +1448,61 @@ async fn main_task(
         .parse()
         .map_err(|_| "could not parse FTP address")?;
 
+    let auth_cache = make_auth_cache(&arg_matches)?;
+    let failed_logins = failed_logins_policy(log, &arg_matches).await?.map(Arc::new);
+    let sessions = Arc::new(sessions::Sessions::new(sessions::SessionLimits {
+        total: parse_max_sessions(&arg_matches, args::MAX_SESSIONS)?,
+        per_ip: parse_max_sessions(&arg_matches, args::MAX_SESSIONS_PER_IP)?,
+    }));
+    let probes = Arc::new(accept::Probes::default());
+    let gate = Arc::new(accept::Gate::new(
+        log.new(o!("module" => "accept")),
+        make_ip_filter(log, &arg_matches)?,
+        sessions.clone(),
+        probes.clone(),
+    ));
+
+    #[cfg(feature = "auth_sql")]
+    if arg_matches.is_present(args::AUTH_SQL_MIGRATE) {
+        let pool = make_sql_pool(&arg_matches)?;
+        infra::sql::migrate(&pool).await?;
+        pool.close().await;
+        info!(log, "Migrated the user database");
+    }
+
+    let admin_token: Option<Arc<str>> = match arg_matches.is_present(args::ENABLE_HTTP_ADMIN) {
+        false => None,
+        true => match arg_matches.value_of(args::HTTP_ADMIN_TOKEN) {
+            Some(token) if !token.is_empty() => Some(Arc::from(token)),
+            _ => {
+                return Err(format!(
+                    "--{} needs a --{}",
+                    args::ENABLE_HTTP_ADMIN,
+                    args::HTTP_ADMIN_TOKEN
+                ))
+            }
+        },
+    };
     if let Some(addr) = arg_matches.value_of(args::HTTP_BIND_ADDRESS) {
         let addr = String::from(addr);
         let log = log.clone();
+        let auth_cache = auth_cache.clone();
+        let sessions = sessions.clone();
+        let failed_logins = failed_logins.clone();
         tokio::spawn(async move {
-            if let Err(e) =
-                http::start(&log, &addr, ftp_addr, http_receiver, http_done_sender).await
+            if let Err(e) = http::start(
+                &log,
+                &addr,
+                ftp_addr,
+                auth_cache,
+                sessions,
+                failed_logins,
+                probes,
+                admin_token,
+                http_receiver,
+                http_done_sender,
+            )
+            .await
             {
                 error!(log, "HTTP Server error: {}", e)
             }
@@ -853,6 +1513,10 @@ async fn main_task(
         log,
         root_log,
         &arg_matches,
+        auth_cache,
+        sessions,
+        failed_logins,
+        gate,
         shutdown_sender.subscribe(),
         ftp_done_sender,
     )?;
@@ -949,6 +1613,14 @@ async fn main() {
     let tmp_dir = tmp_dir.as_path().to_str().unwrap();
     let arg_matches = args::clap_app(tmp_dir).get_matches();
 
+    if let Some(m) = arg_matches.subcommand_matches(args::USER_CMD) {
+        if let Err(e) = user_cmd::run(m) {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
     #[cfg(feature = "tokio_console")]
     {
         let console_addr: SocketAddr = arg_matches
@@ -0,0 +1,232 @@
+//! Counts the sessions in total, per client IP and per user and enforces the session limits.
+//!
+//! The total and per IP limits are checked when a client connects, see the accept module, so that
+//! clients over them get a `421` reply before the greeting. The user is only known at login:
+//! libunftp creates a storage back-end for every connection and tells it who logged in, and the
+//! [`SessionVfs`](crate::storage::SessionVfs) around it claims a login here. Both are given back when
+//! the connection ends.
+
+use prometheus::{IntCounterVec, IntGauge, Opts};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::Notify;
+
+lazy_static! {
+    static ref SESSIONS: IntGauge =
+        prometheus::register_int_gauge!("unftp_sessions", "The number of connected sessions.")
+            .unwrap();
+    static ref SESSION_USERS: IntGauge = prometheus::register_int_gauge!(
+        "unftp_session_users",
+        "The number of users with at least one logged in session."
+    )
+    .unwrap();
+    static ref SESSION_IPS: IntGauge = prometheus::register_int_gauge!(
+        "unftp_session_ips",
+        "The number of client IP addresses with at least one connected session."
+    )
+    .unwrap();
+    static ref SESSION_REJECTIONS: IntCounterVec = prometheus::register_int_counter_vec!(
+        Opts::new(
+            "unftp_session_rejections_total",
+            "Connections and logins that were rejected because of a session limit."
+        ),
+        &["limit"]
+    )
+    .unwrap();
+}
+
+/// The limits on the number of connected sessions.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SessionLimits {
+    /// The sessions of all clients together.
+    pub total: Option<u32>,
+    /// The sessions from a single client IP address.
+    pub per_ip: Option<u32>,
+}
+
+#[derive(Debug, Default)]
+struct Counts {
+    total: u32,
+    users: HashMap<String, u32>,
+    ips: HashMap<IpAddr, u32>,
+}
+
+/// The current number of sessions, as shown on the HTTP admin API.
+#[derive(Debug, Serialize)]
+pub struct SessionCounts {
+    pub total: u32,
+    pub users: BTreeMap<String, u32>,
+    pub ips: BTreeMap<IpAddr, u32>,
+}
+
+/// Keeps count of the sessions.
+#[derive(Debug, Default)]
+pub struct Sessions {
+    limits: SessionLimits,
+    counts: Mutex<Counts>,
+    // Woken when the last logged in session is given back.
+    logged_out: Notify,
+}
+
+impl Sessions {
+    pub fn new(limits: SessionLimits) -> Self {
+        Sessions {
+            limits,
+            counts: Mutex::default(),
+            logged_out: Notify::new(),
+        }
+    }
+
+    /// Claims a session for a client that connects from the given address. Fails if that would go
+    /// over the total or the per IP limit.
+    pub fn connect(self: &Arc<Self>, ip: IpAddr) -> Result<ConnectionClaim, String> {
+        let mut counts = self.counts.lock().unwrap();
+        let ip_count = counts.ips.get(&ip).copied().unwrap_or(0);
+        let rejected = if self.limits.total.is_some_and(|max| counts.total >= max) {
+            Some(("total", "too many sessions on the server"))
+        } else if self.limits.per_ip.is_some_and(|max| ip_count >= max) {
+            Some(("ip", "too many sessions from the client's IP address"))
+        } else {
+            None
+        };
+        if let Some((limit, reason)) = rejected {
+            SESSION_REJECTIONS.with_label_values(&[limit]).inc();
+            return Err(reason.to_string());
+        }
+        counts.total += 1;
+        *counts.ips.entry(ip).or_default() += 1;
+        update_gauges(&counts);
+        Ok(ConnectionClaim {
+            sessions: self.clone(),
+            ip,
+        })
+    }
+
+    /// Claims a login for the user, who may have at most `user_max` sessions. Fails if that would
+    /// go over it.
+    pub fn log_in(
+        self: &Arc<Self>,
+        username: &str,
+        user_max: Option<u32>,
+    ) -> Result<LoginClaim, String> {
+        let mut counts = self.counts.lock().unwrap();
+        let user_count = counts.users.get(username).copied().unwrap_or(0);
+        if user_max.is_some_and(|max| user_count >= max) {
+            SESSION_REJECTIONS.with_label_values(&["user"]).inc();
+            return Err(format!("too many sessions for user '{}'", username));
+        }
+        *counts.users.entry(username.to_string()).or_default() += 1;
+        update_gauges(&counts);
+        Ok(LoginClaim {
+            sessions: self.clone(),
+            username: username.to_string(),
+        })
+    }
+
+    /// Waits until no user is logged in any more.
+    pub async fn all_logged_out(&self) {
+        loop {
+            let logged_out = self.logged_out.notified();
+            if self.counts.lock().unwrap().users.is_empty() {
+                return;
+            }
+            logged_out.await;
+        }
+    }
+
+    pub fn counts(&self) -> SessionCounts {
+        let counts = self.counts.lock().unwrap();
+        SessionCounts {
+            total: counts.total,
+            users: counts.users.iter().map(|(k, v)| (k.clone(), *v)).collect(),
+            ips: counts.ips.iter().map(|(k, v)| (*k, *v)).collect(),
+        }
+    }
+}
+
+fn update_gauges(counts: &Counts) {
+    SESSIONS.set(counts.total.into());
+    SESSION_USERS.set(counts.users.len() as i64);
+    SESSION_IPS.set(counts.ips.len() as i64);
+}
+
+/// A connected session, which is given back when this is dropped.
+#[derive(Debug)]
+pub struct ConnectionClaim {
+    sessions: Arc<Sessions>,
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionClaim {
+    fn drop(&mut self) {
+        let mut counts = self.sessions.counts.lock().unwrap();
+        counts.total = counts.total.saturating_sub(1);
+        if let Some(count) = counts.ips.get_mut(&self.ip) {
+            *count -= 1;
+        }
+        counts.ips.retain(|_, count| *count > 0);
+        update_gauges(&counts);
+    }
+}
+
+/// A logged in session of a user, which is given back when this is dropped.
+#[derive(Debug)]
+pub struct LoginClaim {
+    sessions: Arc<Sessions>,
+    username: String,
+}
+
+impl Drop for LoginClaim {
+    fn drop(&mut self) {
+        let mut counts = self.sessions.counts.lock().unwrap();
+        if let Some(count) = counts.users.get_mut(&self.username) {
+            *count -= 1;
+        }
+        counts.users.retain(|_, count| *count > 0);
+        update_gauges(&counts);
+        if counts.users.is_empty() {
+            self.sessions.logged_out.notify_waiters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SessionLimits, Sessions};
+    use std::net::IpAddr;
+    use std::sync::Arc;
+
+    #[test]
+    fn sessions_are_limited_per_user_ip_and_in_total() {
+        let sessions = Arc::new(Sessions::new(SessionLimits {
+            total: Some(3),
+            per_ip: Some(2),
+        }));
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+
+        let first = sessions.connect(ip("10.0.0.1")).unwrap();
+        let second = sessions.connect(ip("10.0.0.1")).unwrap();
+        assert!(sessions.connect(ip("10.0.0.1")).is_err());
+        let _third = sessions.connect(ip("10.0.0.2")).unwrap();
+        assert!(sessions.connect(ip("10.0.0.3")).is_err());
+        assert_eq!(sessions.counts().ips.len(), 2);
+
+        let alice = sessions.log_in("alice", Some(1)).unwrap();
+        assert!(sessions.log_in("alice", Some(1)).is_err());
+        let _bob = sessions.log_in("bob", None).unwrap();
+
+        drop(alice);
+        drop(first);
+        drop(second);
+        let counts = sessions.counts();
+        assert_eq!(counts.total, 1);
+        assert!(!counts.ips.contains_key(&ip("10.0.0.1")));
+        assert!(!counts.users.contains_key("alice"));
+        assert!(sessions.log_in("alice", Some(1)).is_ok());
+        assert!(sessions.connect(ip("10.0.0.1")).is_ok());
+    }
+}
@@ -2,13 +2,18 @@ use std::fmt::Debug;
 use std::io::Cursor;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::SystemTime;
+use std::time::{Instant, SystemTime};
 
 use async_trait::async_trait;
 use libunftp::storage;
 use libunftp::storage::{Fileinfo, StorageBackend};
 
-use crate::domain::user::User;
+use super::home::CreateHome;
+use super::throttle::{SessionThrottle, ThrottledReader, ThrottledWriter};
+use super::transfers::{
+    Direction, MeteredReader, MeteredWriter, Transfer, TransferMeter, TransferTracker,
+};
+use crate::domain::{events::TransferOutcome, user::User};
 
 /**
  * A virtual file system that represents either a Cloud or file system back-end.
@@ -16,8 +21,10 @@ use crate::domain::user::User;
 #[derive(Debug)]
 pub struct ChoosingVfs {
     pub inner: InnerVfs,
-    #[allow(dead_code)]
     pub log: Arc<slog::Logger>,
+    pub transfers: Arc<TransferTracker>,
+    pub home: Option<CreateHome>,
+    pub throttle: SessionThrottle,
 }
 
 #[derive(Debug)]
@@ -32,6 +39,90 @@ pub enum InnerVfs {
     Iso(unftp_sbe_iso::Storage),
 }
 
+impl InnerVfs {
+    /// The name of the storage back-end type as accepted by --sbe-type.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            #[cfg(feature = "sbe_azblob")]
+            InnerVfs::OpenDAL(_) => "azblob",
+            #[cfg(feature = "sbe_gcs")]
+            InnerVfs::Cloud(_) => "gcs",
+            InnerVfs::File(_) => "filesystem",
+            #[cfg(feature = "sbe_iso")]
+            InnerVfs::Iso(_) => "iso",
+        }
+    }
+}
+
+impl ChoosingVfs {
+    // Creates the user's home directory and its skeleton if they don't exist yet. This goes
+    // straight to the inner back-end so that it isn't held to the user's permissions.
+    async fn create_home(&self, user: &User) {
+        let (Some(home), Some(root)) = (&self.home, &user.root) else {
+            return;
+        };
+        if !home.start() {
+            return;
+        }
+        for dir in home.dirs(root) {
+            let exists = match &self.inner {
+                #[cfg(feature = "sbe_azblob")]
+                InnerVfs::OpenDAL(i) => i.metadata(user, &dir).await.is_ok(),
+                #[cfg(feature = "sbe_gcs")]
+                InnerVfs::Cloud(i) => i.metadata(user, &dir).await.is_ok(),
+                InnerVfs::File(i) => i.metadata(user, &dir).await.is_ok(),
+                #[cfg(feature = "sbe_iso")]
+                InnerVfs::Iso(i) => i.metadata(user, &dir).await.is_ok(),
+            };
+            if exists {
+                continue;
+            }
+            let created = match &self.inner {
+                #[cfg(feature = "sbe_azblob")]
+                InnerVfs::OpenDAL(i) => i.mkd(user, &dir).await,
+                #[cfg(feature = "sbe_gcs")]
+                InnerVfs::Cloud(i) => i.mkd(user, &dir).await,
+                InnerVfs::File(i) => i.mkd(user, &dir).await,
+                #[cfg(feature = "sbe_iso")]
+                InnerVfs::Iso(i) => i.mkd(user, &dir).await,
+            };
+            match created {
+                Ok(()) => {
+                    slog::info!(self.log, "Created home directory"; "username" => &user.username, "path" => dir.display().to_string())
+                }
+                Err(e) => {
+                    slog::warn!(self.log, "Could not create home directory: {}", e; "username" => &user.username, "path" => dir.display().to_string());
+                    return;
+                }
+            }
+        }
+    }
+
+    fn record_transfer<T>(
+        &self,
+        user: &User,
+        direction: Direction,
+        path: PathBuf,
+        started: Instant,
+        meter: &TransferMeter,
+        result: &storage::Result<T>,
+    ) {
+        let outcome = match result {
+            Ok(_) => TransferOutcome::Succeeded,
+            Err(_) => TransferOutcome::Failed,
+        };
+        self.transfers.record(Transfer::new(
+            user,
+            direction,
+            path,
+            started,
+            self.inner.type_name(),
+            meter,
+            outcome,
+        ));
+    }
+}
+
 #[derive(Debug)]
 pub enum SbeMeta {
     #[cfg(feature = "sbe_azblob")]
@@ -162,6 +253,7 @@ impl StorageBackend<User> for ChoosingVfs {
         user: &User,
         path: P,
     ) -> storage::Result<Self::Metadata> {
+        self.create_home(user).await;
         match &self.inner {
             #[cfg(feature = "sbe_azblob")]
             InnerVfs::OpenDAL(i) => i.metadata(user, path).await.map(SbeMeta::OpenDAL),
@@ -181,6 +273,7 @@ impl StorageBackend<User> for ChoosingVfs {
     where
         <Self as StorageBackend<User>>::Metadata: libunftp::storage::Metadata,
     {
+        self.create_home(user).await;
         match &self.inner {
             #[cfg(feature = "sbe_azblob")]
             InnerVfs::OpenDAL(i) => i.list(user, path).await.map(|v| {
@@ -225,6 +318,7 @@ impl StorageBackend<User> for ChoosingVfs {
         P: AsRef<Path> + Send + Debug,
         Self::Metadata: libunftp::storage::Metadata + 'static,
     {
+        self.create_home(user).await;
         match &self.inner {
             #[cfg(feature = "sbe_azblob")]
             InnerVfs::OpenDAL(i) => i.list_fmt(user, path).await,
@@ -241,6 +335,7 @@ impl StorageBackend<User> for ChoosingVfs {
         P: AsRef<Path> + Send + Debug,
         Self::Metadata: libunftp::storage::Metadata + 'static,
     {
+        self.create_home(user).await;
         match &self.inner {
             #[cfg(feature = "sbe_azblob")]
             InnerVfs::OpenDAL(i) => i.nlst(user, path).await,
@@ -263,7 +358,16 @@ impl StorageBackend<User> for ChoosingVfs {
         W: tokio::io::AsyncWrite + Unpin + Sync + Send,
         P: AsRef<Path> + Send + Debug,
     {
-        match &self.inner {
+        self.create_home(user).await;
+        let started = Instant::now();
+        let path_buf = path.as_ref().to_path_buf();
+        // The digest of a resumed transfer would only cover part of the file.
+        let meter = TransferMeter::new(start_pos == 0);
+        let mut output = MeteredWriter::new(output, meter.clone());
+        let mut output =
+            ThrottledWriter::new(&mut output, self.throttle.limits(user, Direction::Get));
+        let output = &mut output;
+        let result = match &self.inner {
             #[cfg(feature = "sbe_azblob")]
             InnerVfs::OpenDAL(i) => i.get_into(user, path, start_pos, output).await,
             #[cfg(feature = "sbe_gcs")]
@@ -271,7 +375,9 @@ impl StorageBackend<User> for ChoosingVfs {
             InnerVfs::File(i) => i.get_into(user, path, start_pos, output).await,
             #[cfg(feature = "sbe_iso")]
             InnerVfs::Iso(i) => i.get_into(user, path, start_pos, output).await,
-        }
+        };
+        self.record_transfer(user, Direction::Get, path_buf, started, &meter, &result);
+        result
     }
 
     async fn get<P: AsRef<Path> + Send + Debug>(
@@ -280,6 +386,7 @@ impl StorageBackend<User> for ChoosingVfs {
         path: P,
         start_pos: u64,
     ) -> storage::Result<Box<dyn tokio::io::AsyncRead + Send + Sync + Unpin>> {
+        self.create_home(user).await;
         match &self.inner {
             #[cfg(feature = "sbe_azblob")]
             InnerVfs::OpenDAL(i) => i.get(user, path, start_pos).await,
@@ -313,7 +420,15 @@ impl StorageBackend<User> for ChoosingVfs {
         path: P,
         start_pos: u64,
     ) -> storage::Result<u64> {
-        match &self.inner {
+        self.create_home(user).await;
+        let started = Instant::now();
+        let path_buf = path.as_ref().to_path_buf();
+        let meter = TransferMeter::new(start_pos == 0);
+        let input = ThrottledReader::new(
+            MeteredReader::new(input, meter.clone()),
+            self.throttle.limits(user, Direction::Put),
+        );
+        let result = match &self.inner {
             #[cfg(feature = "sbe_azblob")]
             InnerVfs::OpenDAL(i) => i.put(user, input, path, start_pos).await,
             #[cfg(feature = "sbe_gcs")]
@@ -321,7 +436,9 @@ impl StorageBackend<User> for ChoosingVfs {
             InnerVfs::File(i) => i.put(user, input, path, start_pos).await,
             #[cfg(feature = "sbe_iso")]
             InnerVfs::Iso(i) => i.put(user, input, path, start_pos).await,
-        }
+        };
+        self.record_transfer(user, Direction::Put, path_buf, started, &meter, &result);
+        result
     }
 
     async fn del<P: AsRef<Path> + Send + Debug>(
@@ -329,6 +446,7 @@ impl StorageBackend<User> for ChoosingVfs {
         user: &User,
         path: P,
     ) -> storage::Result<()> {
+        self.create_home(user).await;
         match &self.inner {
             #[cfg(feature = "sbe_azblob")]
             InnerVfs::OpenDAL(i) => i.del(user, path).await,
@@ -345,6 +463,7 @@ impl StorageBackend<User> for ChoosingVfs {
         user: &User,
         path: P,
     ) -> storage::Result<()> {
+        self.create_home(user).await;
         match &self.inner {
             #[cfg(feature = "sbe_azblob")]
             InnerVfs::OpenDAL(i) => i.mkd(user, path).await,
@@ -362,6 +481,7 @@ impl StorageBackend<User> for ChoosingVfs {
         from: P,
         to: P,
     ) -> storage::Result<()> {
+        self.create_home(user).await;
         match &self.inner {
             #[cfg(feature = "sbe_azblob")]
             InnerVfs::OpenDAL(i) => i.rename(user, from, to).await,
@@ -378,6 +498,7 @@ impl StorageBackend<User> for ChoosingVfs {
         user: &User,
         path: P,
     ) -> storage::Result<()> {
+        self.create_home(user).await;
         match &self.inner {
             #[cfg(feature = "sbe_azblob")]
             InnerVfs::OpenDAL(i) => i.rmd(user, path).await,
@@ -394,6 +515,7 @@ impl StorageBackend<User> for ChoosingVfs {
         user: &User,
         path: P,
     ) -> storage::Result<()> {
+        self.create_home(user).await;
         match &self.inner {
             #[cfg(feature = "sbe_azblob")]
             InnerVfs::OpenDAL(i) => i.cwd(user, path).await,
@@ -0,0 +1,107 @@
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// The directories, like `incoming` and `outgoing/done`, to create in the home directory of a
+/// user.
+#[derive(Debug, Default)]
+pub struct HomeSkeleton {
+    dirs: Vec<PathBuf>,
+}
+
+impl HomeSkeleton {
+    /// Parses a comma separated list of directories relative to the home directory.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let dirs = s
+            .split(',')
+            .map(str::trim)
+            .filter(|d| !d.is_empty())
+            .map(|d| {
+                let dir = PathBuf::from(d);
+                if dir
+                    .components()
+                    .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+                {
+                    Ok(dir)
+                } else {
+                    Err(format!(
+                        "'{}' is not a directory inside the home directory",
+                        d
+                    ))
+                }
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(HomeSkeleton { dirs })
+    }
+}
+
+/// Creates the home directory of the user of a session, and the skeleton in it, the first time
+/// the session uses the storage back-end.
+#[derive(Debug)]
+pub struct CreateHome {
+    skeleton: Arc<HomeSkeleton>,
+    done: AtomicBool,
+}
+
+impl CreateHome {
+    pub fn new(skeleton: Arc<HomeSkeleton>) -> Self {
+        CreateHome {
+            skeleton,
+            done: AtomicBool::new(false),
+        }
+    }
+
+    // Tells whether the home directory still has to be created in this session, and assumes it
+    // will be. A failure is logged once rather than on every command.
+    pub(super) fn start(&self) -> bool {
+        !self.done.swap(true, Ordering::Relaxed)
+    }
+
+    // The directories to create for the given home directory, parents first.
+    pub(super) fn dirs(&self, root: &Path) -> Vec<PathBuf> {
+        let mut dirs: Vec<PathBuf> = vec![];
+        let mut add = |path: &Path| {
+            let mut dir = PathBuf::new();
+            for c in path.components() {
+                dir.push(c);
+                if matches!(c, Component::Normal(_)) && !dirs.contains(&dir) {
+                    dirs.push(dir.clone());
+                }
+            }
+        };
+        add(root);
+        for sub in &self.skeleton.dirs {
+            add(&root.join(sub));
+        }
+        dirs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CreateHome, HomeSkeleton};
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+
+    #[test]
+    fn parents_are_created_before_the_skeleton() {
+        let skeleton = HomeSkeleton::parse("incoming, outgoing/done,").unwrap();
+        let home = CreateHome::new(Arc::new(skeleton));
+        assert_eq!(
+            home.dirs(Path::new("/home/alice")),
+            [
+                "/home",
+                "/home/alice",
+                "/home/alice/incoming",
+                "/home/alice/outgoing",
+                "/home/alice/outgoing/done"
+            ]
+            .map(PathBuf::from)
+        );
+        assert!(home.start());
+        assert!(!home.start());
+
+        assert!(HomeSkeleton::parse("../etc").is_err());
+        assert!(HomeSkeleton::parse("/etc").is_err());
+    }
+}
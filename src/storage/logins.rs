@@ -0,0 +1,124 @@
+//! Keeps track of the logins that the [`SessionVfs`](super::SessionVfs) sees.
+//!
+//! libunftp tells the storage back-end which user logged in, but only tells its
+//! [`PresenceListener`](libunftp::notification::PresenceListener) the username and the trace ID of
+//! the session. The storage back-end records what it learns here and the listener picks it up when
+//! the login event comes in. From then on the login ID and the trace ID of the session can be found
+//! from one another, until the connection closes.
+
+use crate::domain::user::User;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+};
+
+/// What the storage back-end learns about a login from the user that libunftp gives it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Login {
+    /// The login ID of the user, that transfers are recorded under.
+    pub id: u64,
+    /// The authentication chain steps that accepted the user.
+    pub auth_steps: Option<String>,
+}
+
+/// Holds logins until the matching login event takes them.
+///
+/// libunftp tells the listener right after the storage back-end, in the same session. The logins
+/// of a user are handed over in the order they happened, so two logins of the same user at the
+/// same moment can only be mixed up if libunftp reports them in the other order.
+#[derive(Debug, Default)]
+pub struct LoginTracker {
+    // The logins that the listener hasn't taken yet, per username.
+    pending: Mutex<HashMap<String, VecDeque<Login>>>,
+    // The login IDs of the sessions whose login was taken, by trace ID.
+    sessions: Mutex<HashMap<String, u64>>,
+}
+
+impl LoginTracker {
+    /// Records the login of the user.
+    pub fn entered(&self, user: &User) {
+        let login = Login {
+            id: user.login_id,
+            auth_steps: user.auth_steps.clone(),
+        };
+        let mut pending = self.pending.lock().unwrap();
+        pending
+            .entry(user.username.clone())
+            .or_default()
+            .push_back(login);
+    }
+
+    /// Takes the oldest login of the user that wasn't taken yet, for the session with the given
+    /// trace ID.
+    pub fn take(&self, username: &str, trace_id: &str) -> Option<Login> {
+        let mut pending = self.pending.lock().unwrap();
+        let logins = pending.get_mut(username)?;
+        let login = logins.pop_front();
+        if logins.is_empty() {
+            pending.remove(username);
+        }
+        if let Some(login) = &login {
+            let mut sessions = self.sessions.lock().unwrap();
+            sessions.insert(trace_id.to_string(), login.id);
+        }
+        login
+    }
+
+    /// Returns the login ID of the session with the given trace ID.
+    pub fn session(&self, trace_id: &str) -> Option<u64> {
+        self.sessions.lock().unwrap().get(trace_id).copied()
+    }
+
+    /// Returns the trace ID of the session of the given login.
+    pub fn trace_id(&self, id: u64) -> Option<String> {
+        let sessions = self.sessions.lock().unwrap();
+        sessions
+            .iter()
+            .find(|(_, login_id)| **login_id == id)
+            .map(|(trace_id, _)| trace_id.clone())
+    }
+
+    /// Forgets a login because its connection closed, whether libunftp reported it or not.
+    pub fn forget(&self, username: &str, id: u64) {
+        let mut pending = self.pending.lock().unwrap();
+        if let Some(logins) = pending.get_mut(username) {
+            logins.retain(|login| login.id != id);
+            if logins.is_empty() {
+                pending.remove(username);
+            }
+        }
+        drop(pending);
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, login_id| *login_id != id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LoginTracker;
+    use crate::domain::user::User;
+
+    #[test]
+    fn logins_are_taken_in_order_and_forgotten() {
+        let tracker = LoginTracker::default();
+        let user = |login_id: u64, steps: &str| User {
+            login_id,
+            auth_steps: Some(steps.to_string()),
+            ..User::with_defaults("alice")
+        };
+
+        tracker.entered(&user(1, "json"));
+        tracker.entered(&user(2, "ldap+pam"));
+        tracker.entered(&user(3, "pam"));
+        tracker.forget("alice", 1);
+        let login = tracker.take("alice", "0x2").unwrap();
+        assert_eq!(login.auth_steps.as_deref(), Some("ldap+pam"));
+        assert_eq!(tracker.session("0x2"), Some(2));
+        assert_eq!(tracker.trace_id(2).as_deref(), Some("0x2"));
+        tracker.forget("alice", 3);
+        assert_eq!(tracker.take("alice", "0x3"), None);
+        assert!(tracker.pending.lock().unwrap().is_empty());
+        tracker.forget("alice", 2);
+        assert_eq!(tracker.session("0x2"), None);
+    }
+}
@@ -1,3 +1,15 @@
 mod choose;
+mod home;
+mod logins;
+mod session;
+mod throttle;
+mod transfers;
 
 pub use choose::{ChoosingVfs, InnerVfs, SbeMeta};
+pub use home::{CreateHome, HomeSkeleton};
+pub use logins::LoginTracker;
+pub use session::SessionVfs;
+pub use throttle::{Rates, Throttle};
+#[cfg(test)]
+pub use transfers::TransferMeter;
+pub use transfers::{Direction, Transfer, TransferTracker};
@@ -0,0 +1,193 @@
+use super::LoginTracker;
+use crate::domain::user::User;
+use crate::sessions::{LoginClaim, Sessions};
+use async_trait::async_trait;
+use libunftp::storage::{self, Fileinfo, Metadata, StorageBackend};
+use std::{
+    fmt::Debug,
+    io::Cursor,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+/// Wraps the storage back-end of a connection to count it as a session of the user that logged
+/// in. Logins that would go over the user's session limit fail.
+#[derive(Debug)]
+pub struct SessionVfs<S> {
+    inner: S,
+    sessions: Arc<Sessions>,
+    logins: Arc<LoginTracker>,
+    // Given back when libunftp drops the storage back-end at the end of the connection.
+    claim: Option<LoginClaim>,
+    // The username and ID of the login, to forget it when the connection closes.
+    login: Option<(String, u64)>,
+}
+
+impl<S> SessionVfs<S> {
+    pub fn new(inner: S, sessions: Arc<Sessions>, logins: Arc<LoginTracker>) -> Self {
+        SessionVfs {
+            inner,
+            sessions,
+            logins,
+            claim: None,
+            login: None,
+        }
+    }
+}
+
+impl<S> Drop for SessionVfs<S> {
+    fn drop(&mut self) {
+        if let Some((username, id)) = &self.login {
+            self.logins.forget(username, *id);
+        }
+    }
+}
+
+#[async_trait]
+impl<S> StorageBackend<User> for SessionVfs<S>
+where
+    S: StorageBackend<User>,
+    S::Metadata: Metadata,
+{
+    type Metadata = S::Metadata;
+
+    fn enter(&mut self, user: &User) -> std::io::Result<()> {
+        let claim = self
+            .sessions
+            .log_in(&user.username, user.max_sessions)
+            .map_err(std::io::Error::other)?;
+        self.inner.enter(user)?;
+        self.claim = Some(claim);
+        self.logins.entered(user);
+        self.login = Some((user.username.clone(), user.login_id));
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn supported_features(&self) -> u32 {
+        self.inner.supported_features()
+    }
+
+    async fn metadata<P: AsRef<Path> + Send + Debug>(
+        &self,
+        user: &User,
+        path: P,
+    ) -> storage::Result<Self::Metadata> {
+        self.inner.metadata(user, path).await
+    }
+
+    async fn md5<P: AsRef<Path> + Send + Debug>(
+        &self,
+        user: &User,
+        path: P,
+    ) -> storage::Result<String> {
+        self.inner.md5(user, path).await
+    }
+
+    async fn list<P: AsRef<Path> + Send + Debug>(
+        &self,
+        user: &User,
+        path: P,
+    ) -> storage::Result<Vec<Fileinfo<PathBuf, Self::Metadata>>>
+    where
+        <Self as StorageBackend<User>>::Metadata: Metadata,
+    {
+        self.inner.list(user, path).await
+    }
+
+    async fn list_fmt<P>(&self, user: &User, path: P) -> storage::Result<Cursor<Vec<u8>>>
+    where
+        P: AsRef<Path> + Send + Debug,
+        Self::Metadata: Metadata + 'static,
+    {
+        self.inner.list_fmt(user, path).await
+    }
+
+    async fn nlst<P>(&self, user: &User, path: P) -> std::io::Result<Cursor<Vec<u8>>>
+    where
+        P: AsRef<Path> + Send + Debug,
+        Self::Metadata: Metadata + 'static,
+    {
+        self.inner.nlst(user, path).await
+    }
+
+    async fn get_into<'a, P, W: ?Sized>(
+        &self,
+        user: &User,
+        path: P,
+        start_pos: u64,
+        output: &'a mut W,
+    ) -> storage::Result<u64>
+    where
+        W: tokio::io::AsyncWrite + Unpin + Sync + Send,
+        P: AsRef<Path> + Send + Debug,
+    {
+        self.inner.get_into(user, path, start_pos, output).await
+    }
+
+    async fn get<P: AsRef<Path> + Send + Debug>(
+        &self,
+        user: &User,
+        path: P,
+        start_pos: u64,
+    ) -> storage::Result<Box<dyn tokio::io::AsyncRead + Send + Sync + Unpin>> {
+        self.inner.get(user, path, start_pos).await
+    }
+
+    async fn put<
+        P: AsRef<Path> + Send + Debug,
+        R: tokio::io::AsyncRead + Send + Sync + Unpin + 'static,
+    >(
+        &self,
+        user: &User,
+        input: R,
+        path: P,
+        start_pos: u64,
+    ) -> storage::Result<u64> {
+        self.inner.put(user, input, path, start_pos).await
+    }
+
+    async fn del<P: AsRef<Path> + Send + Debug>(
+        &self,
+        user: &User,
+        path: P,
+    ) -> storage::Result<()> {
+        self.inner.del(user, path).await
+    }
+
+    async fn mkd<P: AsRef<Path> + Send + Debug>(
+        &self,
+        user: &User,
+        path: P,
+    ) -> storage::Result<()> {
+        self.inner.mkd(user, path).await
+    }
+
+    async fn rename<P: AsRef<Path> + Send + Debug>(
+        &self,
+        user: &User,
+        from: P,
+        to: P,
+    ) -> storage::Result<()> {
+        self.inner.rename(user, from, to).await
+    }
+
+    async fn rmd<P: AsRef<Path> + Send + Debug>(
+        &self,
+        user: &User,
+        path: P,
+    ) -> storage::Result<()> {
+        self.inner.rmd(user, path).await
+    }
+
+    async fn cwd<P: AsRef<Path> + Send + Debug>(
+        &self,
+        user: &User,
+        path: P,
+    ) -> storage::Result<()> {
+        self.inner.cwd(user, path).await
+    }
+}
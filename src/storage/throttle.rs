@@ -0,0 +1,343 @@
+//! Limits the bandwidth of the file transfers done through the [`ChoosingVfs`](super::ChoosingVfs).
+//!
+//! Every transfer takes its bytes from up to three token buckets: the global one, the one of the
+//! user, shared by all of their sessions, and the one of the session. A bucket holds at most a tenth
+//! of a second worth of bytes, so concurrent transfers that share a bucket take turns in small
+//! slices and get about the same share of it.
+
+use super::transfers::Direction;
+use crate::domain::user::User;
+use prometheus::{CounterVec, Opts};
+use std::{
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex, Weak},
+    task::{ready, Context, Poll},
+    time::{Duration, Instant},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Sleep;
+
+lazy_static! {
+    static ref THROTTLED_SECONDS: CounterVec = prometheus::register_counter_vec!(
+        Opts::new(
+            "unftp_throttled_seconds_total",
+            "Time that transfers waited because of bandwidth limits."
+        ),
+        &["direction"]
+    )
+    .unwrap();
+}
+
+/// Bytes per second limits for downloads and uploads.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Rates {
+    pub download: Option<u64>,
+    pub upload: Option<u64>,
+}
+
+impl Rates {
+    fn get(&self, direction: Direction) -> Option<u64> {
+        match direction {
+            Direction::Get => self.download,
+            Direction::Put => self.upload,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    rate: u64,
+    capacity: f64,
+    // The tokens, which may be negative when transfers took more than there was at the same time,
+    // and when they were last counted.
+    state: Mutex<(f64, Instant)>,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Self {
+        let capacity = (rate as f64 / 10.0).max(1.0);
+        Bucket {
+            rate,
+            capacity,
+            state: Mutex::new((capacity, Instant::now())),
+        }
+    }
+
+    fn available(&self) -> f64 {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let refill = now.duration_since(state.1).as_secs_f64() * self.rate as f64;
+        *state = ((state.0 + refill).min(self.capacity), now);
+        state.0
+    }
+
+    fn take(&self, bytes: usize) {
+        self.state.lock().unwrap().0 -= bytes as f64;
+    }
+
+    // How long until the given number of bytes are available, at most a bucket full.
+    fn wait(&self, bytes: usize) -> Duration {
+        let missing = (bytes as f64).min(self.capacity) - self.available();
+        Duration::from_secs_f64(missing.max(0.0) / self.rate as f64)
+    }
+}
+
+/// The global limits and the buckets of the users, shared by all sessions.
+#[derive(Debug, Default)]
+pub struct Throttle {
+    global: HashMap<Direction, Arc<Bucket>>,
+    session: Rates,
+    users: Mutex<HashMap<(String, Direction), Weak<Bucket>>>,
+}
+
+impl Throttle {
+    pub fn new(global: Rates, session: Rates) -> Self {
+        let global = [Direction::Get, Direction::Put]
+            .into_iter()
+            .filter_map(|d| Some((d, Arc::new(Bucket::new(global.get(d)?)))))
+            .collect();
+        Throttle {
+            global,
+            session,
+            users: Mutex::default(),
+        }
+    }
+
+    /// Creates the buckets of a new session.
+    pub fn session(self: &Arc<Self>) -> SessionThrottle {
+        let buckets = [Direction::Get, Direction::Put]
+            .into_iter()
+            .filter_map(|d| Some((d, Arc::new(Bucket::new(self.session.get(d)?)))))
+            .collect();
+        SessionThrottle {
+            throttle: self.clone(),
+            buckets,
+        }
+    }
+
+    // The bucket of the user, which is replaced when their limit changed.
+    fn user(&self, username: &str, direction: Direction, rate: u64) -> Arc<Bucket> {
+        let mut users = self.users.lock().unwrap();
+        let id = (username.to_string(), direction);
+        if let Some(bucket) = users.get(&id).and_then(Weak::upgrade) {
+            if bucket.rate == rate {
+                return bucket;
+            }
+        }
+        users.retain(|_, b| b.strong_count() > 0);
+        let bucket = Arc::new(Bucket::new(rate));
+        users.insert(id, Arc::downgrade(&bucket));
+        bucket
+    }
+}
+
+/// The bandwidth limits of one session.
+#[derive(Debug)]
+pub struct SessionThrottle {
+    throttle: Arc<Throttle>,
+    buckets: HashMap<Direction, Arc<Bucket>>,
+}
+
+impl SessionThrottle {
+    /// The limits for a transfer of the given user in the given direction.
+    pub fn limits(&self, user: &User, direction: Direction) -> Limits {
+        let user_rate = match direction {
+            Direction::Get => user.max_download_bps,
+            Direction::Put => user.max_upload_bps,
+        };
+        let buckets = [
+            self.throttle.global.get(&direction).cloned(),
+            user_rate.map(|rate| self.throttle.user(&user.username, direction, rate)),
+            self.buckets.get(&direction).cloned(),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        Limits {
+            buckets,
+            direction,
+            sleep: None,
+        }
+    }
+}
+
+/// The buckets a transfer takes its bytes from.
+pub struct Limits {
+    buckets: Vec<Arc<Bucket>>,
+    direction: Direction,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl Limits {
+    // Waits until some bytes are available in all buckets and tells how many, at most `wanted`.
+    fn poll_allowed(&mut self, cx: &mut Context<'_>, wanted: usize) -> Poll<usize> {
+        loop {
+            if let Some(sleep) = &mut self.sleep {
+                ready!(sleep.as_mut().poll(cx));
+                self.sleep = None;
+            }
+            let available = self
+                .buckets
+                .iter()
+                .map(|b| b.available())
+                .fold(f64::INFINITY, f64::min);
+            if available >= 1.0 {
+                return Poll::Ready(wanted.min(available as usize));
+            }
+            let wait = self
+                .buckets
+                .iter()
+                .map(|b| b.wait(wanted))
+                .max()
+                .unwrap_or_default();
+            let direction = match self.direction {
+                Direction::Get => "download",
+                Direction::Put => "upload",
+            };
+            THROTTLED_SECONDS
+                .with_label_values(&[direction])
+                .inc_by(wait.as_secs_f64());
+            self.sleep = Some(Box::pin(tokio::time::sleep(wait)));
+        }
+    }
+
+    fn take(&self, bytes: usize) {
+        for bucket in &self.buckets {
+            bucket.take(bytes);
+        }
+    }
+}
+
+/// Wraps an upload stream to read from it no faster than the limits allow.
+pub struct ThrottledReader<R> {
+    inner: R,
+    limits: Limits,
+    scratch: Vec<u8>,
+}
+
+impl<R> ThrottledReader<R> {
+    pub fn new(inner: R, limits: Limits) -> Self {
+        ThrottledReader {
+            inner,
+            limits,
+            scratch: vec![],
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ThrottledReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if this.limits.buckets.is_empty() || buf.remaining() == 0 {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        let allowed = ready!(this.limits.poll_allowed(cx, buf.remaining()));
+        this.scratch.resize(allowed, 0);
+        let mut limited = ReadBuf::new(&mut this.scratch);
+        ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited))?;
+        this.limits.take(limited.filled().len());
+        buf.put_slice(limited.filled());
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Wraps a download stream to write to it no faster than the limits allow.
+pub struct ThrottledWriter<'a, W: ?Sized> {
+    inner: &'a mut W,
+    limits: Limits,
+}
+
+impl<'a, W: ?Sized> ThrottledWriter<'a, W> {
+    pub fn new(inner: &'a mut W, limits: Limits) -> Self {
+        ThrottledWriter { inner, limits }
+    }
+}
+
+impl<W: AsyncWrite + Unpin + ?Sized> AsyncWrite for ThrottledWriter<'_, W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        if this.limits.buckets.is_empty() || buf.is_empty() {
+            return Pin::new(&mut *this.inner).poll_write(cx, buf);
+        }
+        let allowed = ready!(this.limits.poll_allowed(cx, buf.len()));
+        let written = ready!(Pin::new(&mut *this.inner).poll_write(cx, &buf[..allowed]))?;
+        this.limits.take(written);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Rates, SessionThrottle, Throttle, ThrottledWriter};
+    use crate::domain::user::User;
+    use crate::storage::Direction;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn transfers_share_the_lowest_limit() {
+        let throttle = Arc::new(Throttle::new(
+            Rates {
+                download: Some(100_000),
+                upload: None,
+            },
+            Rates::default(),
+        ));
+        let user = User {
+            max_download_bps: Some(50_000),
+            ..User::with_defaults("alice")
+        };
+        let (first, second) = (throttle.session(), throttle.session());
+
+        // Two sessions of the same user each download 10 KB. The first 5 KB are in the bucket, the
+        // other 15 KB take 0.3 seconds at 50 KB/s.
+        let started = Instant::now();
+        let download = |session: SessionThrottle| {
+            let user = user.clone();
+            tokio::spawn(async move {
+                let mut out = tokio::io::sink();
+                let mut writer =
+                    ThrottledWriter::new(&mut out, session.limits(&user, Direction::Get));
+                writer.write_all(&[0; 10_000]).await.unwrap();
+            })
+        };
+        let (a, b) = (download(first), download(second));
+        a.await.unwrap();
+        b.await.unwrap();
+        let took = started.elapsed();
+        assert!(took >= Duration::from_millis(250), "{:?}", took);
+        assert!(took < Duration::from_secs(2), "{:?}", took);
+
+        // Uploads are not limited.
+        let session = throttle.session();
+        let mut out = tokio::io::sink();
+        let started = Instant::now();
+        ThrottledWriter::new(
+            &mut out,
+            session.limits(&User::with_defaults("bob"), Direction::Put),
+        )
+        .write_all(&[0; 1_000_000])
+        .await
+        .unwrap();
+        assert!(started.elapsed() < Duration::from_millis(100));
+    }
+}
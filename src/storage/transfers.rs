@@ -0,0 +1,308 @@
+//! Keeps track of file transfers done through the [`ChoosingVfs`](super::ChoosingVfs).
+//!
+//! libunftp only tells its [`DataListener`](libunftp::notification::DataListener) the path and
+//! byte count of a completed RETR or STOR, and nothing about a failed one. The storage back-end sees
+//! more, so it records the details here. The listener picks them up when the corresponding data
+//! event comes in and is handed failed transfers as they happen.
+
+use crate::domain::{events::TransferOutcome, user::User};
+use md5::{Digest, Md5};
+use std::{
+    fmt,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+// Records that were not claimed by a data event within this time are discarded.
+const MAX_AGE: Duration = Duration::from_secs(300);
+
+// Upper bound on unclaimed records to keep memory in check.
+const MAX_RECORDS: usize = 1024;
+
+/// The direction of a file transfer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    Get,
+    Put,
+}
+
+/// The details the storage back-end knows about a transfer.
+#[derive(Debug, Clone)]
+pub struct Transfer {
+    pub username: String,
+    /// The login that the transfer was done in, see [`User::login_id`].
+    pub login_id: u64,
+    pub direction: Direction,
+    /// The path as passed to the storage back-end i.e. including the user's root.
+    pub path: PathBuf,
+    /// The absolute path as the client sees it i.e. without the user's root.
+    pub client_path: PathBuf,
+    pub outcome: TransferOutcome,
+    /// The bytes that went through, also when the transfer failed.
+    pub bytes: u64,
+    pub duration: Duration,
+    pub client_ip: Option<IpAddr>,
+    pub content_type: Option<String>,
+    pub storage_backend: &'static str,
+    /// Only set for complete transfers that succeeded.
+    pub md5: Option<String>,
+    finished: Instant,
+}
+
+impl Transfer {
+    pub fn new(
+        user: &User,
+        direction: Direction,
+        path: PathBuf,
+        started: Instant,
+        storage_backend: &'static str,
+        meter: &TransferMeter,
+        outcome: TransferOutcome,
+    ) -> Self {
+        let content_type = mime_guess::from_path(&path)
+            .first()
+            .map(|m| m.essence_str().to_string());
+        let client_path = match &user.root {
+            Some(root) => Path::new("/").join(path.strip_prefix(root).unwrap_or(&path)),
+            None => path.clone(),
+        };
+        let md5 = match outcome {
+            TransferOutcome::Succeeded => meter.md5(),
+            TransferOutcome::Failed => None,
+        };
+        Transfer {
+            username: user.username.clone(),
+            login_id: user.login_id,
+            direction,
+            path,
+            client_path,
+            outcome,
+            bytes: meter.bytes(),
+            duration: started.elapsed(),
+            client_ip: user.source_ip,
+            content_type,
+            storage_backend,
+            md5,
+            finished: Instant::now(),
+        }
+    }
+}
+
+/// Holds the records of successful transfers until the matching data event claims them, and
+/// hands failed transfers on right away.
+#[derive(Default)]
+pub struct TransferTracker {
+    records: Mutex<Vec<Transfer>>,
+    on_failure: OnceLock<Box<dyn Fn(Transfer) + Send + Sync>>,
+}
+
+impl fmt::Debug for TransferTracker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TransferTracker")
+            .field("records", &self.records)
+            .finish()
+    }
+}
+
+impl TransferTracker {
+    /// Sets what to do with failed transfers, which libunftp sends no data event for. Failed
+    /// transfers are dropped until this is set, and it can only be set once.
+    pub fn on_failure(&self, report: impl Fn(Transfer) + Send + Sync + 'static) {
+        let _ = self.on_failure.set(Box::new(report));
+    }
+
+    pub fn record(&self, transfer: Transfer) {
+        if transfer.outcome == TransferOutcome::Failed {
+            if let Some(report) = self.on_failure.get() {
+                report(transfer);
+            }
+            return;
+        }
+        let mut records = self.records.lock().unwrap();
+        records.retain(|r| r.finished.elapsed() < MAX_AGE);
+        if records.len() >= MAX_RECORDS {
+            records.remove(0);
+        }
+        records.push(transfer);
+    }
+
+    /// Removes and returns the most recent record of the login for the given direction and the
+    /// path the client specified. That path is relative to the client's working directory unless
+    /// it starts with a '/'. A session transfers one file at a time, so a relative path can only
+    /// match the file it just transferred.
+    pub fn take(&self, login_id: u64, direction: Direction, client_path: &str) -> Option<Transfer> {
+        let client_path = Path::new(client_path);
+        let mut records = self.records.lock().unwrap();
+        let pos = records.iter().rposition(|r| {
+            r.login_id == login_id
+                && r.direction == direction
+                && if client_path.is_absolute() {
+                    r.client_path == client_path
+                } else {
+                    r.client_path.ends_with(client_path)
+                }
+        })?;
+        Some(records.remove(pos))
+    }
+}
+
+/// Counts the bytes of a transfer and, when asked to, calculates their MD5 digest. It is fed by
+/// [`MeteredReader`] and [`MeteredWriter`].
+#[derive(Debug, Clone, Default)]
+pub struct TransferMeter {
+    bytes: Arc<AtomicU64>,
+    digest: Option<Arc<Mutex<Md5>>>,
+}
+
+impl TransferMeter {
+    pub fn new(with_digest: bool) -> Self {
+        TransferMeter {
+            bytes: Arc::default(),
+            digest: with_digest.then(Arc::default),
+        }
+    }
+
+    fn update(&self, data: &[u8]) {
+        self.bytes.fetch_add(data.len() as u64, Ordering::Relaxed);
+        if let Some(digest) = &self.digest {
+            digest.lock().unwrap().update(data);
+        }
+    }
+
+    /// Returns the amount of bytes seen so far.
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    /// Returns the hex encoded digest of everything seen so far, if a digest is calculated.
+    pub fn md5(&self) -> Option<String> {
+        let digest = self.digest.as_ref()?.lock().unwrap().clone().finalize();
+        Some(digest.iter().map(|b| format!("{:02x}", b)).collect())
+    }
+}
+
+/// Wraps an upload stream to meter the bytes read from it.
+pub struct MeteredReader<R> {
+    inner: R,
+    meter: TransferMeter,
+}
+
+impl<R> MeteredReader<R> {
+    pub fn new(inner: R, meter: TransferMeter) -> Self {
+        MeteredReader { inner, meter }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for MeteredReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = &result {
+            this.meter.update(&buf.filled()[before..]);
+        }
+        result
+    }
+}
+
+/// Wraps a download stream to meter the bytes written to it.
+pub struct MeteredWriter<'a, W: ?Sized> {
+    inner: &'a mut W,
+    meter: TransferMeter,
+}
+
+impl<'a, W: ?Sized> MeteredWriter<'a, W> {
+    pub fn new(inner: &'a mut W, meter: TransferMeter) -> Self {
+        MeteredWriter { inner, meter }
+    }
+}
+
+impl<W: AsyncWrite + Unpin + ?Sized> AsyncWrite for MeteredWriter<'_, W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut *this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = &result {
+            this.meter.update(&buf[..*n]);
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Direction, Transfer, TransferMeter, TransferTracker};
+    use crate::domain::{events::TransferOutcome, user::User};
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+    use std::time::Instant;
+
+    fn transfer(login_id: u64, direction: Direction, path: &str) -> Transfer {
+        let user = User {
+            login_id,
+            root: Some(PathBuf::from("/alice")),
+            ..User::with_defaults("alice")
+        };
+        let meter = TransferMeter::new(true);
+        Transfer::new(
+            &user,
+            direction,
+            PathBuf::from(path),
+            Instant::now(),
+            "filesystem",
+            &meter,
+            TransferOutcome::Succeeded,
+        )
+    }
+
+    #[test]
+    fn take_matches_on_login_direction_and_full_path() {
+        let tracker = TransferTracker::default();
+        tracker.record(transfer(1, Direction::Put, "/alice/in/report.csv"));
+        tracker.record(transfer(2, Direction::Put, "/alice/out/report.csv"));
+
+        assert!(tracker.take(1, Direction::Get, "report.csv").is_none());
+        assert!(tracker.take(1, Direction::Put, "/report.csv").is_none());
+        assert!(tracker.take(1, Direction::Put, "/out/report.csv").is_none());
+
+        let t = tracker.take(1, Direction::Put, "/in/report.csv").unwrap();
+        assert_eq!(t.path, PathBuf::from("/alice/in/report.csv"));
+        assert_eq!(t.client_path, PathBuf::from("/in/report.csv"));
+        assert_eq!(t.content_type.as_deref(), Some("text/csv"));
+        assert!(tracker.take(1, Direction::Put, "report.csv").is_none());
+        let t = tracker.take(2, Direction::Put, "report.csv").unwrap();
+        assert_eq!(t.client_path, PathBuf::from("/out/report.csv"));
+
+        let failed = Arc::new(Mutex::new(Vec::new()));
+        let reported = failed.clone();
+        tracker.on_failure(move |t| reported.lock().unwrap().push(t));
+        let mut t = transfer(3, Direction::Get, "/alice/in/report.csv");
+        t.outcome = TransferOutcome::Failed;
+        tracker.record(t);
+        assert!(tracker.take(3, Direction::Get, "report.csv").is_none());
+        assert_eq!(failed.lock().unwrap()[0].login_id, 3);
+    }
+}
@@ -0,0 +1,163 @@
+//! Time-based one-time passwords (RFC 6238) as a second factor that users append to their FTP
+//! password.
+
+use crate::domain::user::TotpSecret;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use std::{
+    collections::HashMap,
+    fmt::{Debug, Formatter},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// The number of digits in a code.
+const DIGITS: usize = 6;
+
+// The number of seconds a code is valid for.
+const TIME_STEP: u64 = 30;
+
+/// Splits the code off the end of a password, if it ends with enough digits.
+pub fn split_code(password: &str) -> Option<(&str, &str)> {
+    let at = password.len().checked_sub(DIGITS)?;
+    if !password.is_char_boundary(at) {
+        return None;
+    }
+    let (password, code) = password.split_at(at);
+    code.bytes()
+        .all(|b| b.is_ascii_digit())
+        .then_some((password, code))
+}
+
+/// Verifies codes and remembers which ones were used so that they can't be used again.
+pub struct Totp {
+    // How many time steps before and after the current one are accepted to allow for clock skew.
+    skew: u64,
+    // The last time step a code was used for, per user.
+    used: Mutex<HashMap<String, u64>>,
+}
+
+impl Debug for Totp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Totp").field("skew", &self.skew).finish()
+    }
+}
+
+impl Totp {
+    pub fn new(skew: u64) -> Self {
+        Totp {
+            skew,
+            used: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Checks the code against the current time and returns the time step it is for. Codes for a
+    /// time step that was already used by this user are refused.
+    pub fn check(&self, username: &str, secret: &TotpSecret, code: &str) -> Option<u64> {
+        self.check_at(username, secret, code, now())
+    }
+
+    fn check_at(&self, username: &str, secret: &TotpSecret, code: &str, time: u64) -> Option<u64> {
+        let key = secret.key().ok()?;
+        let current = time / TIME_STEP;
+        let last_used = self.used.lock().unwrap().get(username).copied();
+        (current.saturating_sub(self.skew)..=current + self.skew)
+            .filter(|step| last_used.is_none_or(|used| *step > used))
+            .find(|step| constant_time_eq(hotp(&key, *step).as_bytes(), code.as_bytes()))
+    }
+
+    /// Marks the time step as used after a successful login. Returns false if another login used
+    /// it in the meantime.
+    pub fn redeem(&self, username: &str, step: u64) -> bool {
+        let mut used = self.used.lock().unwrap();
+        match used.get(username) {
+            Some(last) if *last >= step => false,
+            _ => {
+                used.insert(username.to_string(), step);
+                true
+            }
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+// The HOTP value (RFC 4226) for the counter.
+fn hotp(key: &[u8], counter: u64) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let value = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    format!(
+        "{:0width$}",
+        value % 10u32.pow(DIGITS as u32),
+        width = DIGITS
+    )
+}
+
+/// Compares the bytes in constant time so that the comparison doesn't tell how much of them
+/// matched.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hotp, split_code, Totp};
+    use crate::domain::user::TotpSecret;
+
+    // The secret of the RFC 6238 test vectors, "12345678901234567890" in base32.
+    const SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    #[test]
+    fn codes_match_rfc_test_vectors() {
+        let key = TotpSecret::new(SECRET).unwrap().key().unwrap();
+        // The last 6 digits of the SHA1 vectors in RFC 6238 appendix B.
+        assert_eq!(hotp(&key, 59 / 30), "287082");
+        assert_eq!(hotp(&key, 1111111109 / 30), "081804");
+        assert_eq!(hotp(&key, 2000000000 / 30), "279037");
+    }
+
+    #[test]
+    fn codes_are_checked_within_the_window_and_not_replayed() {
+        let totp = Totp::new(1);
+        let secret = TotpSecret::new(SECRET).unwrap();
+        let time = 1111111109;
+
+        // The code of the previous step is still accepted, the one from two steps ago is not.
+        let previous = hotp(&secret.key().unwrap(), time / 30 - 1);
+        let too_old = hotp(&secret.key().unwrap(), time / 30 - 2);
+        assert!(totp.check_at("alice", &secret, &too_old, time).is_none());
+        assert_eq!(
+            totp.check_at("alice", &secret, &previous, time),
+            Some(time / 30 - 1)
+        );
+
+        let step = totp.check_at("alice", &secret, "081804", time).unwrap();
+        assert!(totp.redeem("alice", step));
+        assert!(!totp.redeem("alice", step));
+        assert!(totp.check_at("alice", &secret, "081804", time).is_none());
+        // Once a code was used, older ones can't be used either.
+        assert!(totp.check_at("alice", &secret, &previous, time).is_none());
+        assert!(totp.check_at("bob", &secret, "081804", time).is_some());
+    }
+
+    #[test]
+    fn codes_are_split_off_passwords() {
+        assert_eq!(split_code("hunter2123456"), Some(("hunter2", "123456")));
+        assert_eq!(split_code("123456"), Some(("", "123456")));
+        assert_eq!(split_code("hunter2"), None);
+        assert_eq!(split_code("12345"), None);
+    }
+}
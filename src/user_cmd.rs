@@ -0,0 +1,373 @@
+//! The `unftp user` subcommands that add, remove and change users in the JSON credentials file of
+//! `--auth-json-path` and the user detail file of `--usr-json-path`.
+
+use crate::{
+    args,
+    domain::user::validate_vfs_permissions,
+    infra::usrdetail_json::{decode, encode, FileEncoding},
+};
+use base64::{engine, Engine};
+use clap::ArgMatches;
+use serde_json::{Map, Value};
+use std::{
+    fs,
+    io::{BufRead, IsTerminal, Write},
+    path::PathBuf,
+};
+
+// The number of PBKDF2 iterations that the unftp-auth-jsonfile documentation uses.
+const PBKDF2_ITERATIONS: u32 = 500_000;
+
+// A JSON user or credentials file: a list of objects with a username. Fields this command doesn't
+// know about are kept as they are, in the order they were in.
+#[derive(Debug)]
+struct UserFile {
+    path: PathBuf,
+    encoding: FileEncoding,
+    users: Vec<Map<String, Value>>,
+}
+
+impl UserFile {
+    // Reads the file, or starts a new one if it doesn't exist yet.
+    fn open(path: &str) -> Result<UserFile, String> {
+        let path = PathBuf::from(path);
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(UserFile {
+                    path,
+                    encoding: FileEncoding::Plain,
+                    users: vec![],
+                })
+            }
+            Err(e) => return Err(format!("could not read '{}': {}", path.display(), e)),
+        };
+        let invalid =
+            |e: &dyn std::fmt::Display| format!("invalid file '{}': {}", path.display(), e);
+        let json = decode(&data).map_err(|e| invalid(&e))?;
+        let users: Vec<Map<String, Value>> =
+            serde_json::from_str(&json).map_err(|e| invalid(&e))?;
+        if let Some(i) = users.iter().position(|u| !u["username"].is_string()) {
+            return Err(invalid(&format!("entry {} has no username", i + 1)));
+        }
+        Ok(UserFile {
+            encoding: FileEncoding::detect(&data),
+            path,
+            users,
+        })
+    }
+
+    fn find(&mut self, username: &str) -> Option<&mut Map<String, Value>> {
+        self.users.iter_mut().find(|u| u["username"] == username)
+    }
+
+    fn remove(&mut self, username: &str) -> bool {
+        let before = self.users.len();
+        self.users.retain(|u| u["username"] != username);
+        self.users.len() != before
+    }
+
+    // Writes the file to a temporary file next to it first and then renames that, so that the
+    // server never reads a half written file.
+    fn save(&self, encoding: Option<FileEncoding>) -> Result<(), String> {
+        let failed =
+            |e: std::io::Error| format!("could not write '{}': {}", self.path.display(), e);
+        let json = serde_json::to_string_pretty(&self.users).map_err(|e| e.to_string())?;
+        let data = encode(&json, encoding.unwrap_or(self.encoding)).map_err(failed)?;
+        let name = self.path.file_name().unwrap_or_default().to_string_lossy();
+        let tmp = self.path.with_file_name(format!(".{}.tmp", name));
+        fs::write(&tmp, data).map_err(failed)?;
+        let permissions = match fs::metadata(&self.path) {
+            Ok(meta) => Some(meta.permissions()),
+            // The files can contain password hashes, so new ones are only for the owner to read.
+            #[cfg(unix)]
+            Err(_) => Some(std::os::unix::fs::PermissionsExt::from_mode(0o600)),
+            #[cfg(not(unix))]
+            Err(_) => None,
+        };
+        if let Some(permissions) = permissions {
+            fs::set_permissions(&tmp, permissions).map_err(failed)?;
+        }
+        fs::rename(&tmp, &self.path).map_err(failed)
+    }
+}
+
+/// Runs the `unftp user` subcommand given in the arguments.
+pub fn run(m: &ArgMatches) -> Result<(), String> {
+    let Some((cmd, m)) = m.subcommand() else {
+        return Err("please specify what to do with users".to_string());
+    };
+    let mut credentials = m
+        .value_of(args::AUTH_JSON_PATH)
+        .map(UserFile::open)
+        .transpose()?;
+    let mut details = m
+        .value_of(args::USR_JSON_PATH)
+        .map(UserFile::open)
+        .transpose()?;
+    if credentials.is_none() && details.is_none() {
+        return Err(format!(
+            "please specify the files to work on with --{}, --{} or both",
+            args::AUTH_JSON_PATH,
+            args::USR_JSON_PATH
+        ));
+    }
+    let username = match cmd {
+        args::USER_CMD_LIST => "",
+        _ => m.value_of(args::USER_NAME).unwrap_or_default(),
+    };
+    let needs_details = |what: &str| {
+        format!(
+            "{} needs the user detail file, please specify --{}",
+            what,
+            args::USR_JSON_PATH
+        )
+    };
+
+    match cmd {
+        args::USER_CMD_ADD => {
+            if [&mut credentials, &mut details]
+                .into_iter()
+                .flatten()
+                .any(|f| f.find(username).is_some())
+            {
+                return Err(format!("user '{}' exists already", username));
+            }
+            let vfs_perms = m.value_of(args::USER_VFS_PERMS).map(|perms| {
+                perms
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|p| !p.is_empty())
+                    .map(str::to_string)
+                    .collect::<Vec<_>>()
+            });
+            if let Some(perms) = &vfs_perms {
+                validate_vfs_permissions(perms)?;
+            }
+            let root = m.value_of(args::USER_ROOT);
+            let disabled = m.is_present(args::USER_DISABLED);
+            if details.is_none() && (root.is_some() || vfs_perms.is_some() || disabled) {
+                return Err(needs_details("--root, --vfs-perms or --disabled"));
+            }
+            if let Some(file) = &mut credentials {
+                let mut user = user_entry(username);
+                set_password(&mut user, &password(m, username)?);
+                file.users.push(user);
+            }
+            if let Some(file) = &mut details {
+                let mut user = user_entry(username);
+                if let Some(root) = root {
+                    user.insert("root".to_string(), root.into());
+                }
+                if let Some(perms) = vfs_perms {
+                    user.insert("vfs_perms".to_string(), perms.into());
+                }
+                user.insert("account_enabled".to_string(), (!disabled).into());
+                file.users.push(user);
+            }
+        }
+        args::USER_CMD_REMOVE => {
+            // Remove the user from both files, even if the first one had them.
+            let removed: Vec<bool> = [&mut credentials, &mut details]
+                .into_iter()
+                .flatten()
+                .map(|f| f.remove(username))
+                .collect();
+            if !removed.contains(&true) {
+                return Err(format!("user '{}' not found", username));
+            }
+        }
+        args::USER_CMD_PASSWD => {
+            let file = credentials.as_mut().ok_or_else(|| {
+                format!(
+                    "changing a password needs the credentials file, please specify --{}",
+                    args::AUTH_JSON_PATH
+                )
+            })?;
+            let user = file
+                .find(username)
+                .ok_or_else(|| format!("user '{}' not found", username))?;
+            set_password(user, &password(m, username)?);
+        }
+        args::USER_CMD_DISABLE | args::USER_CMD_ENABLE => {
+            let file = details
+                .as_mut()
+                .ok_or_else(|| needs_details("enabling or disabling accounts"))?;
+            let user = file
+                .find(username)
+                .ok_or_else(|| format!("user '{}' not found", username))?;
+            user.insert(
+                "account_enabled".to_string(),
+                (cmd == args::USER_CMD_ENABLE).into(),
+            );
+        }
+        args::USER_CMD_LIST => {
+            list(credentials.as_ref(), details.as_ref());
+            return Ok(());
+        }
+        other => return Err(format!("unknown user subcommand '{}'", other)),
+    }
+
+    let encoding = match m.value_of(args::USER_ENCODING) {
+        Some("gzip") => Some(FileEncoding::Gzip),
+        Some("gzip-base64") => Some(FileEncoding::GzipBase64),
+        Some(_) => Some(FileEncoding::Plain),
+        None => None,
+    };
+    for file in [credentials, details].iter().flatten() {
+        file.save(encoding)?;
+    }
+    Ok(())
+}
+
+fn user_entry(username: &str) -> Map<String, Value> {
+    let mut user = Map::new();
+    user.insert("username".to_string(), username.into());
+    user
+}
+
+// Stores the password as the PBKDF2 hash that unftp-auth-jsonfile checks, replacing a plaintext
+// password if there was one.
+fn set_password(user: &mut Map<String, Value>, password: &str) {
+    let salt: [u8; 16] = rand::random();
+    let mut key = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<sha2::Sha256>(password.as_bytes(), &salt, PBKDF2_ITERATIONS, &mut key);
+    let base64 = engine::general_purpose::STANDARD;
+    user.remove("password");
+    user.insert("pbkdf2_salt".to_string(), base64.encode(salt).into());
+    user.insert("pbkdf2_key".to_string(), base64.encode(key).into());
+    user.insert("pbkdf2_iter".to_string(), PBKDF2_ITERATIONS.into());
+}
+
+// The password given on the command line, or else the first line of standard input.
+fn password(m: &ArgMatches, username: &str) -> Result<String, String> {
+    let password = match m.value_of(args::USER_PASSWORD) {
+        Some(password) => password.to_string(),
+        None => {
+            let stdin = std::io::stdin();
+            if stdin.is_terminal() {
+                eprint!("Password for {}: ", username);
+                let _ = std::io::stderr().flush();
+            }
+            let mut line = String::new();
+            stdin
+                .lock()
+                .read_line(&mut line)
+                .map_err(|e| format!("could not read the password: {}", e))?;
+            line.trim_end_matches(['\r', '\n']).to_string()
+        }
+    };
+    if password.is_empty() {
+        return Err("the password can't be empty".to_string());
+    }
+    Ok(password)
+}
+
+// Prints the users in both files, in the order they appear.
+fn list(credentials: Option<&UserFile>, details: Option<&UserFile>) {
+    let users = |file: Option<&UserFile>| file.map(|f| f.users.clone()).unwrap_or_default();
+    let (credentials, details) = (users(credentials), users(details));
+    let mut usernames: Vec<&str> = vec![];
+    for user in credentials.iter().chain(details.iter()) {
+        let username = user["username"].as_str().unwrap_or_default();
+        if !usernames.contains(&username) {
+            usernames.push(username);
+        }
+    }
+    println!("{:<24} {:<9} {:<8} ROOT", "USERNAME", "PASSWORD", "ENABLED");
+    for username in usernames {
+        let find = |users: &[Map<String, Value>]| {
+            users.iter().find(|u| u["username"] == username).cloned()
+        };
+        let detail = find(&details);
+        let password = match find(&credentials) {
+            Some(c) if c.contains_key("pbkdf2_key") => "pbkdf2",
+            Some(c) if c.contains_key("password") => "plain",
+            _ => "-",
+        };
+        let enabled = detail
+            .as_ref()
+            .and_then(|d| d.get("account_enabled"))
+            .and_then(Value::as_bool)
+            .unwrap_or(true);
+        let root = detail
+            .as_ref()
+            .and_then(|d| d.get("root"))
+            .and_then(Value::as_str)
+            .unwrap_or("-");
+        println!("{:<24} {:<9} {:<8} {}", username, password, enabled, root);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{run, UserFile};
+    use crate::{args, infra::usrdetail_json::FileEncoding};
+
+    fn user(argv: &[&str]) -> Result<(), String> {
+        let m = args::clap_app("/tmp")
+            .try_get_matches_from(["unftp", "user"].iter().chain(argv))
+            .map_err(|e| e.to_string())?;
+        run(m.subcommand_matches(args::USER_CMD).unwrap())
+    }
+
+    #[tokio::test]
+    async fn users_are_added_changed_and_removed() {
+        let dir = std::env::temp_dir().join(format!("unftp-user-cmd-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let creds = dir.join("credentials.json");
+        let details = dir.join("users.json");
+        let (creds, details) = (creds.to_str().unwrap(), details.to_str().unwrap());
+        let files = ["--auth-json-path", creds, "--usr-json-path", details];
+        let with_files = |argv: &[&'static str]| -> Vec<&str> {
+            files.iter().copied().chain(argv.iter().copied()).collect()
+        };
+
+        let add = |argv: &[&'static str]| {
+            let mut all = vec!["add"];
+            all.extend(with_files(argv));
+            user(&all)
+        };
+        add(&[
+            "alice",
+            "--password",
+            "s3cret",
+            "--root",
+            "alice",
+            "--vfs-perms",
+            "-mkdir,-del",
+        ])
+        .unwrap();
+        assert!(add(&["alice", "--password", "again"]).is_err());
+        assert!(add(&["bob", "--password", "pw", "--vfs-perms", "-delete"]).is_err());
+
+        let mut all = vec!["disable"];
+        all.extend(with_files(&["alice", "--encoding", "gzip-base64"]));
+        user(&all).unwrap();
+
+        let users = UserFile::open(details).unwrap();
+        assert_eq!(users.encoding, FileEncoding::GzipBase64);
+        assert_eq!(
+            users.users[0]["vfs_perms"],
+            serde_json::json!(["-mkdir", "-del"])
+        );
+        assert_eq!(users.users[0]["account_enabled"], false);
+
+        #[cfg(feature = "auth_jsonfile")]
+        {
+            use libunftp::auth::{Authenticator, Credentials};
+            let auth = unftp_auth_jsonfile::JsonFileAuthenticator::from_file(creds).unwrap();
+            assert!(auth
+                .authenticate("alice", &Credentials::from("s3cret"))
+                .await
+                .is_ok());
+        }
+
+        let mut all = vec!["remove"];
+        all.extend(with_files(&["alice"]));
+        user(&all).unwrap();
+        assert!(user(&all).is_err());
+        assert!(UserFile::open(creds).unwrap().users.is_empty());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}